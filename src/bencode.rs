@@ -1,37 +1,221 @@
-use serde_bencode;
+//! Bencode: parsing with byte spans, streaming, canonical validation, and
+//! conversion from and to JSON.
 
-pub fn decode_bencoded_value(encoded_value: &str) -> anyhow::Result<serde_json::Value> {
-    let value: serde_bencode::value::Value = serde_bencode::from_str(encoded_value)?;
-    convert_bencode_value_to_json_value(value)
+pub mod canonical;
+pub mod decoder;
+pub mod diff;
+pub mod dump;
+pub mod error;
+pub mod parser;
+
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+pub use self::error::BencodeError;
+
+// Raw bytes in JSON: {"$hex": "<hex>"} or {"$base64": "<base64>"} as values,
+// "$hex:<hex>" or "$base64:<base64>" as keys
+const HEX_TAG: &str = "$hex";
+const BASE64_TAG: &str = "$base64";
+const KEY_TAG_PREFIX: char = '$';
+
+/// How byte strings that are not UTF-8 show up in decoded JSON
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ByteEncoding {
+    /// `{"$hex": "<hex>"}`
+    #[default]
+    Hex,
+    /// `{"$base64": "<base64>"}`
+    Base64,
+}
+
+impl FromStr for ByteEncoding {
+    type Err = BencodeError;
+
+    fn from_str(string: &str) -> Result<Self, BencodeError> {
+        match string {
+            "hex" => Ok(Self::Hex),
+            "base64" => Ok(Self::Base64),
+            _ => Err(BencodeError::UnknownByteEncoding {
+                name: string.into(),
+            }),
+        }
+    }
+}
+
+impl ByteEncoding {
+    fn tag(&self) -> &'static str {
+        match self {
+            Self::Hex => HEX_TAG,
+            Self::Base64 => BASE64_TAG,
+        }
+    }
+
+    fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            Self::Hex => hex::encode(bytes),
+            Self::Base64 => BASE64.encode(bytes),
+        }
+    }
 }
 
+/// Decodes a single bencoded value to JSON, tagging byte strings that are not UTF-8
+pub fn decode_bencoded_value(
+    encoded_value: &[u8],
+    byte_encoding: ByteEncoding,
+) -> Result<serde_json::Value, BencodeError> {
+    let node = parser::parse(encoded_value)?;
+    Ok(convert_bencode_value_to_json_value(
+        &node.value,
+        byte_encoding,
+    ))
+}
+
+// Lossless: byte strings that are not UTF-8 are tagged with their encoding
 fn convert_bencode_value_to_json_value(
-    value: serde_bencode::value::Value,
-) -> anyhow::Result<serde_json::Value> {
+    value: &parser::Value,
+    byte_encoding: ByteEncoding,
+) -> serde_json::Value {
     match value {
-        serde_bencode::value::Value::Bytes(bytes) => {
-            let string = String::from_utf8(bytes)?;
-            Ok(serde_json::Value::String(string))
+        parser::Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+            Ok(string) => serde_json::Value::String(string.into()),
+            Err(_) => {
+                let mut map = serde_json::Map::new();
+                map.insert(
+                    byte_encoding.tag().into(),
+                    serde_json::Value::String(byte_encoding.encode(bytes)),
+                );
+                serde_json::Value::Object(map)
+            }
+        },
+        parser::Value::Int(int) => serde_json::Value::Number(serde_json::Number::from(*int)),
+        parser::Value::List(nodes) => serde_json::Value::Array(
+            nodes
+                .iter()
+                .map(|node| convert_bencode_value_to_json_value(&node.value, byte_encoding))
+                .collect(),
+        ),
+        parser::Value::Dict(entries) => {
+            let mut map = serde_json::Map::new();
+            for (key_bytes, node) in entries {
+                let key = convert_key_to_json_key(key_bytes, byte_encoding);
+                map.insert(
+                    key,
+                    convert_bencode_value_to_json_value(&node.value, byte_encoding),
+                );
+            }
+            serde_json::Value::Object(map)
         }
-        serde_bencode::value::Value::Int(int) => {
-            Ok(serde_json::Value::Number(serde_json::Number::from(int)))
+    }
+}
+
+// Keys starting with the tag prefix are tagged too, so they cannot be mistaken for tags
+fn convert_key_to_json_key(key_bytes: &[u8], byte_encoding: ByteEncoding) -> String {
+    match std::str::from_utf8(key_bytes) {
+        Ok(key) if !key.starts_with(KEY_TAG_PREFIX) => key.into(),
+        _ => format!(
+            "{}:{}",
+            byte_encoding.tag(),
+            byte_encoding.encode(key_bytes)
+        ),
+    }
+}
+
+/// JSON to canonical bencode: keys sorted as raw bytes, integers only, UTF-8 strings as is.
+/// A key starting with a tag and a colon has to be written tagged itself.
+pub fn encode_json_value(value: &serde_json::Value) -> Result<Vec<u8>, BencodeError> {
+    let mut bytes = vec![];
+    encode_json_value_into(value, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn encode_json_value_into(
+    value: &serde_json::Value,
+    bytes: &mut Vec<u8>,
+) -> Result<(), BencodeError> {
+    match value {
+        serde_json::Value::String(string) => encode_byte_string(string.as_bytes(), bytes),
+        serde_json::Value::Number(number) => {
+            let int = number
+                .as_i64()
+                .ok_or_else(|| BencodeError::NumberNotInteger {
+                    number: number.to_string(),
+                })?;
+            bytes.extend_from_slice(format!("i{int}e").as_bytes());
         }
-        serde_bencode::value::Value::List(values) => {
-            let array = values
-                .into_iter()
-                .map(|item| convert_bencode_value_to_json_value(item))
-                .collect::<anyhow::Result<Vec<serde_json::Value>>>()?;
-            Ok(serde_json::Value::Array(array))
+        serde_json::Value::Array(values) => {
+            bytes.push(b'l');
+            for value in values {
+                encode_json_value_into(value, bytes)?;
+            }
+            bytes.push(b'e');
         }
-        serde_bencode::value::Value::Dict(hash_map) => {
-            let mut map = serde_json::Map::new();
-            for (key_bytes, json_value) in hash_map {
-                let key = String::from_utf8(key_bytes)
-                    .map_err(|_| anyhow::anyhow!("Failed to convert key to string"))?;
-                let value = convert_bencode_value_to_json_value(json_value)?;
-                map.insert(key, value);
+        serde_json::Value::Object(map) => {
+            if let Some(raw_bytes) = tagged_bytes(map)? {
+                encode_byte_string(&raw_bytes, bytes);
+                return Ok(());
             }
-            Ok(serde_json::Value::Object(map))
+
+            let mut entries = map
+                .iter()
+                .map(|(key, value)| Ok((key_bytes(key)?, value)))
+                .collect::<Result<Vec<(Vec<u8>, &serde_json::Value)>, BencodeError>>()?;
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            if entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                return Err(BencodeError::DuplicateKeys);
+            }
+
+            bytes.push(b'd');
+            for (key, value) in entries {
+                encode_byte_string(&key, bytes);
+                encode_json_value_into(value, bytes)?;
+            }
+            bytes.push(b'e');
+        }
+        serde_json::Value::Bool(_) | serde_json::Value::Null => {
+            return Err(BencodeError::ValueNotEncodable {
+                value: value.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn encode_byte_string(string: &[u8], bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(string.len().to_string().as_bytes());
+    bytes.push(b':');
+    bytes.extend_from_slice(string);
+}
+
+// An object holding only a byte string tag stands for raw bytes
+fn tagged_bytes(
+    map: &serde_json::Map<String, serde_json::Value>,
+) -> Result<Option<Vec<u8>>, BencodeError> {
+    let Some((tag, value)) = map.iter().next().filter(|_| map.len() == 1) else {
+        return Ok(None);
+    };
+    if tag != HEX_TAG && tag != BASE64_TAG {
+        return Ok(None);
+    }
+    match value {
+        serde_json::Value::String(encoded) => decode_tagged(tag, encoded).map(Some),
+        _ => Err(BencodeError::TagNotString { tag: tag.clone() }),
+    }
+}
+
+fn key_bytes(key: &str) -> Result<Vec<u8>, BencodeError> {
+    for tag in [HEX_TAG, BASE64_TAG] {
+        if let Some(encoded) = key.strip_prefix(tag).and_then(|key| key.strip_prefix(':')) {
+            return decode_tagged(tag, encoded);
         }
     }
+    Ok(key.as_bytes().to_vec())
+}
+
+fn decode_tagged(tag: &str, encoded: &str) -> Result<Vec<u8>, BencodeError> {
+    match tag {
+        BASE64_TAG => Ok(BASE64.decode(encoded)?),
+        _ => Ok(hex::decode(encoded)?),
+    }
 }
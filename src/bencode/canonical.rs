@@ -0,0 +1,284 @@
+//! Canonical bencode: finding the constructs that have another encoding, and
+//! rewriting values canonically
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+};
+
+use super::{
+    encode_byte_string,
+    parser::{self, Node, ParseError, Value},
+};
+
+/// Constructs that parse fine but have another, canonical, encoding
+#[derive(Debug, Clone, PartialEq)]
+pub enum IssueKind {
+    /// An integer like i03e
+    IntegerLeadingZeros,
+    /// i-0e
+    NegativeZero,
+    /// A string length like 03:abc
+    LengthLeadingZeros,
+    /// A dictionary key smaller than one before it
+    UnsortedKey {
+        /// The key, as raw bytes
+        key: Vec<u8>,
+    },
+    /// A dictionary key found earlier in the same dictionary
+    DuplicateKey {
+        /// The key, as raw bytes
+        key: Vec<u8>,
+    },
+}
+
+/// A non-canonical construct and where it is
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    /// Byte offset of the construct in the input
+    pub offset: usize,
+    /// What is not canonical
+    pub kind: IssueKind,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Byte {}: ", self.offset)?;
+        match &self.kind {
+            IssueKind::IntegerLeadingZeros => write!(f, "integer has leading zeros"),
+            IssueKind::NegativeZero => write!(f, "integer is negative zero"),
+            IssueKind::LengthLeadingZeros => write!(f, "string length has leading zeros"),
+            IssueKind::UnsortedKey { key } => {
+                write!(f, "key {} is not sorted", String::from_utf8_lossy(key))
+            }
+            IssueKind::DuplicateKey { key } => {
+                write!(f, "key {} is duplicated", String::from_utf8_lossy(key))
+            }
+        }
+    }
+}
+
+/// Every non-canonical construct of the input, in input order
+pub fn validate(input: &[u8]) -> Result<Vec<Issue>, ParseError> {
+    let root = parser::parse(input)?;
+    let mut issues = vec![];
+    validate_node(input, &root, &mut issues);
+    Ok(issues)
+}
+
+fn validate_node(input: &[u8], node: &Node, issues: &mut Vec<Issue>) {
+    let start = node.span.start;
+    match &node.value {
+        Value::Int(int) => {
+            let text = &input[start + 1..node.span.end - 1];
+            let digits = text.strip_prefix(b"-").unwrap_or(text);
+            if *int == 0 && text[0] == b'-' {
+                issues.push(Issue {
+                    offset: start,
+                    kind: IssueKind::NegativeZero,
+                });
+            } else if digits.len() > 1 && digits[0] == b'0' {
+                issues.push(Issue {
+                    offset: start,
+                    kind: IssueKind::IntegerLeadingZeros,
+                });
+            }
+        }
+        Value::Bytes(_) => validate_length(input, start, issues),
+        Value::List(nodes) => {
+            for node in nodes {
+                validate_node(input, node, issues);
+            }
+        }
+        Value::Dict(entries) => {
+            // Keys have no span of their own, each one starts where the previous value ends
+            let mut key_start = start + 1;
+            let mut seen_keys = HashSet::new();
+            let mut greatest_key: Option<&[u8]> = None;
+            for (key, node) in entries {
+                validate_length(input, key_start, issues);
+                let kind = if !seen_keys.insert(*key) {
+                    Some(IssueKind::DuplicateKey { key: key.to_vec() })
+                } else if greatest_key.is_some_and(|greatest_key| *key < greatest_key) {
+                    Some(IssueKind::UnsortedKey { key: key.to_vec() })
+                } else {
+                    None
+                };
+                if let Some(kind) = kind {
+                    issues.push(Issue {
+                        offset: key_start,
+                        kind,
+                    });
+                }
+                if greatest_key.is_none_or(|greatest_key| *key > greatest_key) {
+                    greatest_key = Some(key);
+                }
+
+                validate_node(input, node, issues);
+                key_start = node.span.end;
+            }
+        }
+    }
+}
+
+// "0:" is the only length allowed to start with a zero
+fn validate_length(input: &[u8], start: usize, issues: &mut Vec<Issue>) {
+    if input[start] == b'0' && input[start + 1] != b':' {
+        issues.push(Issue {
+            offset: start,
+            kind: IssueKind::LengthLeadingZeros,
+        });
+    }
+}
+
+/// Canonical encoding of a parsed value: keys sorted, the first of duplicated keys kept
+pub fn encode(node: &Node) -> Vec<u8> {
+    let mut bytes = vec![];
+    encode_into(node, &mut bytes);
+    bytes
+}
+
+fn encode_into(node: &Node, bytes: &mut Vec<u8>) {
+    match &node.value {
+        Value::Int(int) => bytes.extend_from_slice(format!("i{int}e").as_bytes()),
+        Value::Bytes(string) => encode_byte_string(string, bytes),
+        Value::List(nodes) => {
+            bytes.push(b'l');
+            for node in nodes {
+                encode_into(node, bytes);
+            }
+            bytes.push(b'e');
+        }
+        Value::Dict(entries) => {
+            let mut entries: Vec<&(&[u8], Node)> = entries.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            entries.dedup_by_key(|(key, _)| *key);
+
+            bytes.push(b'd');
+            for (key, node) in entries {
+                encode_byte_string(key, bytes);
+                encode_into(node, bytes);
+            }
+            bytes.push(b'e');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+
+    fn issues_of(input: &[u8]) -> Vec<Issue> {
+        validate(input).unwrap()
+    }
+
+    fn canonical(input: &[u8]) -> Vec<u8> {
+        encode(&parser::parse(input).unwrap())
+    }
+
+    #[test]
+    fn canonical_input_has_no_issues() {
+        let input = b"d1:ai0e1:bli-1e0:e1:cd1:xi10eee";
+        assert!(issues_of(input).is_empty());
+        assert_eq!(canonical(input), input);
+    }
+
+    #[test]
+    fn integers_with_leading_zeros_or_negative_zero_are_reported() {
+        assert_eq!(
+            issues_of(b"li007ei-0ei-01ee"),
+            vec![
+                Issue {
+                    offset: 1,
+                    kind: IssueKind::IntegerLeadingZeros,
+                },
+                Issue {
+                    offset: 6,
+                    kind: IssueKind::NegativeZero,
+                },
+                Issue {
+                    offset: 10,
+                    kind: IssueKind::IntegerLeadingZeros,
+                },
+            ]
+        );
+        assert_eq!(canonical(b"li007ei-0ei-01ee"), b"li7ei0ei-1ee");
+    }
+
+    #[test]
+    fn string_lengths_with_leading_zeros_are_reported_for_keys_and_values() {
+        assert_eq!(
+            issues_of(b"d01:a03:abce"),
+            vec![
+                Issue {
+                    offset: 1,
+                    kind: IssueKind::LengthLeadingZeros,
+                },
+                Issue {
+                    offset: 5,
+                    kind: IssueKind::LengthLeadingZeros,
+                },
+            ]
+        );
+        assert_eq!(canonical(b"d01:a03:abce"), b"d1:a3:abce");
+    }
+
+    #[test]
+    fn unsorted_and_duplicate_keys_are_reported_at_the_key() {
+        let input = b"d1:bi1e1:ai2e1:bi3ee";
+        assert_eq!(
+            issues_of(input),
+            vec![
+                Issue {
+                    offset: 7,
+                    kind: IssueKind::UnsortedKey { key: b"a".to_vec() },
+                },
+                Issue {
+                    offset: 13,
+                    kind: IssueKind::DuplicateKey { key: b"b".to_vec() },
+                },
+            ]
+        );
+        // Keys are sorted and the first of duplicated keys is kept
+        assert_eq!(canonical(input), b"d1:ai2e1:bi1ee");
+        assert_eq!(
+            issues_of(input)[0].to_string(),
+            "Byte 7: key a is not sorted"
+        );
+    }
+
+    #[test]
+    fn rewriting_a_non_canonical_info_dict_changes_its_hash() {
+        let input = b"d4:infod6:lengthi010e4:name1:aee";
+        let info = parser::parse(input)
+            .unwrap()
+            .get(b"info")
+            .unwrap()
+            .span
+            .clone();
+        let canonical_input = canonical(input);
+        let canonical_info = parser::parse(&canonical_input)
+            .unwrap()
+            .get(b"info")
+            .unwrap()
+            .span
+            .clone();
+
+        assert_eq!(
+            &canonical_input[canonical_info.clone()],
+            b"d6:lengthi10e4:name1:ae"
+        );
+        assert_ne!(
+            Sha1::digest(&input[info]),
+            Sha1::digest(&canonical_input[canonical_info])
+        );
+        assert!(issues_of(&canonical_input).is_empty());
+    }
+
+    #[test]
+    fn unparsable_input_is_an_error() {
+        assert!(validate(b"d1:a").is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! Incremental decoding of bencoded values arriving in chunks
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{
+    error::BencodeError,
+    parser::{self, Limits, ParseError, ParseErrorKind},
+};
+
+const READ_CHUNK_SIZE: usize = 16_384; // 16 KiB
+
+#[derive(Debug, PartialEq)]
+/// What [`StreamDecoder::decode`] found in the bytes pushed so far
+pub enum Decoded {
+    /// The value is not complete yet
+    NeedMoreData,
+    /// The bytes of exactly one value, they parse without error
+    Complete(Vec<u8>),
+}
+
+/// Splits bencoded values out of input arriving in chunks, like network reads.
+/// Values only get parsed once complete, the scan in between is resumed at the
+/// last complete token. Whitespace between values is skipped. Error offsets are
+/// relative to the start of the value, and the decoder should not be used anymore
+/// after an error.
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    buffer: Vec<u8>,
+    // Start of the first token not scanned yet
+    position: usize,
+    depth: usize,
+    limits: Limits,
+}
+
+impl StreamDecoder {
+    /// Appends bytes to the input
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Takes the next value out of the input once it is complete
+    pub fn decode(&mut self) -> Result<Decoded, ParseError> {
+        // Values may be separated by whitespace, like lines of a file
+        if self.depth == 0 {
+            let whitespace_length = self
+                .buffer
+                .iter()
+                .take_while(|byte| byte.is_ascii_whitespace())
+                .count();
+            self.buffer.drain(..whitespace_length);
+        }
+
+        loop {
+            let Some(&byte) = self.buffer.get(self.position) else {
+                return self.need_more_data();
+            };
+            match byte {
+                b'i' => match self.scan_integer()? {
+                    Some(end) => self.position = end,
+                    None => return self.need_more_data(),
+                },
+                b'0'..=b'9' => match self.scan_bytes()? {
+                    Some(end) => self.position = end,
+                    None => return self.need_more_data(),
+                },
+                b'l' | b'd' if self.depth >= self.limits.max_depth => {
+                    return Err(self.error(ParseErrorKind::DepthLimitExceeded {
+                        limit: self.limits.max_depth,
+                    }))
+                }
+                b'l' | b'd' => {
+                    self.depth += 1;
+                    self.position += 1;
+                }
+                b'e' if self.depth > 0 => {
+                    self.depth -= 1;
+                    self.position += 1;
+                }
+                found => {
+                    return Err(self.error(ParseErrorKind::UnexpectedByte {
+                        expected: "'i', 'l', 'd' or a digit",
+                        found,
+                    }))
+                }
+            }
+
+            if self.depth == 0 {
+                let bytes: Vec<u8> = self.buffer.drain(..self.position).collect();
+                self.position = 0;
+                // The scan only finds where values end, the parser checks them
+                parser::parse_with_limits(&bytes, self.limits)?;
+                return Ok(Decoded::Complete(bytes));
+            }
+        }
+    }
+
+    /// Called once the input is over: the last value if complete, None if nothing is left
+    pub fn finish(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        match self.decode()? {
+            Decoded::Complete(bytes) => Ok(Some(bytes)),
+            Decoded::NeedMoreData if self.buffer.is_empty() => Ok(None),
+            Decoded::NeedMoreData => Err(ParseError {
+                offset: self.buffer.len(),
+                kind: ParseErrorKind::UnexpectedEnd {
+                    expected: "the rest of the value",
+                },
+            }),
+        }
+    }
+
+    /// Reads until a value is complete, None when the reader ends between values
+    pub async fn read_value<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<Vec<u8>>, BencodeError> {
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        loop {
+            if let Decoded::Complete(bytes) = self.decode()? {
+                return Ok(Some(bytes));
+            }
+            let count = reader.read(&mut chunk).await?;
+            if count == 0 {
+                return Ok(self.finish()?);
+            }
+            self.push(&chunk[..count]);
+        }
+    }
+
+    // i<digits>e, returns where the integer ends if it is all there
+    fn scan_integer(&self) -> Result<Option<usize>, ParseError> {
+        for (index, &byte) in self.buffer.iter().enumerate().skip(self.position + 1) {
+            match byte {
+                b'e' => return Ok(Some(index + 1)),
+                b'0'..=b'9' | b'-' => {}
+                found => {
+                    return Err(ParseError {
+                        offset: index,
+                        kind: ParseErrorKind::UnexpectedByte {
+                            expected: "a digit or the end of the integer",
+                            found,
+                        },
+                    })
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // <length>:<bytes>, returns where the string ends if it is all there
+    fn scan_bytes(&self) -> Result<Option<usize>, ParseError> {
+        let mut length: usize = 0;
+        for (index, &byte) in self.buffer.iter().enumerate().skip(self.position) {
+            match byte {
+                b':' => {
+                    let end = index + 1 + length;
+                    if end > self.limits.max_input_length {
+                        return Err(self.too_long_error(end));
+                    }
+                    return Ok((end <= self.buffer.len()).then_some(end));
+                }
+                b'0'..=b'9' => {
+                    length = length
+                        .checked_mul(10)
+                        .and_then(|length| length.checked_add((byte - b'0') as usize))
+                        .ok_or(ParseError {
+                            offset: self.position,
+                            kind: ParseErrorKind::IntegerOutOfRange,
+                        })?;
+                }
+                found => {
+                    return Err(ParseError {
+                        offset: index,
+                        kind: ParseErrorKind::UnexpectedByte {
+                            expected: "a digit or ':'",
+                            found,
+                        },
+                    })
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Everything buffered belongs to the value being scanned at this point
+    fn need_more_data(&self) -> Result<Decoded, ParseError> {
+        match self.buffer.len() > self.limits.max_input_length {
+            true => Err(self.too_long_error(self.buffer.len())),
+            false => Ok(Decoded::NeedMoreData),
+        }
+    }
+
+    fn too_long_error(&self, length: usize) -> ParseError {
+        ParseError {
+            offset: 0,
+            kind: ParseErrorKind::InputTooLong {
+                length,
+                limit: self.limits.max_input_length,
+            },
+        }
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            offset: self.position,
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every value of the input, pushed one byte at a time
+    fn decode_bytewise(input: &[u8]) -> Result<Vec<Vec<u8>>, ParseError> {
+        let mut decoder = StreamDecoder::default();
+        let mut values = vec![];
+        for byte in input {
+            decoder.push(&[*byte]);
+            if let Decoded::Complete(bytes) = decoder.decode()? {
+                values.push(bytes);
+            }
+        }
+        while let Some(bytes) = decoder.finish()? {
+            values.push(bytes);
+        }
+        Ok(values)
+    }
+
+    #[test]
+    fn values_are_complete_once_their_last_byte_arrives() {
+        let mut decoder = StreamDecoder::default();
+        decoder.push(b"d3:fooli1e");
+        assert_eq!(decoder.decode(), Ok(Decoded::NeedMoreData));
+        decoder.push(b"ee4:");
+        assert_eq!(
+            decoder.decode(),
+            Ok(Decoded::Complete(b"d3:fooli1eee".to_vec()))
+        );
+        assert_eq!(decoder.decode(), Ok(Decoded::NeedMoreData));
+        decoder.push(b"spam");
+        assert_eq!(decoder.decode(), Ok(Decoded::Complete(b"4:spam".to_vec())));
+    }
+
+    #[test]
+    fn whitespace_between_values_is_skipped() {
+        assert_eq!(
+            decode_bytewise(b"i1e\n 3:abc\r\n\tle\n"),
+            Ok(vec![b"i1e".to_vec(), b"3:abc".to_vec(), b"le".to_vec()])
+        );
+        assert_eq!(decode_bytewise(b" \n"), Ok(vec![]));
+    }
+
+    #[test]
+    fn whitespace_within_a_value_is_an_error() {
+        let error = decode_bytewise(b"l i1ee").unwrap_err();
+        assert_eq!(error.offset, 1);
+        assert!(matches!(
+            error.kind,
+            ParseErrorKind::UnexpectedByte { found: b' ', .. }
+        ));
+    }
+
+    #[test]
+    fn a_value_cut_short_is_an_error_at_the_end() {
+        let mut decoder = StreamDecoder::default();
+        decoder.push(b"\nl4:ab");
+        assert_eq!(decoder.decode(), Ok(Decoded::NeedMoreData));
+        assert_eq!(
+            decoder.finish(),
+            Err(ParseError {
+                offset: 5,
+                kind: ParseErrorKind::UnexpectedEnd {
+                    expected: "the rest of the value",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn oversized_string_lengths_are_rejected_before_they_arrive() {
+        let mut decoder = StreamDecoder::default();
+        decoder.push(b"999999999999:");
+        assert!(matches!(
+            decoder.decode(),
+            Err(ParseError {
+                kind: ParseErrorKind::InputTooLong { .. },
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn values_are_read_from_an_async_reader() {
+        let mut reader: &[u8] = b"i1e\nd1:ai2ee\n";
+        let mut decoder = StreamDecoder::default();
+        assert_eq!(
+            decoder.read_value(&mut reader).await.unwrap(),
+            Some(b"i1e".to_vec())
+        );
+        assert_eq!(
+            decoder.read_value(&mut reader).await.unwrap(),
+            Some(b"d1:ai2ee".to_vec())
+        );
+        assert_eq!(decoder.read_value(&mut reader).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! Structural differences between two bencoded documents
+
+use std::fmt::{self, Display, Formatter};
+
+use super::{
+    dump::{key_preview, summary},
+    parser::{self, Node, ParseError, Value},
+};
+
+/// How a value differs between the two documents
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Only the first document has a value at the path
+    OnlyInFirst {
+        /// Summary of the value
+        value: String,
+    },
+    /// Only the second document has a value at the path
+    OnlyInSecond {
+        /// Summary of the value
+        value: String,
+    },
+    /// Both documents have a value at the path, they differ
+    Changed {
+        /// Summary of the value in the first document
+        first: String,
+        /// Summary of the value in the second document
+        second: String,
+    },
+    /// Same keys and values, which still changes the hash of the dictionary
+    KeyOrder,
+    /// Same value, like i3e and i03e
+    Encoding,
+}
+
+/// A difference at a path like `info.files[0].length`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    /// Where the values are, empty for the root value
+    pub path: String,
+    /// How they differ
+    pub change: Change,
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "(root)"
+        } else {
+            &self.path
+        };
+        match &self.change {
+            Change::OnlyInFirst { value } => write!(f, "{path}: only in the first, {value}"),
+            Change::OnlyInSecond { value } => write!(f, "{path}: only in the second, {value}"),
+            Change::Changed { first, second } => write!(f, "{path}: {first} -> {second}"),
+            Change::KeyOrder => write!(f, "{path}: same keys in another order"),
+            Change::Encoding => write!(f, "{path}: same value, encoded differently"),
+        }
+    }
+}
+
+/// Structural differences between two bencoded documents, in the first one's order
+pub fn diff(first_input: &[u8], second_input: &[u8]) -> Result<Vec<Difference>, ParseError> {
+    let first = parser::parse(first_input)?;
+    let second = parser::parse(second_input)?;
+    let mut differ = Differ {
+        first_input,
+        second_input,
+        differences: vec![],
+    };
+    differ.diff_nodes(String::new(), &first, &second);
+    Ok(differ.differences)
+}
+
+struct Differ<'a> {
+    first_input: &'a [u8],
+    second_input: &'a [u8],
+    differences: Vec<Difference>,
+}
+
+impl Differ<'_> {
+    fn diff_nodes(&mut self, path: String, first: &Node, second: &Node) {
+        match (&first.value, &second.value) {
+            (Value::Dict(first_entries), Value::Dict(second_entries)) => {
+                for (key, first_node) in first_entries {
+                    let key_path = Self::key_path(&path, key);
+                    match second.get(key) {
+                        Some(second_node) => self.diff_nodes(key_path, first_node, second_node),
+                        None => self.push(
+                            key_path,
+                            Change::OnlyInFirst {
+                                value: summary(&first_node.value),
+                            },
+                        ),
+                    }
+                }
+                for (key, second_node) in second_entries {
+                    if first.get(key).is_none() {
+                        self.push(
+                            Self::key_path(&path, key),
+                            Change::OnlyInSecond {
+                                value: summary(&second_node.value),
+                            },
+                        );
+                    }
+                }
+
+                let common_keys = |entries: &[(&[u8], Node)], other: &Node| -> Vec<Vec<u8>> {
+                    entries
+                        .iter()
+                        .filter(|(key, _)| other.get(key).is_some())
+                        .map(|(key, _)| key.to_vec())
+                        .collect()
+                };
+                if common_keys(first_entries, second) != common_keys(second_entries, first) {
+                    self.push(path, Change::KeyOrder);
+                }
+            }
+            (Value::List(first_nodes), Value::List(second_nodes)) => {
+                for (index, first_node) in first_nodes.iter().enumerate() {
+                    let index_path = format!("{path}[{index}]");
+                    match second_nodes.get(index) {
+                        Some(second_node) => self.diff_nodes(index_path, first_node, second_node),
+                        None => self.push(
+                            index_path,
+                            Change::OnlyInFirst {
+                                value: summary(&first_node.value),
+                            },
+                        ),
+                    }
+                }
+                for (index, second_node) in second_nodes.iter().enumerate().skip(first_nodes.len())
+                {
+                    self.push(
+                        format!("{path}[{index}]"),
+                        Change::OnlyInSecond {
+                            value: summary(&second_node.value),
+                        },
+                    );
+                }
+            }
+            (first_value, second_value) if first_value == second_value => {
+                if self.first_input[first.span.clone()] != self.second_input[second.span.clone()] {
+                    self.push(path, Change::Encoding);
+                }
+            }
+            (first_value, second_value) => self.push(
+                path,
+                Change::Changed {
+                    first: summary(first_value),
+                    second: summary(second_value),
+                },
+            ),
+        }
+    }
+
+    fn key_path(path: &str, key: &[u8]) -> String {
+        match path.is_empty() {
+            true => key_preview(key),
+            false => format!("{path}.{}", key_preview(key)),
+        }
+    }
+
+    fn push(&mut self, path: String, change: Change) {
+        self.differences.push(Difference { path, change });
+    }
+}
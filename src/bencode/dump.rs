@@ -0,0 +1,109 @@
+//! An indented tree of a bencoded value, for reading it
+
+use std::fmt::Write;
+
+use super::parser::{Node, Value};
+
+const INDENT: &str = "  ";
+const TEXT_PREVIEW_LENGTH: usize = 60; // characters
+const BINARY_PREVIEW_LENGTH: usize = 16; // bytes
+
+/// Indented tree of a parsed value, one line per value, each starting with the
+/// byte range of the value in the input
+pub fn dump(node: &Node) -> String {
+    let mut output = String::new();
+    // Ranges are padded to the longest one, which lines the tree up
+    let offset_width = node.span.end.to_string().len();
+    dump_into(node, "", 0, offset_width * 2 + 2, &mut output);
+    output
+}
+
+fn dump_into(node: &Node, label: &str, depth: usize, span_width: usize, output: &mut String) {
+    let span = format!("{}..{}", node.span.start, node.span.end);
+    let indent = INDENT.repeat(depth);
+    let _ = writeln!(
+        output,
+        "{span:<span_width$} {indent}{label}{}",
+        summary(&node.value)
+    );
+    match &node.value {
+        Value::List(nodes) => {
+            for (index, node) in nodes.iter().enumerate() {
+                let label = format!("[{index}] ");
+                dump_into(node, &label, depth + 1, span_width, output);
+            }
+        }
+        Value::Dict(entries) => {
+            for (key, node) in entries {
+                let label = format!("{}: ", key_preview(key));
+                dump_into(node, &label, depth + 1, span_width, output);
+            }
+        }
+        Value::Int(_) | Value::Bytes(_) => {}
+    }
+}
+
+// One line describing a value, without its children
+pub(crate) fn summary(value: &Value) -> String {
+    match value {
+        Value::Int(int) => int.to_string(),
+        Value::Bytes(bytes) => bytes_preview(bytes),
+        Value::List(nodes) => match nodes.len() {
+            1 => "list (1 item)".to_string(),
+            count => format!("list ({count} items)"),
+        },
+        Value::Dict(entries) => match entries.len() {
+            1 => "dict (1 entry)".to_string(),
+            count => format!("dict ({count} entries)"),
+        },
+    }
+}
+
+// Text is quoted, anything else shows as hex, both cut past their preview length
+fn bytes_preview(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.chars().any(char::is_control) => {
+            let preview: String = text.chars().take(TEXT_PREVIEW_LENGTH).collect();
+            let ellipsis = if preview.len() < text.len() {
+                "..."
+            } else {
+                ""
+            };
+            format!("{preview:?}{ellipsis} ({} bytes)", bytes.len())
+        }
+        _ => {
+            let preview = &bytes[..bytes.len().min(BINARY_PREVIEW_LENGTH)];
+            let ellipsis = if preview.len() < bytes.len() {
+                "..."
+            } else {
+                ""
+            };
+            format!("<{} bytes> {}{ellipsis}", bytes.len(), hex::encode(preview))
+        }
+    }
+}
+
+pub(crate) fn key_preview(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) if !key.chars().any(char::is_control) => key.to_string(),
+        _ => format!("<{}>", hex::encode(key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::parser::parse;
+
+    #[test]
+    fn lines_start_with_the_byte_range_of_their_value() {
+        let node = parse(b"d3:agei42e5:filesl4:a.mdee").unwrap();
+        assert_eq!(
+            dump(&node),
+            "0..26  dict (2 entries)\n\
+             6..10    age: 42\n\
+             17..25   files: list (1 item)\n\
+             18..24     [0] \"a.md\" (4 bytes)\n"
+        );
+    }
+}
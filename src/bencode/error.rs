@@ -0,0 +1,52 @@
+//! Errors of the bencode subsystem
+
+use thiserror::Error;
+
+use super::parser::ParseError;
+
+/// Bencode that cannot be parsed, decoded or encoded
+#[derive(Debug, Error)]
+pub enum BencodeError {
+    /// Input that is not valid bencode
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    /// Bencode that does not fit the type it is decoded into, or a type that cannot be encoded
+    #[error(transparent)]
+    Serde(#[from] serde_bencode::Error),
+    /// A byte encoding other than hex and base64
+    #[error("Unknown byte encoding '{name}', expected hex or base64")]
+    UnknownByteEncoding {
+        /// The name given
+        name: String,
+    },
+    /// A JSON number with a fraction or an exponent
+    #[error("Only integers can be encoded, got {number}")]
+    NumberNotInteger {
+        /// The number, as written in JSON
+        number: String,
+    },
+    /// A JSON boolean or null
+    #[error("Bencode has no booleans or null, got {value}")]
+    ValueNotEncodable {
+        /// The value, as written in JSON
+        value: String,
+    },
+    /// Two JSON keys standing for the same bytes
+    #[error("Dictionary has duplicate keys")]
+    DuplicateKeys,
+    /// A raw bytes tag holding something else than a string
+    #[error("'{tag}' must hold a string")]
+    TagNotString {
+        /// The tag, $hex or $base64
+        tag: String,
+    },
+    /// A $hex tag holding something else than hex
+    #[error(transparent)]
+    Hex(#[from] hex::FromHexError),
+    /// A $base64 tag holding something else than base64
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    /// The input could not be read
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
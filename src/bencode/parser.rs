@@ -0,0 +1,467 @@
+//! A parser borrowing its byte strings from the input, keeping the span of every value
+
+use std::{
+    error,
+    fmt::{self, Display, Formatter},
+    ops::Range,
+};
+
+/// Lists and dictionaries nested deeper are rejected
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+/// Longer inputs are rejected before parsing
+pub const DEFAULT_MAX_INPUT_LENGTH: usize = 64 * 1024 * 1024; // 64 MiB
+
+/// A bencoded value borrowing its byte strings from the input
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    /// `i<integer>e`
+    Int(i64),
+    /// `<length>:<bytes>`
+    Bytes(&'a [u8]),
+    /// `l<values>e`
+    List(Vec<Node<'a>>),
+    /// `d<keys and values>e`, entries in input order, which canonical bencode requires
+    /// to be sorted
+    Dict(Vec<(&'a [u8], Node<'a>)>),
+}
+
+/// A value along with the bytes of the input it was parsed from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<'a> {
+    /// The parsed value
+    pub value: Value<'a>,
+    /// Where the value is in the input, from its first to its last byte
+    pub span: Range<usize>,
+}
+
+impl<'a> Node<'a> {
+    /// The value of a dictionary key, the first one if the key is duplicated
+    pub fn get(&self, key: &[u8]) -> Option<&Node<'a>> {
+        match &self.value {
+            Value::Dict(entries) => entries
+                .iter()
+                .find(|(entry_key, _)| *entry_key == key)
+                .map(|(_, node)| node),
+            _ => None,
+        }
+    }
+}
+
+/// Limits protecting against hostile input
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Deepest nesting of lists and dictionaries
+    pub max_depth: usize,
+    /// Longest input, in bytes
+    pub max_input_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_input_length: DEFAULT_MAX_INPUT_LENGTH,
+        }
+    }
+}
+
+/// Why the input is not valid bencode
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// The input ended in the middle of a value
+    UnexpectedEnd {
+        /// What should have come next
+        expected: &'static str,
+    },
+    /// A byte that cannot come at this point
+    UnexpectedByte {
+        /// What should have come instead
+        expected: &'static str,
+        /// The byte found
+        found: u8,
+    },
+    /// An integer or a string length that does not fit in 64 bits
+    IntegerOutOfRange,
+    /// Bytes after the end of the value
+    TrailingBytes,
+    /// Lists and dictionaries nested deeper than [`Limits::max_depth`]
+    DepthLimitExceeded {
+        /// The depth allowed
+        limit: usize,
+    },
+    /// An input longer than [`Limits::max_input_length`]
+    InputTooLong {
+        /// Length of the input
+        length: usize,
+        /// The length allowed
+        limit: usize,
+    },
+}
+
+/// Invalid bencode, with the byte offset where it was found
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Byte offset in the input
+    pub offset: usize,
+    /// What is wrong
+    pub kind: ParseErrorKind,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnexpectedEnd { expected } => {
+                write!(
+                    f,
+                    "Unexpected end of input at byte {}, expected {expected}",
+                    self.offset
+                )
+            }
+            ParseErrorKind::UnexpectedByte { expected, found } => write!(
+                f,
+                "Unexpected byte {:?} at byte {}, expected {expected}",
+                char::from(*found),
+                self.offset
+            ),
+            ParseErrorKind::IntegerOutOfRange => {
+                write!(f, "Integer at byte {} does not fit in 64 bits", self.offset)
+            }
+            ParseErrorKind::TrailingBytes => {
+                write!(f, "Trailing bytes after the value at byte {}", self.offset)
+            }
+            ParseErrorKind::DepthLimitExceeded { limit } => write!(
+                f,
+                "Nesting deeper than {limit} levels at byte {}",
+                self.offset
+            ),
+            ParseErrorKind::InputTooLong { length, limit } => {
+                write!(
+                    f,
+                    "Input is {length} bytes long, at most {limit} are allowed"
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Parses a whole input holding exactly one value
+pub fn parse(input: &[u8]) -> Result<Node<'_>, ParseError> {
+    parse_with_limits(input, Limits::default())
+}
+
+/// Parses a whole input holding exactly one value, within the given limits
+pub fn parse_with_limits(input: &[u8], limits: Limits) -> Result<Node<'_>, ParseError> {
+    if input.len() > limits.max_input_length {
+        return Err(ParseError {
+            offset: 0,
+            kind: ParseErrorKind::InputTooLong {
+                length: input.len(),
+                limit: limits.max_input_length,
+            },
+        });
+    }
+
+    let mut parser = Parser {
+        input,
+        position: 0,
+        limits,
+    };
+    let node = parser.parse_value(0)?;
+    if parser.position != input.len() {
+        return Err(parser.error(ParseErrorKind::TrailingBytes));
+    }
+    Ok(node)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+    limits: Limits,
+}
+
+impl<'a> Parser<'a> {
+    fn parse_value(&mut self, depth: usize) -> Result<Node<'a>, ParseError> {
+        let start = self.position;
+        let value = match self.peek("a value")? {
+            b'i' => {
+                self.position += 1;
+                Value::Int(self.parse_integer(b'e')?)
+            }
+            b'l' | b'd' if depth >= self.limits.max_depth => {
+                return Err(self.error(ParseErrorKind::DepthLimitExceeded {
+                    limit: self.limits.max_depth,
+                }))
+            }
+            b'l' => {
+                self.position += 1;
+                let mut nodes = vec![];
+                while self.peek("a value or 'e'")? != b'e' {
+                    nodes.push(self.parse_value(depth + 1)?);
+                }
+                self.position += 1;
+                Value::List(nodes)
+            }
+            b'd' => {
+                self.position += 1;
+                let mut entries = vec![];
+                while self.peek("a key or 'e'")? != b'e' {
+                    let key = self.parse_bytes()?;
+                    entries.push((key, self.parse_value(depth + 1)?));
+                }
+                self.position += 1;
+                Value::Dict(entries)
+            }
+            b'0'..=b'9' => Value::Bytes(self.parse_bytes()?),
+            found => {
+                return Err(self.error(ParseErrorKind::UnexpectedByte {
+                    expected: "'i', 'l', 'd' or a digit",
+                    found,
+                }))
+            }
+        };
+
+        Ok(Node {
+            value,
+            span: start..self.position,
+        })
+    }
+
+    // <length>:<bytes>, borrowed from the input
+    fn parse_bytes(&mut self) -> Result<&'a [u8], ParseError> {
+        let length_offset = self.position;
+        let found = self.peek("a string length")?;
+        if !found.is_ascii_digit() {
+            return Err(self.error(ParseErrorKind::UnexpectedByte {
+                expected: "a string length",
+                found,
+            }));
+        }
+        let length = self.parse_integer(b':')?;
+
+        let start = self.position;
+        let remaining = self.input.len() - start;
+        if length as usize > remaining {
+            self.position = self.input.len();
+            return Err(ParseError {
+                offset: length_offset,
+                kind: ParseErrorKind::UnexpectedEnd {
+                    expected: "as many bytes as the string length",
+                },
+            });
+        }
+        self.position += length as usize;
+        Ok(&self.input[start..self.position])
+    }
+
+    // An optionally negative decimal integer, up to the terminator
+    fn parse_integer(&mut self, terminator: u8) -> Result<i64, ParseError> {
+        let start = self.position;
+        let is_negative = self.peek("a digit or '-'")? == b'-';
+        if is_negative {
+            self.position += 1;
+        }
+
+        let mut value: i64 = 0;
+        let mut digits_count = 0;
+        loop {
+            match self.peek("a digit")? {
+                byte @ b'0'..=b'9' => {
+                    let digit = (byte - b'0') as i64;
+                    value = value
+                        .checked_mul(10)
+                        .and_then(|value| match is_negative {
+                            true => value.checked_sub(digit),
+                            false => value.checked_add(digit),
+                        })
+                        .ok_or(ParseError {
+                            offset: start,
+                            kind: ParseErrorKind::IntegerOutOfRange,
+                        })?;
+                    digits_count += 1;
+                    self.position += 1;
+                }
+                byte if byte == terminator && digits_count > 0 => {
+                    self.position += 1;
+                    return Ok(value);
+                }
+                found => {
+                    return Err(self.error(ParseErrorKind::UnexpectedByte {
+                        expected: if digits_count > 0 {
+                            "a digit or the end of the integer"
+                        } else {
+                            "a digit"
+                        },
+                        found,
+                    }))
+                }
+            }
+        }
+    }
+
+    fn peek(&self, expected: &'static str) -> Result<u8, ParseError> {
+        self.input
+            .get(self.position)
+            .copied()
+            .ok_or_else(|| self.error(ParseErrorKind::UnexpectedEnd { expected }))
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            offset: self.position,
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of(input: &[u8]) -> ParseError {
+        parse(input).unwrap_err()
+    }
+
+    #[test]
+    fn nodes_keep_their_spans_and_borrow_the_input() {
+        let input = b"d4:infod6:lengthi12ee4:listl3:abcee";
+        let root = parse(input).unwrap();
+        assert_eq!(root.span, 0..input.len());
+
+        let info = root.get(b"info").unwrap();
+        assert_eq!(&input[info.span.clone()], b"d6:lengthi12ee");
+        assert_eq!(info.get(b"length").unwrap().value, Value::Int(12));
+
+        let Value::List(items) = &root.get(b"list").unwrap().value else {
+            panic!("list expected");
+        };
+        let Value::Bytes(bytes) = items[0].value else {
+            panic!("bytes expected");
+        };
+        assert_eq!(bytes, b"abc");
+        assert!(std::ptr::eq(bytes.as_ptr(), input[30..].as_ptr()));
+    }
+
+    #[test]
+    fn integers_cover_the_whole_64_bits_range() {
+        assert_eq!(
+            parse(b"i-9223372036854775808e").unwrap().value,
+            Value::Int(i64::MIN)
+        );
+        assert_eq!(
+            parse(b"i9223372036854775807e").unwrap().value,
+            Value::Int(i64::MAX)
+        );
+        assert_eq!(
+            error_of(b"li1ei9223372036854775808ee"),
+            ParseError {
+                offset: 5,
+                kind: ParseErrorKind::IntegerOutOfRange,
+            }
+        );
+    }
+
+    #[test]
+    fn errors_tell_the_offset_and_what_was_expected() {
+        assert_eq!(
+            error_of(b"li1ex"),
+            ParseError {
+                offset: 4,
+                kind: ParseErrorKind::UnexpectedByte {
+                    expected: "'i', 'l', 'd' or a digit",
+                    found: b'x',
+                },
+            }
+        );
+        assert_eq!(
+            error_of(b"i12"),
+            ParseError {
+                offset: 3,
+                kind: ParseErrorKind::UnexpectedEnd {
+                    expected: "a digit",
+                },
+            }
+        );
+        assert_eq!(
+            error_of(b"di1ei2ee"),
+            ParseError {
+                offset: 1,
+                kind: ParseErrorKind::UnexpectedByte {
+                    expected: "a string length",
+                    found: b'i',
+                },
+            }
+        );
+        assert_eq!(
+            error_of(b"ie"),
+            ParseError {
+                offset: 1,
+                kind: ParseErrorKind::UnexpectedByte {
+                    expected: "a digit",
+                    found: b'e',
+                },
+            }
+        );
+        assert_eq!(error_of(b"i1ei2e").kind, ParseErrorKind::TrailingBytes);
+        assert_eq!(error_of(b"i1ei2e").offset, 3);
+    }
+
+    #[test]
+    fn string_lengths_past_the_end_are_reported_at_the_length() {
+        assert_eq!(
+            error_of(b"l10:abce"),
+            ParseError {
+                offset: 1,
+                kind: ParseErrorKind::UnexpectedEnd {
+                    expected: "as many bytes as the string length",
+                },
+            }
+        );
+        // A huge length is not allocated
+        assert_eq!(error_of(b"9999999999:a").offset, 0);
+    }
+
+    #[test]
+    fn nesting_deeper_than_the_limit_is_rejected() {
+        let limits = Limits {
+            max_depth: 3,
+            ..Limits::default()
+        };
+        assert!(parse_with_limits(b"llleee", limits).is_ok());
+        assert_eq!(
+            parse_with_limits(b"lllleeee", limits).unwrap_err(),
+            ParseError {
+                offset: 3,
+                kind: ParseErrorKind::DepthLimitExceeded { limit: 3 },
+            }
+        );
+
+        // The default limit stops hostile input before the stack overflows
+        let hostile = [vec![b'l'; 100_000], vec![b'e'; 100_000]].concat();
+        assert_eq!(
+            error_of(&hostile).kind,
+            ParseErrorKind::DepthLimitExceeded {
+                limit: DEFAULT_MAX_DEPTH,
+            }
+        );
+    }
+
+    #[test]
+    fn inputs_longer_than_the_limit_are_rejected_before_parsing() {
+        let limits = Limits {
+            max_input_length: 4,
+            ..Limits::default()
+        };
+        assert_eq!(
+            parse_with_limits(b"5:abcde", limits).unwrap_err(),
+            ParseError {
+                offset: 0,
+                kind: ParseErrorKind::InputTooLong {
+                    length: 7,
+                    limit: 4,
+                },
+            }
+        );
+    }
+}
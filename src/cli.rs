@@ -1,22 +1,220 @@
+use std::net::SocketAddr;
+
+use bittorrent_starter_rust::bencode::ByteEncoding;
+use clap::{ArgAction, Args, Parser, Subcommand};
+use clap_complete::Shell;
+use tracing::level_filters::LevelFilter;
+
+#[derive(Parser)]
+#[command(version, about = "Inspect, create and download BitTorrent torrents")]
+pub struct Cli {
+    #[command(flatten)]
+    pub log_options: LogOptions,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// Command names keep their underscores, the dashed spellings are accepted too
+#[derive(Subcommand)]
 pub enum Command {
-    Decode,
-    Info,
-    Peers,
-    Handshake,
-    DownloadPiece,
-    Download,
-}
-
-impl Command {
-    pub fn from_str(string: &str) -> Option<Command> {
-        return match string {
-            "decode" => Some(Command::Decode),
-            "info" => Some(Command::Info),
-            "peers" => Some(Command::Peers),
-            "handshake" => Some(Command::Handshake),
-            "download_piece" => Some(Command::DownloadPiece),
-            "download" => Some(Command::Download),
-            _ => None,
-        };
+    /// Decode a bencoded value to JSON
+    Decode(DecodeOptions),
+    /// Show what a torrent file holds
+    Info {
+        /// Torrent file
+        file_path: String,
+        /// Print a JSON document
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the peers of the torrent's swarm
+    Peers {
+        /// Torrent file
+        file_path: String,
+        #[command(flatten)]
+        swarm_options: SwarmOptions,
+        /// Print a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+    /// Handshake with a peer of the swarm and show who it is
+    Handshake {
+        /// Torrent file
+        file_path: String,
+        #[command(flatten)]
+        swarm_options: SwarmOptions,
+        /// Print a JSON document
+        #[arg(long)]
+        json: bool,
+    },
+    /// Download and verify a single piece
+    #[command(name = "download_piece", alias = "download-piece")]
+    DownloadPiece {
+        /// Where the piece is written
+        #[arg(short, long)]
+        output: String,
+        /// Torrent file
+        file_path: String,
+        /// Zero-based index of the piece
+        piece_index: u32,
+        #[command(flatten)]
+        swarm_options: SwarmOptions,
+    },
+    /// Download a torrent, multi-file torrents into a directory
+    Download {
+        /// Where the file or directory is written
+        #[arg(short, long)]
+        output: String,
+        /// Torrent file
+        file_path: String,
+        #[command(flatten)]
+        swarm_options: SwarmOptions,
+    },
+    /// Download many torrents at once behind one listening port
+    #[command(name = "download_all", alias = "download-all")]
+    DownloadAll(DownloadAllOptions),
+    /// Create a torrent file from a file or a directory
+    Create(CreateOptions),
+    /// Encode JSON to canonical bencode, written raw to stdout
+    Encode {
+        /// JSON value, or - to read it from stdin
+        #[arg(allow_hyphen_values = true)]
+        json_value: String,
+    },
+    /// Report the non-canonical bencode of a torrent file
+    Lint {
+        /// Torrent file
+        file_path: String,
+        /// Write the canonical encoding to this file
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Show the structure of a bencoded file with byte offsets
+    Dump {
+        /// Bencoded file
+        file_path: String,
+    },
+    /// Show where two bencoded files differ
+    Diff {
+        /// Bencoded file
+        first_file_path: String,
+        /// Bencoded file compared to the first one
+        second_file_path: String,
+    },
+    /// Print a shell completion script
+    Completions {
+        /// Shell the script is written for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Args)]
+pub struct DecodeOptions {
+    /// Bencoded value, a file path with --file, or - to decode every value on stdin
+    #[arg(allow_hyphen_values = true)]
+    pub input: String,
+    /// Read the value from the file at the input path
+    #[arg(long = "file")]
+    pub from_file: bool,
+    /// How byte strings that are not UTF-8 are shown: hex or base64
+    #[arg(long = "bytes", value_name = "ENCODING", default_value = "hex")]
+    pub byte_encoding: ByteEncoding,
+}
+
+// How commands talking to a single swarm find their peers
+#[derive(Args)]
+pub struct SwarmOptions {
+    /// Port announced to the trackers
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Use this peer instead of asking the trackers, can be repeated
+    #[arg(long = "peer", value_name = "ADDRESS")]
+    pub peers: Vec<SocketAddr>,
+    /// Try at most this many peers
+    #[arg(long)]
+    pub max_peers: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct CreateOptions {
+    /// Where the torrent file is written
+    #[arg(short, long)]
+    pub output: String,
+    /// File or directory the torrent describes
+    pub input_path: String,
+    /// Announce URL, can be repeated
+    #[arg(long = "tracker", value_name = "URL")]
+    pub trackers: Vec<String>,
+    /// Free-form comment shown by clients
+    #[arg(long)]
+    pub comment: Option<String>,
+    /// Defaults to this program and its version
+    #[arg(long)]
+    pub created_by: Option<String>,
+    /// Leave the creation date out
+    #[arg(long)]
+    pub no_date: bool,
+    /// Only use the trackers to find peers
+    #[arg(long)]
+    pub private: bool,
+    /// Web seed URL, can be repeated
+    #[arg(long = "web-seed", value_name = "URL")]
+    pub web_seeds: Vec<String>,
+    /// Source tag, making the info hash unique to a tracker
+    #[arg(long)]
+    pub source: Option<String>,
+    /// Bytes per piece, picked from the total length when missing
+    #[arg(long)]
+    pub piece_length: Option<usize>,
+}
+
+// Every torrent is saved under its name in the output directory
+#[derive(Args)]
+pub struct DownloadAllOptions {
+    /// Directory the torrents are saved in
+    #[arg(short, long = "output")]
+    pub output_directory: String,
+    /// Torrent files
+    #[arg(required = true)]
+    pub torrent_file_paths: Vec<String>,
+    /// Port listened on and announced to the trackers
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Peer connections open at once, over all torrents
+    #[arg(long, alias = "max-connections")]
+    pub max_peers: Option<usize>,
+    /// Bytes per second, over all torrents
+    #[arg(long = "max-rate", value_name = "BYTES")]
+    pub max_download_rate: Option<u64>,
+    /// Only find peers through the trackers, without running a DHT node
+    #[arg(long)]
+    pub no_dht: bool,
+}
+
+// Logging options, accepted anywhere on the command line by every command
+#[derive(Args)]
+#[command(next_help_heading = "Logging")]
+pub struct LogOptions {
+    /// Show more events, -v for debug ones and -vv for every peer message
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Show fewer events, -q for warnings only and -qq for errors only
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub quiet: u8,
+    /// Write events to stderr as one JSON object per line
+    #[arg(long = "log-json", global = true)]
+    pub json: bool,
+}
+
+impl LogOptions {
+    pub fn max_level(&self) -> LevelFilter {
+        match self.verbose as i16 - self.quiet as i16 {
+            i16::MIN..=-2 => LevelFilter::ERROR,
+            -1 => LevelFilter::WARN,
+            0 => LevelFilter::INFO,
+            1 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        }
     }
 }
@@ -0,0 +1,182 @@
+use std::fmt::{Display, Formatter, Result};
+
+use serde::Serialize;
+
+use bittorrent_starter_rust::metainfo::TorrentMetainfo;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+// Everything the info command shows about a torrent. Fields are only ever added,
+// scripts rely on the JSON document.
+#[derive(Serialize, Debug)]
+pub struct InfoReport {
+    pub name: String,
+    pub length: usize,
+    pub piece_length: usize,
+    pub pieces_count: usize,
+    pub info_hash_v1: Option<String>,
+    pub info_hash_v2: Option<String>,
+    pub announce: Option<String>,
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    pub private: bool,
+    pub source: Option<String>,
+    pub web_seeds: Vec<String>,
+    pub http_seeds: Vec<String>,
+    pub files: Vec<FileReport>,
+    pub piece_hashes: Vec<String>,
+    pub magnet_link: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileReport {
+    pub path: String,
+    pub length: usize,
+    pub attributes: Vec<String>,
+}
+
+impl InfoReport {
+    pub fn from_metainfo(torrent: &TorrentMetainfo) -> anyhow::Result<Self> {
+        let info = &torrent.info;
+        let files = info
+            .files()?
+            .into_iter()
+            .map(|file| FileReport {
+                path: file.path.join("/"),
+                length: file.length,
+                attributes: file
+                    .attributes()
+                    .split(", ")
+                    .filter(|attribute| !attribute.is_empty())
+                    .map(String::from)
+                    .collect(),
+            })
+            .collect();
+
+        Ok(Self {
+            name: info.name.clone(),
+            length: info.total_length(),
+            piece_length: info.piece_length,
+            pieces_count: info.pieces_count(),
+            info_hash_v1: info.is_v1().then(|| info.hash_hex()).transpose()?,
+            info_hash_v2: info.is_v2().then(|| info.hash_v2_hex()).transpose()?,
+            announce: Some(torrent.announce.clone()).filter(|announce| !announce.is_empty()),
+            announce_list: torrent.announce_list.clone(),
+            comment: torrent.comment.clone(),
+            created_by: torrent.created_by.clone(),
+            creation_date: torrent.creation_date,
+            private: info.is_private(),
+            source: info.source.clone(),
+            web_seeds: torrent.web_seeds(),
+            http_seeds: torrent.http_seeds(),
+            files,
+            piece_hashes: info.pieces_hashes(),
+            magnet_link: torrent.magnet_link()?,
+        })
+    }
+}
+
+// The first lines keep the order the info command always had
+impl Display for InfoReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(
+            f,
+            "Tracker URL: {}",
+            self.announce.as_deref().unwrap_or_default()
+        )?;
+        writeln!(f, "Length: {}", self.length)?;
+        if let Some(info_hash) = &self.info_hash_v1 {
+            writeln!(f, "Info Hash: {info_hash}")?;
+        }
+        if let Some(info_hash) = &self.info_hash_v2 {
+            writeln!(f, "Info Hash v2: {info_hash}")?;
+        }
+        writeln!(f, "Piece Length: {}", self.piece_length)?;
+        if !self.piece_hashes.is_empty() {
+            writeln!(f, "Piece Hashes:")?;
+            for hash in &self.piece_hashes {
+                writeln!(f, "{hash}")?;
+            }
+        }
+
+        writeln!(f, "Name: {}", self.name)?;
+        writeln!(f, "Pieces: {}", self.pieces_count)?;
+        if !self.announce_list.is_empty() {
+            writeln!(f, "Announce List:")?;
+            for (tier_index, tier) in self.announce_list.iter().enumerate() {
+                writeln!(f, "Tier {}: {}", tier_index + 1, tier.join(", "))?;
+            }
+        }
+        if let Some(comment) = &self.comment {
+            writeln!(f, "Comment: {comment}")?;
+        }
+        if let Some(created_by) = &self.created_by {
+            writeln!(f, "Created By: {created_by}")?;
+        }
+        if let Some(creation_date) = self.creation_date {
+            writeln!(f, "Creation Date: {}", format_timestamp(creation_date))?;
+        }
+        writeln!(f, "Private: {}", if self.private { "yes" } else { "no" })?;
+        if let Some(source) = &self.source {
+            writeln!(f, "Source: {source}")?;
+        }
+        if !self.web_seeds.is_empty() {
+            writeln!(f, "Web Seeds:")?;
+            for url in &self.web_seeds {
+                writeln!(f, "{url}")?;
+            }
+        }
+        if !self.http_seeds.is_empty() {
+            writeln!(f, "HTTP Seeds:")?;
+            for url in &self.http_seeds {
+                writeln!(f, "{url}")?;
+            }
+        }
+
+        writeln!(f, "Files:")?;
+        for file in &self.files {
+            match file.attributes.is_empty() {
+                true => writeln!(f, "{} ({} bytes)", file.path, file.length)?,
+                false => writeln!(
+                    f,
+                    "{} ({} bytes, {})",
+                    file.path,
+                    file.length,
+                    file.attributes.join(", ")
+                )?,
+            }
+        }
+        write!(f, "Magnet Link: {}", self.magnet_link)
+    }
+}
+
+// UTC date of a Unix timestamp, as YYYY-MM-DD HH:MM:SS
+fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(SECONDS_PER_DAY);
+    let seconds = timestamp.rem_euclid(SECONDS_PER_DAY);
+
+    // Civil date from days since the epoch, counting in 400 years eras from 0000-03-01
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        seconds / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,43 @@
+//! A BitTorrent client library: bencode, torrent metainfo, tracker announces,
+//! the peer wire protocol, and a client downloading torrents from peers and
+//! web seeds. The `bittorrent-starter-rust` binary is a front end to it.
+//!
+//! Each subsystem fails with its own error type: [`bencode::BencodeError`],
+//! [`metainfo::MetainfoError`], [`tracker::TrackerError`], [`peer_wire::PeerError`],
+//! [`WebSeedError`] and [`StorageError`]. The client gathers them in [`Error`],
+//! where [`Error::is_retryable`] tells failures of a single peer or web seed
+//! from those fatal to the torrent.
+
+#![warn(missing_docs)]
+
+pub mod bencode;
+mod torrent_client;
+
+pub use torrent_client::error::{Error, StorageError, WebSeedError};
+pub use torrent_client::{
+    DownloadStats, Event, EventKind, Events, PeerStats, Session, SessionOptions, TorrentClient,
+    TorrentState, WebSeed, WebSeedKind,
+};
+
+/// Torrent files: reading, writing and creating them
+pub mod metainfo {
+    pub use crate::torrent_client::error::MetainfoError;
+    pub use crate::torrent_client::{
+        File, FileV2, Info, TorrentMetainfo, TorrentMetainfoBuilder, UrlList,
+    };
+}
+
+/// Announces to HTTP trackers
+pub mod tracker {
+    pub use crate::torrent_client::error::TrackerError;
+    pub use crate::torrent_client::{GetTrackersRequest, GetTrackersResponse};
+}
+
+/// The peer wire protocol, its messages and its extensions
+pub mod peer_wire {
+    pub use crate::torrent_client::error::PeerError;
+    pub use crate::torrent_client::{
+        ExtensionHandshake, HandshakeMessage, HashRange, PeerClient, PeerConnection, PeerId,
+        PeerMessage,
+    };
+}
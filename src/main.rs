@@ -1,92 +1,229 @@
-use cli::Command;
+use bittorrent_starter_rust::bencode::{self, decoder::StreamDecoder};
+use bittorrent_starter_rust::metainfo::{TorrentMetainfo, TorrentMetainfoBuilder};
+use bittorrent_starter_rust::peer_wire::{PeerClient, PeerId};
+use bittorrent_starter_rust::{Session, SessionOptions, TorrentClient, TorrentState};
+use clap::{CommandFactory, Parser};
+use cli::{
+    Cli, Command, CreateOptions, DecodeOptions, DownloadAllOptions, LogOptions, SwarmOptions,
+};
+use info_report::InfoReport;
+use serde_json::json;
 use std::env::{self};
+use std::io::{IsTerminal, Write};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{filter, fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
-use crate::torrent_client::TorrentClient;
-
-mod bencode;
 mod cli;
-mod torrent_client;
+mod info_report;
+
+const PEER_ID_PREFIX_ENV_VAR: &str = "BITTORRENT_PEER_ID_PREFIX";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let command_str = &args[1];
-
-    let command = Command::from_str(command_str);
-    let Some(command) = command else {
-        println!("Unknown command: {}", args[1]);
-        return Ok(());
-    };
+    let cli = Cli::parse();
+    init_logging(&cli.log_options);
 
-    match command {
-        Command::Decode => {
-            execute_command_decode(&args[2])?;
+    match cli.command {
+        Command::Decode(options) => {
+            execute_command_decode(options).await?;
         }
-        Command::Info => {
-            execute_command_info(&args[2])?;
+        Command::Info { file_path, json } => {
+            execute_command_info(&file_path, json)?;
         }
-        Command::Peers => {
-            execute_command_peers(&args[2]).await?;
+        Command::Peers {
+            file_path,
+            swarm_options,
+            json,
+        } => {
+            execute_command_peers(&file_path, &swarm_options, json).await?;
         }
-        Command::Handshake => {
-            execute_command_handshake(&args[2]).await?;
+        Command::Handshake {
+            file_path,
+            swarm_options,
+            json,
+        } => {
+            execute_command_handshake(&file_path, &swarm_options, json).await?;
         }
-        Command::DownloadPiece => {
-            let input_file_path = &args[4];
-            let output_file_path = &args[3];
-            let piece_index: &u32 = &args[5].parse()?;
-            execute_command_download_piece(&input_file_path, &output_file_path, *piece_index)
+        Command::DownloadPiece {
+            output,
+            file_path,
+            piece_index,
+            swarm_options,
+        } => {
+            execute_command_download_piece(&file_path, &output, piece_index, &swarm_options)
                 .await?;
         }
-        Command::Download => {
-            let input_file_path = &args[4];
-            let output_file_path = &args[3];
-            execute_command_download(&input_file_path, &output_file_path).await?;
+        Command::Download {
+            output,
+            file_path,
+            swarm_options,
+        } => {
+            execute_command_download(&file_path, &output, &swarm_options).await?;
+        }
+        Command::Encode { json_value } => {
+            execute_command_encode(&json_value)?;
+        }
+        Command::Create(options) => {
+            execute_command_create(options)?;
+        }
+        Command::Lint { file_path, output } => {
+            execute_command_lint(&file_path, output.as_deref())?;
+        }
+        Command::DownloadAll(options) => {
+            execute_command_download_all(options).await?;
+        }
+        Command::Dump { file_path } => {
+            execute_command_dump(&file_path)?;
+        }
+        Command::Diff {
+            first_file_path,
+            second_file_path,
+        } => {
+            execute_command_diff(&first_file_path, &second_file_path)?;
+        }
+        Command::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
         }
     }
 
     Ok(())
 }
 
+// Client events go to stderr, stdout only carries the output of the command
+fn init_logging(options: &LogOptions) {
+    let max_level = options.max_level();
+    // Spans are always kept, so that warnings still name their torrent and peer. The
+    // details of the crates we use are left out.
+    let filter = filter::filter_fn(move |metadata| {
+        let max_level = match metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            true => max_level,
+            false => max_level.min(LevelFilter::WARN),
+        };
+        metadata.is_span() || *metadata.level() <= max_level
+    });
+    let layer = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_target(false);
+    let layer = match options.json {
+        true => layer.json().boxed(),
+        false => layer.boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .init();
+}
+
 // ---
 // Commands bodies
 
-fn execute_command_decode(encoded_value: &str) -> anyhow::Result<()> {
-    let decoded_value = bencode::decode_bencoded_value(encoded_value)?;
+// The peer id prefix can be overridden to tell our instances apart
+fn load_client(file_path: &str) -> anyhow::Result<TorrentClient> {
+    let client = TorrentClient::from_torrent_file(file_path)?;
+    match env::var(PEER_ID_PREFIX_ENV_VAR) {
+        Ok(prefix) => Ok(client.with_peer_id(PeerId::with_prefix(&prefix)?)),
+        Err(_) => Ok(client),
+    }
+}
+
+// The swarm options override how the client finds its peers
+fn load_swarm_client(file_path: &str, options: &SwarmOptions) -> anyhow::Result<TorrentClient> {
+    let mut client = load_client(file_path)?.with_peers(options.peers.clone());
+    if let Some(port) = options.port {
+        client = client.with_port(port);
+    }
+    if let Some(max_peers) = options.max_peers {
+        client = client.with_max_peers(max_peers);
+    }
+    Ok(client)
+}
+
+// "-" decodes every value coming on stdin, one JSON line each, as soon as it is complete
+async fn execute_command_decode(options: DecodeOptions) -> anyhow::Result<()> {
+    if !options.from_file && options.input == "-" {
+        let mut stdin = tokio::io::stdin();
+        let mut decoder = StreamDecoder::default();
+        while let Some(encoded_value) = decoder.read_value(&mut stdin).await? {
+            let decoded_value =
+                bencode::decode_bencoded_value(&encoded_value, options.byte_encoding)?;
+            println!("{decoded_value}");
+        }
+        return Ok(());
+    }
+
+    let encoded_value = match options.from_file {
+        true => std::fs::read(&options.input)?,
+        false => options.input.into_bytes(),
+    };
+    let decoded_value = bencode::decode_bencoded_value(&encoded_value, options.byte_encoding)?;
     println!("{decoded_value}");
     Ok(())
 }
 
-fn execute_command_info(file_path: &str) -> anyhow::Result<()> {
-    let client = TorrentClient::from_torrent_file(file_path)?;
-    let torrent = client.torrent_metainfo;
-    println!("Tracker URL: {}", torrent.announce);
-    println!("Length: {}", torrent.info.length);
-    println!("Info Hash: {}", torrent.info.hash_hex()?);
+// Reads the JSON from stdin when given "-", the bencode goes to stdout as raw bytes
+fn execute_command_encode(json_value: &str) -> anyhow::Result<()> {
+    let json_value = match json_value {
+        "-" => std::io::read_to_string(std::io::stdin())?,
+        _ => json_value.to_string(),
+    };
+    let value: serde_json::Value = serde_json::from_str(&json_value)?;
+    let encoded_value = bencode::encode_json_value(&value)?;
 
-    println!("Piece Length: {}", torrent.info.piece_length);
-    println!("Piece Hashes:");
-    torrent
-        .info
-        .pieces_hashes()
-        .iter()
-        .for_each(|hash| println!("{hash}"));
+    let mut stdout = std::io::stdout();
+    stdout.write_all(&encoded_value)?;
+    stdout.flush()?;
     Ok(())
 }
 
-async fn execute_command_peers(file_path: &str) -> anyhow::Result<()> {
-    let mut client = TorrentClient::from_torrent_file(file_path)?;
+fn execute_command_info(file_path: &str, json: bool) -> anyhow::Result<()> {
+    let client = load_client(file_path)?;
+    let report = InfoReport::from_metainfo(&client.torrent_metainfo)?;
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&report)?),
+        false => println!("{report}"),
+    }
+    Ok(())
+}
+
+async fn execute_command_peers(
+    file_path: &str,
+    swarm_options: &SwarmOptions,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut client = load_swarm_client(file_path, swarm_options)?;
     client.fetch_peers().await?;
-    client.peers.iter().for_each(|peer| println!("{peer}"));
+    match json {
+        true => println!("{}", json!(client.peers)),
+        false => client.peers.iter().for_each(|peer| println!("{peer}")),
+    }
     Ok(())
 }
 
-async fn execute_command_handshake(file_path: &str) -> anyhow::Result<()> {
-    let mut client = TorrentClient::from_torrent_file(file_path)?;
+async fn execute_command_handshake(
+    file_path: &str,
+    swarm_options: &SwarmOptions,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut client = load_swarm_client(file_path, swarm_options)?;
     client.fetch_peers().await?;
     client.connect().await?;
     let peer_id = client.handshake().await?;
+    let peer_client = PeerClient::from_peer_id(&peer_id);
+    if json {
+        let report = json!({
+            "peer_id": peer_id.to_string(),
+            "peer_client": peer_client.map(|peer_client| peer_client.to_string()),
+        });
+        println!("{report}");
+        return Ok(());
+    }
+
     println!("Peer ID: {peer_id}");
+    if let Some(peer_client) = peer_client {
+        println!("Peer Client: {peer_client}");
+    }
     Ok(())
 }
 
@@ -94,37 +231,180 @@ async fn execute_command_download_piece(
     input_file_path: &str,
     output_file_path: &str,
     piece_index: u32,
+    swarm_options: &SwarmOptions,
 ) -> anyhow::Result<()> {
-    let mut client = TorrentClient::from_torrent_file(input_file_path)?;
-    client.fetch_peers().await?;
-    client.connect().await?;
-    client.handshake().await?;
-    client.prepare_for_download().await?;
-
-    let mut stream = client
-        .stream
-        .as_mut()
-        .ok_or_else(|| anyhow::Error::msg("TcpStream not available"))?;
-
-    let piece_bytes =
-        TorrentClient::download_piece(&mut stream, &client.torrent_metainfo, piece_index.clone())
-            .await?;
-    std::fs::write(&output_file_path, piece_bytes)?;
-    client.disconnect().await?;
+    let mut client = load_swarm_client(input_file_path, swarm_options)?;
+    join_swarm(&mut client).await?;
+
+    let piece_bytes = client.download_verified_piece(piece_index).await?;
+    std::fs::write(output_file_path, piece_bytes)?;
+    if client.is_connected() {
+        client.disconnect().await?;
+    }
     Ok(())
 }
 
 async fn execute_command_download(
     input_file_path: &str,
     output_file_path: &str,
+    swarm_options: &SwarmOptions,
 ) -> anyhow::Result<()> {
-    let mut client = TorrentClient::from_torrent_file(input_file_path)?;
-    client.fetch_peers().await?;
-    client.connect().await?;
-    client.handshake().await?;
-    client.prepare_for_download().await?;
+    let mut client = load_swarm_client(input_file_path, swarm_options)?;
+    join_swarm(&mut client).await?;
     client.download().await?;
     client.save(output_file_path).await?;
-    client.disconnect().await?;
+    if client.is_connected() {
+        client.disconnect().await?;
+    }
+    Ok(())
+}
+
+// Downloads every torrent at once, each one under its name in the output directory
+async fn execute_command_download_all(options: DownloadAllOptions) -> anyhow::Result<()> {
+    let defaults = SessionOptions::default();
+    let mut session = Session::bind(SessionOptions {
+        port: options.port.unwrap_or(defaults.port),
+        max_connections: options.max_peers.unwrap_or(defaults.max_connections),
+        max_download_rate: options.max_download_rate,
+        dht: !options.no_dht,
+    })
+    .await?;
+    if let Ok(prefix) = env::var(PEER_ID_PREFIX_ENV_VAR) {
+        session = session.with_peer_id(PeerId::with_prefix(&prefix)?);
+    }
+
+    std::fs::create_dir_all(&options.output_directory)?;
+    for file_path in &options.torrent_file_paths {
+        let torrent = TorrentMetainfo::from_bytes(&std::fs::read(file_path)?)?;
+        // The name comes from the torrent, only its last component is kept
+        let name = std::path::Path::new(&torrent.info.name)
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{file_path} has no usable name"))?
+            .to_owned();
+        let output_path = std::path::Path::new(&options.output_directory).join(name);
+        let info_hash = session.add(torrent, output_path)?;
+        println!("Added {file_path} ({info_hash})");
+    }
+
+    session.wait().await;
+    let mut failures_count = 0;
+    for (info_hash, state) in session.torrents() {
+        println!("{info_hash}: {state}");
+        if !matches!(state, TorrentState::Finished) {
+            failures_count += 1;
+        }
+    }
+    match failures_count {
+        0 => Ok(()),
+        count => Err(anyhow::anyhow!("Torrents not downloaded: {count}")),
+    }
+}
+
+// Web seeds can serve the whole torrent, a peer session is optional then
+async fn join_swarm(client: &mut TorrentClient) -> anyhow::Result<()> {
+    match client.join_swarm().await {
+        Err(error) if !client.web_seeds.is_empty() => {
+            client.events.warn(format!(
+                "Could not join the swarm ({error}), downloading from web seeds"
+            ));
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+fn execute_command_create(options: CreateOptions) -> anyhow::Result<()> {
+    let created_by = options
+        .created_by
+        .unwrap_or_else(|| format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")));
+    let mut builder = TorrentMetainfoBuilder::new(&options.input_path)
+        .created_by(&created_by)
+        .private(options.private);
+    for tracker in &options.trackers {
+        builder = builder.tracker(tracker);
+    }
+    for web_seed in &options.web_seeds {
+        builder = builder.web_seed(web_seed);
+    }
+    if let Some(comment) = &options.comment {
+        builder = builder.comment(comment);
+    }
+    if let Some(source) = &options.source {
+        builder = builder.source(source);
+    }
+    if let Some(piece_length) = options.piece_length {
+        builder = builder.piece_length(piece_length);
+    }
+    if options.no_date {
+        builder = builder.creation_date(None);
+    }
+
+    let torrent = builder.build()?;
+    std::fs::write(&options.output, torrent.to_bytes()?)?;
+    println!("Created {}", options.output);
+    println!("Info Hash: {}", torrent.info.hash_hex()?);
     Ok(())
 }
+
+// Reports every non-canonical construct, -o writes the canonical encoding to another file
+fn execute_command_lint(file_path: &str, output_file_path: Option<&str>) -> anyhow::Result<()> {
+    let bytes = std::fs::read(file_path)?;
+    let issues = bencode::canonical::validate(&bytes)?;
+    issues.iter().for_each(|issue| println!("{issue}"));
+
+    let Some(output_file_path) = output_file_path else {
+        return match issues.len() {
+            0 => {
+                println!("{file_path} is canonical");
+                Ok(())
+            }
+            count => Err(anyhow::anyhow!("Non-canonical constructs found: {count}")),
+        };
+    };
+
+    let canonical_bytes = bencode::canonical::encode(&bencode::parser::parse(&bytes)?);
+    std::fs::write(output_file_path, &canonical_bytes)?;
+    println!("Wrote canonical {output_file_path}");
+
+    // Peers hash the info dictionary as is, rewriting it makes another torrent
+    let original = TorrentMetainfo::from_bytes(&bytes)?.info;
+    let canonical = TorrentMetainfo::from_bytes(&canonical_bytes)?.info;
+    if original.raw_bytes != canonical.raw_bytes {
+        if original.is_v1() {
+            println!(
+                "Warning: the info hash changes from {} to {}",
+                original.hash_hex()?,
+                canonical.hash_hex()?
+            );
+        }
+        if original.is_v2() {
+            println!(
+                "Warning: the v2 info hash changes from {} to {}",
+                original.hash_v2_hex()?,
+                canonical.hash_v2_hex()?
+            );
+        }
+    }
+    Ok(())
+}
+
+fn execute_command_dump(file_path: &str) -> anyhow::Result<()> {
+    let bytes = std::fs::read(file_path)?;
+    print!("{}", bencode::dump::dump(&bencode::parser::parse(&bytes)?));
+    Ok(())
+}
+
+// Like diff(1), finding differences is a failure
+fn execute_command_diff(first_file_path: &str, second_file_path: &str) -> anyhow::Result<()> {
+    let first_bytes = std::fs::read(first_file_path)?;
+    let second_bytes = std::fs::read(second_file_path)?;
+    let differences = bencode::diff::diff(&first_bytes, &second_bytes)?;
+    differences
+        .iter()
+        .for_each(|difference| println!("{difference}"));
+
+    match differences.len() {
+        0 => Ok(()),
+        count => Err(anyhow::anyhow!("Differences found: {count}")),
+    }
+}
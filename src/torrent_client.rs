@@ -1,69 +1,356 @@
 use std::{
+    collections::{HashMap, VecDeque},
     fs,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
     str::FromStr,
+    sync::Arc,
+    time::Duration,
     vec,
 };
 
 use hex::ToHex;
 use sha1::{Digest, Sha1};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::time::Instant;
+
+use crate::bencode::decoder::{Decoded, StreamDecoder};
 
+mod dht;
+mod download_stats;
 pub mod error;
+mod events;
+mod extension_handshake;
+mod fast_extension;
+mod file_storage;
 mod get_trackers;
 mod handshake_message;
+mod merkle;
+mod peer_bans;
+mod peer_client;
+mod peer_connection;
+mod peer_id;
 mod peer_message;
+mod piece_buffer;
+mod rate_limiter;
+mod session;
+mod torrent_builder;
 mod torrent_metainfo;
+mod web_seed;
 
-use self::get_trackers::{GetTrackersRequest, GetTrackersResponse};
-use self::handshake_message::HandshakeMessage;
-use self::peer_message::PeerMessage;
-use self::{error::Error, torrent_metainfo::TorrentMetainfo};
+use self::dht::Dht;
+pub use self::download_stats::{DownloadStats, PeerStats};
+use self::error::{Error, MetainfoError, PeerError, TrackerError, WebSeedError};
+pub use self::events::{Event, EventKind, Events};
+pub use self::extension_handshake::ExtensionHandshake;
+use self::extension_handshake::EXTENSION_HANDSHAKE_ID;
+use self::fast_extension::{allowed_fast_set, ALLOWED_FAST_SET_SIZE};
+pub use self::get_trackers::{GetTrackersRequest, GetTrackersResponse};
+pub use self::handshake_message::HandshakeMessage;
+use self::merkle::{MerkleHash, MERKLE_BLOCK_SIZE};
+use self::peer_bans::PeerBans;
+pub use self::peer_client::PeerClient;
+pub use self::peer_connection::PeerConnection;
+pub use self::peer_id::PeerId;
+use self::peer_message::MAX_HASHES_PER_REQUEST;
+pub use self::peer_message::{HashRange, PeerMessage};
+use self::piece_buffer::PieceBuffer;
+use self::rate_limiter::RateLimiter;
+pub use self::session::{Session, SessionOptions, TorrentState};
+pub use self::torrent_builder::TorrentMetainfoBuilder;
+pub use self::torrent_metainfo::{File, FileV2, Info, TorrentMetainfo, UrlList};
+pub use self::web_seed::{WebSeed, WebSeedKind};
 
-const PEER_ID: &str = "00112233445566778899";
 const PIECE_BLOCK_SIZE: u32 = 16_384; // 16 KiB
+const MAX_PIECE_ATTEMPTS: u32 = 5;
+const MAX_OUTSTANDING_REQUESTS: usize = 5;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// Time the DHT lookup still gets once the tracker gave peers
+const DHT_LOOKUP_GRACE: Duration = Duration::from_secs(2);
+const MAX_SERVED_BLOCK_LENGTH: u32 = 131_072; // 128 KiB
+/// Port announced to the trackers when none is given
+pub(crate) const DEFAULT_PORT: u16 = 6881;
 
+/// Downloads a torrent from the peers of its swarm and from its web seeds,
+/// one peer connection at a time
 pub struct TorrentClient {
+    /// The torrent being downloaded
     pub torrent_metainfo: TorrentMetainfo,
+    pub(crate) peer_id: PeerId,
+    // Announced to trackers, a session listens on it
+    pub(crate) port: u16,
+    /// Peers found by [`TorrentClient::fetch_peers`], in the order they are tried
     pub peers: Vec<SocketAddr>,
-    pub stream: Option<TcpStream>,
+    // Peers given up front are used as is, the trackers are not asked then
+    given_peers: Vec<SocketAddr>,
+    max_peers: Option<usize>,
+    pub(crate) connection: Option<PeerConnection>,
+    // Info hash of the swarm each peer was announced in
+    peer_swarms: HashMap<SocketAddr, Vec<u8>>,
+    /// Web seeds of the torrent, from its `url-list` and `httpseeds`
+    pub web_seeds: Vec<WebSeed>,
+    /// What was downloaded so far, and from where
+    pub stats: DownloadStats,
+    /// Where the client reports what it does
+    pub events: Events,
+    peer_bans: PeerBans,
+    // Rotates pieces between the peer connection and the web seeds
+    source_turn: usize,
     pieces_bytes: Vec<Vec<u8>>,
+    piece_layers_checked: bool,
+    // Shared with the other torrents of a session
+    rate_limiter: Option<Arc<RateLimiter>>,
+    dht: Option<Dht>,
+}
+
+struct BlockRequest {
+    begin: u32,
+    length: u32,
+    requested_at: Instant,
 }
 
 // New and from helpers
 impl TorrentClient {
+    /// A client for the torrent, not connected to any peer yet
     pub fn new(torrent_metainfo: TorrentMetainfo) -> Self {
+        let url_list_seeds = torrent_metainfo
+            .web_seeds()
+            .into_iter()
+            .map(|url| WebSeed::new(url, WebSeedKind::UrlList));
+        let http_seeds = torrent_metainfo
+            .http_seeds()
+            .into_iter()
+            .map(|url| WebSeed::new(url, WebSeedKind::HttpSeed));
+        let web_seeds = url_list_seeds.chain(http_seeds).collect();
+        let info_hash = torrent_metainfo
+            .info
+            .swarm_hash_bytes()
+            .ok()
+            .map(hex::encode);
         Self {
             torrent_metainfo,
+            peer_id: PeerId::generate(),
+            port: DEFAULT_PORT,
             peers: vec![],
-            stream: None,
+            given_peers: vec![],
+            max_peers: None,
+            connection: None,
+            peer_swarms: HashMap::new(),
+            web_seeds,
+            stats: DownloadStats::default(),
+            events: Events::default().for_torrent(info_hash),
+            peer_bans: PeerBans::default(),
+            source_turn: 0,
             pieces_bytes: vec![],
+            piece_layers_checked: false,
+            rate_limiter: None,
+            dht: None,
         }
     }
 
-    pub fn from_torrent_file(file_path: &str) -> anyhow::Result<Self> {
-        let content = fs::read(file_path)?;
-        let torrent_metainfo: TorrentMetainfo = serde_bencode::from_bytes(&content)?;
+    /// A client for the torrent file at `file_path`
+    pub fn from_torrent_file(file_path: &str) -> Result<Self, Error> {
+        let content = fs::read(file_path).map_err(MetainfoError::from)?;
+        let torrent_metainfo = TorrentMetainfo::from_bytes(&content)?;
         Ok(Self::new(torrent_metainfo))
     }
+
+    /// Uses this peer id instead of a random one
+    pub fn with_peer_id(mut self, peer_id: PeerId) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    /// Reports to the subscribers of `events`, like the other torrents of a session
+    pub fn with_events(mut self, events: &Events) -> Self {
+        self.events = events.for_torrent(self.events.info_hash().cloned());
+        self
+    }
+
+    /// Announces this port to the trackers instead of the default 6881
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Uses these peers as they are, the trackers are not asked then
+    pub fn with_peers(mut self, peers: Vec<SocketAddr>) -> Self {
+        self.given_peers = peers;
+        self
+    }
+
+    /// At most this many peers are tried, the first ones announced
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = Some(max_peers);
+        self
+    }
+
+    // Blocks from peers and web seeds wait for the limiter before being downloaded
+    pub(crate) fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    // Peers also come from the DHT, unless the torrent is private
+    pub(crate) fn with_dht(mut self, dht: Option<Dht>) -> Self {
+        self.dht = dht;
+        self
+    }
 }
 
 // Peers related
 impl TorrentClient {
-    pub async fn fetch_peers(&mut self) -> anyhow::Result<()> {
-        let get_trackers_request = GetTrackersRequest::new(PEER_ID, self.torrent_metainfo.clone());
+    /// Announces on every swarm of the torrent, peers from either are usable.
+    /// Fails only if no announce succeeded.
+    pub async fn fetch_peers(&mut self) -> Result<(), Error> {
+        self.peers.clear();
+        self.peer_swarms.clear();
+        let max_peers = self.max_peers.unwrap_or(usize::MAX);
+
+        if !self.given_peers.is_empty() {
+            self.peers = self.given_peers.iter().take(max_peers).copied().collect();
+            return Ok(());
+        }
+
+        let mut announce_error = None;
+        let mut announced = false;
+        for info_hash in self.torrent_metainfo.info.swarm_hashes_bytes()? {
+            let (announce_result, dht_peers) = self.find_swarm_peers(&info_hash).await;
+            let mut peers = match announce_result {
+                Ok(peers) => {
+                    announced = true;
+                    peers
+                }
+                Err(error) => {
+                    announce_error = Some(error);
+                    vec![]
+                }
+            };
+            // A DHT lookup that found peers makes up for a failed announce
+            announced |= !dht_peers.is_empty();
+            peers.extend(dht_peers);
+
+            for peer in peers {
+                if self.peers.len() < max_peers && !self.peers.contains(&peer) {
+                    self.peers.push(peer);
+                    self.peer_swarms.insert(peer, info_hash.clone());
+                }
+            }
+        }
+
+        match (announced, announce_error) {
+            (false, Some(error)) => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    // Announces and looks the swarm up in the DHT at the same time. A lookup outlasting
+    // an announce that gave peers is cut short.
+    async fn find_swarm_peers(
+        &self,
+        info_hash: &[u8],
+    ) -> (Result<Vec<SocketAddr>, Error>, Vec<SocketAddr>) {
+        let announce = self.announce(info_hash);
+        let dht_lookup = self.find_dht_peers(info_hash);
+        tokio::pin!(announce, dht_lookup);
+
+        tokio::select! {
+            dht_peers = &mut dht_lookup => (announce.await, dht_peers),
+            announce_result = &mut announce => {
+                let dht_peers = match &announce_result {
+                    Ok(peers) if !peers.is_empty() => {
+                        tokio::time::timeout(DHT_LOOKUP_GRACE, dht_lookup)
+                            .await
+                            .unwrap_or_else(|_| {
+                                self.events.debug("DHT lookup cut short, the tracker gave peers");
+                                vec![]
+                            })
+                    }
+                    _ => dht_lookup.await,
+                };
+                (announce_result, dht_peers)
+            }
+        }
+    }
+
+    async fn announce(&self, info_hash: &[u8]) -> Result<Vec<SocketAddr>, Error> {
+        let url = self.torrent_metainfo.announce.clone();
+        match self.request_peers(info_hash).await {
+            Ok(peers) => {
+                self.events.emit(EventKind::TrackerReplied {
+                    url,
+                    peers_count: peers.len(),
+                });
+                Ok(peers)
+            }
+            Err(error) => {
+                self.events.emit(EventKind::TrackerFailed {
+                    url,
+                    reason: error.to_string(),
+                });
+                Err(error)
+            }
+        }
+    }
+
+    // Private torrents only get their peers from their trackers (BEP 27)
+    async fn find_dht_peers(&self, info_hash: &[u8]) -> Vec<SocketAddr> {
+        let Some(dht) = self.dht.as_ref() else {
+            return vec![];
+        };
+        if self.torrent_metainfo.info.is_private() {
+            return vec![];
+        }
+        match dht.find_peers(info_hash, Some(self.port)).await {
+            Ok(peers) => {
+                self.events.emit(EventKind::DhtReplied {
+                    peers_count: peers.len(),
+                });
+                peers
+            }
+            Err(error) => {
+                self.events.warn(format!("DHT lookup failed: {error}"));
+                vec![]
+            }
+        }
+    }
+
+    async fn request_peers(&self, info_hash: &[u8]) -> Result<Vec<SocketAddr>, Error> {
+        let get_trackers_request = GetTrackersRequest::new(
+            self.peer_id,
+            info_hash.to_vec(),
+            self.torrent_metainfo.clone(),
+            self.port,
+        );
         let get_trackers_url = get_trackers_request.to_url()?;
-        let response_bytes = reqwest::get(&get_trackers_url).await?.bytes().await?;
-        let tracker_response: GetTrackersResponse = serde_bencode::from_bytes(&response_bytes)?;
 
-        self.peers = tracker_response
+        // The response is decoded as it arrives, bytes after the value are ignored
+        let mut response = reqwest::get(&get_trackers_url)
+            .await
+            .map_err(TrackerError::from)?;
+        let mut decoder = StreamDecoder::default();
+        let response_bytes = loop {
+            if let Decoded::Complete(bytes) = decoder.decode().map_err(TrackerError::from)? {
+                break bytes;
+            }
+            match response.chunk().await.map_err(TrackerError::from)? {
+                Some(chunk) => decoder.push(&chunk),
+                None => {
+                    break decoder
+                        .finish()
+                        .map_err(TrackerError::from)?
+                        .unwrap_or_default()
+                }
+            }
+        };
+        let tracker_response: GetTrackersResponse =
+            serde_bencode::from_bytes(&response_bytes).map_err(TrackerError::from)?;
+
+        let peers = tracker_response
             .peers()
             .iter()
             .filter_map(|peer_string| {
-                let parts: Vec<&str> = peer_string.split(":").collect();
+                let parts: Vec<&str> = peer_string.split(':').collect();
                 if parts.len() != 2 {
                     return None;
                 }
@@ -79,261 +366,1471 @@ impl TorrentClient {
             })
             .collect();
 
-        Ok(())
+        Ok(peers)
     }
 
-    pub async fn connect(&mut self) -> anyhow::Result<()> {
-        let Some(peer_socket_address) = self.peers.first() else {
-            return Err(anyhow::Error::msg(Error::NoPeerAvailable));
-        };
-        self.stream = Some(TcpStream::connect(peer_socket_address).await?);
-        println!("> Connected to {peer_socket_address}");
-        Ok(())
+    /// Connects to the first of the peers that accepts the connection
+    pub async fn connect(&mut self) -> Result<(), Error> {
+        let candidates: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|peer| self.peer_bans.is_usable(peer))
+            .copied()
+            .collect();
+
+        for peer_socket_address in candidates {
+            match PeerConnection::connect(peer_socket_address, self.events.clone()).await {
+                Ok(connection) => {
+                    let pieces_count = self.torrent_metainfo.info.pieces_count();
+                    self.connection = Some(connection.with_pieces_count(pieces_count));
+                    self.events
+                        .for_peer(peer_socket_address)
+                        .emit(EventKind::PeerConnected {
+                            address: peer_socket_address,
+                            incoming: false,
+                        });
+                    return Ok(());
+                }
+                Err(error) => {
+                    self.events.warn(format!(
+                        "Could not connect to {peer_socket_address}: {error}"
+                    ));
+                    self.mark_unresponsive(peer_socket_address);
+                }
+            }
+        }
+
+        Err(Error::NoPeerAvailable)
     }
 
-    pub async fn disconnect(&mut self) -> anyhow::Result<()> {
-        let stream = self
-            .stream
-            .as_mut()
-            .ok_or_else(|| anyhow::Error::msg(Error::TcpStreamNotAvailable))?;
+    /// Sets up a session with a peer of the swarm, ready to download
+    pub async fn join_swarm(&mut self) -> Result<(), Error> {
+        let result = self.try_join_swarm().await;
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    async fn try_join_swarm(&mut self) -> Result<(), Error> {
+        self.fetch_peers().await?;
+        self.connect().await?;
+        self.handshake().await?;
+        self.prepare_for_download().await
+    }
+
+    /// Whether a peer connection is open
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Closes the peer connection
+    pub async fn disconnect(&mut self) -> Result<(), Error> {
+        let mut connection = self.connection.take().ok_or(Error::TcpStreamNotAvailable)?;
 
-        stream.flush().await?;
-        stream.shutdown().await?;
-        self.stream = None;
+        connection.shutdown().await?;
 
-        println!("> Disconnected");
+        self.events
+            .for_peer(connection.address)
+            .emit(EventKind::PeerDisconnected {
+                address: connection.address,
+            });
         Ok(())
     }
 
-    pub async fn handshake(&mut self) -> anyhow::Result<String> {
-        let stream = self
-            .stream
+    /// Handshakes with the connected peer, returning its peer id
+    pub async fn handshake(&mut self) -> Result<PeerId, Error> {
+        let address = self
+            .connection
+            .as_ref()
+            .ok_or(Error::TcpStreamNotAvailable)?
+            .address;
+        let info_hash = match self.peer_swarms.get(&address) {
+            Some(info_hash) => info_hash.clone(),
+            None => self.torrent_metainfo.info.swarm_hash_bytes()?,
+        };
+        let handshake_message = self.our_handshake(info_hash);
+
+        // Send the handshake message and receive a response
+        let connection = self
+            .connection
             .as_mut()
-            .ok_or_else(|| anyhow::Error::msg(Error::TcpStreamNotAvailable))?;
+            .ok_or(Error::TcpStreamNotAvailable)?;
+        let handshake_reply_message = connection.exchange_handshake(&handshake_message).await?;
+        handshake_reply_message.validate_reply(&handshake_message)?;
 
-        let info_hash = self.torrent_metainfo.info.hash_bytes()?;
+        self.finish_handshake(&handshake_message, &handshake_reply_message)
+            .await
+    }
 
-        // Prepare the handshake message
-        let handshake_message = HandshakeMessage::new(info_hash.into(), PEER_ID.into());
+    // Takes a peer that connected to us and handshook first, ready to download from it
+    pub(crate) async fn accept_peer(
+        &mut self,
+        mut connection: PeerConnection,
+        peer_handshake_message: HandshakeMessage,
+    ) -> Result<PeerId, Error> {
+        let info_hash = peer_handshake_message.info_hash.clone();
+        if !self
+            .torrent_metainfo
+            .info
+            .swarm_hashes_bytes()?
+            .contains(&info_hash)
+        {
+            return Err(PeerError::HandshakeInfoHashMismatch {
+                expected: self.torrent_metainfo.info.hash_hex()?,
+                actual: hex::encode(&info_hash),
+            }
+            .into());
+        }
 
-        // Send the handshake message
-        stream.write_all(&handshake_message.to_bytes()).await?;
+        let handshake_message = self.our_handshake(info_hash);
+        peer_handshake_message.validate_reply(&handshake_message)?;
+        connection.events = self.events.for_peer(connection.address);
+        let mut connection =
+            connection.with_pieces_count(self.torrent_metainfo.info.pieces_count());
+        connection.send_handshake(&handshake_message).await?;
+        connection.events.emit(EventKind::PeerConnected {
+            address: connection.address,
+            incoming: true,
+        });
 
-        // Receive a response
-        let mut buffer = [0; 68];
-        stream.read(&mut buffer).await?;
+        self.connection = Some(connection);
+        let result = async {
+            let peer_id = self
+                .finish_handshake(&handshake_message, &peer_handshake_message)
+                .await?;
+            self.prepare_for_download().await?;
+            Ok(peer_id)
+        }
+        .await;
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    // Advertises what we speak: v2 for v2 torrents, the DHT when there is a node
+    fn our_handshake(&self, info_hash: Vec<u8>) -> HandshakeMessage {
+        let mut handshake_message = HandshakeMessage::new(info_hash, self.peer_id);
+        if self.torrent_metainfo.info.is_v2() {
+            handshake_message.enable_v2_upgrade();
+        }
+        if self.dht.is_some() {
+            handshake_message.enable_dht();
+        }
+        handshake_message
+    }
+
+    // Everything following the exchange of handshakes, whoever sent theirs first
+    async fn finish_handshake(
+        &mut self,
+        handshake_message: &HandshakeMessage,
+        handshake_reply_message: &HandshakeMessage,
+    ) -> Result<PeerId, Error> {
+        let connection = self
+            .connection
+            .as_mut()
+            .ok_or(Error::TcpStreamNotAvailable)?;
 
         // Extract the peer ID from the received message
-        let handshake_reply_message = HandshakeMessage::from_bytes(&buffer);
         let peer_id = handshake_reply_message.peer_id;
+        connection.client = PeerClient::from_peer_id(&peer_id);
+        connection.fast_extension = handshake_message.supports_fast_extension()
+            && handshake_reply_message.supports_fast_extension();
+        connection.v2_hashes = handshake_message.supports_v2_upgrade()
+            && handshake_reply_message.supports_v2_upgrade();
+
+        // Tell the peer which pieces we have, it must be the first message after the handshake
+        let pieces_count = self.torrent_metainfo.info.pieces_count() as u32;
+        Self::send_availability(connection, &self.pieces_bytes, pieces_count).await?;
+
+        // Announce ourselves through the extension protocol if the peer speaks it
+        if handshake_reply_message.supports_extension_protocol() {
+            connection
+                .send_message(PeerMessage::Extended {
+                    id: EXTENSION_HANDSHAKE_ID,
+                    payload: ExtensionHandshake::ours().to_bytes()?,
+                })
+                .await?;
+        }
 
-        println!("> Handshake successful (Peer ID: {peer_id})");
+        // Peers running a DHT node may add ours to their routing table
+        if let Some(dht) = self.dht.as_ref() {
+            if handshake_reply_message.supports_dht() {
+                connection
+                    .send_message(PeerMessage::Port { port: dht.port() })
+                    .await?;
+            }
+        }
+
+        // We keep peers choked, the allowed fast set is what they can still get from us
+        if connection.fast_extension {
+            let granted_fast_pieces = allowed_fast_set(
+                connection.address.ip(),
+                &handshake_message.info_hash,
+                pieces_count,
+                ALLOWED_FAST_SET_SIZE,
+            );
+            for index in granted_fast_pieces {
+                connection
+                    .send_message(PeerMessage::AllowedFast { index })
+                    .await?;
+                connection.granted_fast_pieces.insert(index);
+            }
+        }
+        self.record_peer_client();
+
+        self.events
+            .info(format!("Handshake successful (Peer ID: {peer_id})"));
         Ok(peer_id)
     }
 
-    pub async fn prepare_for_download(&mut self) -> anyhow::Result<()> {
-        println!("> Preparing for download");
+    // Waits until the peer serves requests for one of the missing pieces, either by
+    // unchoking us or by allowing it fast
+    pub(crate) async fn prepare_for_download(&mut self) -> Result<(), Error> {
+        self.events.debug("Preparing for download");
 
-        let stream = self
-            .stream
+        let pieces_count = self.torrent_metainfo.info.pieces_count() as u32;
+        let connection = self
+            .connection
             .as_mut()
-            .ok_or_else(|| anyhow::Error::msg(Error::TcpStreamNotAvailable))?;
+            .ok_or(Error::TcpStreamNotAvailable)?;
+        let mut interested = false;
 
         loop {
-            // Wait for the stream to be available
-            stream.readable().await?;
-
-            // Read a message
-            let message = Self::read_message(stream).await?;
-            println!("> Received message: {message}");
+            // Read a message, giving up on peers that never let us request anything
+            let Some(message) = connection.wait_for_message(SNUB_TIMEOUT).await? else {
+                break Err(PeerError::Snubbed {
+                    address: connection.address,
+                }
+                .into());
+            };
+            connection
+                .events
+                .trace(format!("Received message: {message}"));
 
-            // Actionate a received message if necessary
+            // Actionate a received message if necessary. Choking state, allowed fast and
+            // suggested pieces are kept by the connection.
             match message {
-                PeerMessage::Bitfield { .. } => {
+                // A peer that had no pieces becomes interesting with its first one
+                PeerMessage::Bitfield { .. } | PeerMessage::HaveAll | PeerMessage::Have { .. }
+                    if !interested =>
+                {
                     // Send an interested message
-                    Self::send_message(stream, PeerMessage::Interested).await?;
+                    connection.send_message(PeerMessage::Interested).await?;
+                    interested = true;
                 }
-                PeerMessage::Unchoke => {
-                    // Success
-                    break Ok(());
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    Self::answer_request(connection, &self.pieces_bytes, index, begin, length)
+                        .await?;
                 }
                 _ => {}
             }
+
+            let is_missing = |index: u32| {
+                self.pieces_bytes
+                    .get(index as usize)
+                    .is_none_or(Vec::is_empty)
+            };
+            if (0..pieces_count).any(|index| is_missing(index) && connection.can_request(index)) {
+                break Ok(());
+            }
         }
     }
 
-    pub async fn download(&mut self) -> anyhow::Result<()> {
-        let torrent_metainfo = self.torrent_metainfo.clone();
+    /// Downloads and verifies every piece, keeping them in memory until saved
+    pub async fn download(&mut self) -> Result<(), Error> {
         let pieces_count = self.torrent_metainfo.info.pieces_count();
-        println!("> Starting to download {pieces_count} pieces");
+        self.events
+            .info(format!("Starting to download {pieces_count} pieces"));
 
-        let stream = self
-            .stream
-            .as_mut()
-            .ok_or_else(|| anyhow::Error::msg(Error::TcpStreamNotAvailable))?;
-
-        for piece_index in 0..pieces_count {
-            let piece_bytes =
-                Self::download_piece(stream, &torrent_metainfo, piece_index as u32).await?;
-            self.pieces_bytes.resize(piece_index as usize + 1, vec![]);
-            self.pieces_bytes[piece_index as usize] = piece_bytes.clone();
+        loop {
+            match self.download_next_piece().await {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(error) => {
+                    self.events.emit(EventKind::Failed {
+                        reason: error.to_string(),
+                    });
+                    return Err(error);
+                }
+            }
         }
 
-        println!("> Successfully downloaded file");
-        Ok(())
-    }
-
-    pub async fn save(&mut self, output_file_path: &str) -> anyhow::Result<()> {
-        let file_bytes = self.pieces_bytes.concat();
-        std::fs::write(&output_file_path, file_bytes)?;
+        self.events.emit(EventKind::Finished);
+        self.events.info(self.stats.to_string());
+        self.stats
+            .peers
+            .iter()
+            .for_each(|peer_stats| self.events.info(peer_stats.to_string()));
+        self.web_seeds
+            .iter()
+            .for_each(|web_seed| self.events.info(web_seed.to_string()));
         Ok(())
     }
-}
 
-impl TorrentClient {
-    pub async fn download_piece(
-        stream: &mut TcpStream,
-        torrent_metainfo: &TorrentMetainfo,
-        piece_index: u32,
-    ) -> anyhow::Result<Vec<u8>> {
-        println!("> Starting to download piece {piece_index}");
+    // Downloads one of the missing pieces, None once they are all there
+    pub(crate) async fn download_next_piece(&mut self) -> Result<Option<u32>, Error> {
+        let pieces_count = self.torrent_metainfo.info.pieces_count();
+        self.pieces_bytes.resize(pieces_count, vec![]);
+        let remaining_pieces: Vec<u32> = (0..pieces_count as u32)
+            .filter(|piece_index| self.pieces_bytes[*piece_index as usize].is_empty())
+            .collect();
+        let Some(piece_index) = self.next_piece(&remaining_pieces) else {
+            return Ok(None);
+        };
 
-        let piece_length = Self::get_piece_length(&torrent_metainfo, piece_index);
-        println!("> Piece length: {piece_length} bytes");
+        let piece_bytes = self.download_verified_piece(piece_index).await?;
+        self.pieces_bytes[piece_index as usize] = piece_bytes;
+        self.add_peer_dht_node();
 
-        let mut piece_bytes: Vec<u8> = Vec::with_capacity(piece_length);
+        // Let the peer know, pieces in its allowed fast set become available to it
+        if let Some(connection) = self.connection.as_mut() {
+            if let Err(error) = connection
+                .send_message(PeerMessage::Have { index: piece_index })
+                .await
+            {
+                self.events
+                    .for_piece(piece_index)
+                    .warn(format!("Could not announce piece {piece_index}: {error}"));
+            }
+        }
+        Ok(Some(piece_index))
+    }
 
-        // Send the first request message
-        Self::send_download_piece_block_message(stream, piece_index, 0, piece_length).await?;
+    pub(crate) fn pieces_downloaded(&self) -> usize {
+        self.pieces_bytes
+            .iter()
+            .filter(|piece_bytes| !piece_bytes.is_empty())
+            .count()
+    }
 
-        loop {
-            // Wait for the stream to be available
-            stream.readable().await?;
+    // Pieces the peer serves right away go first, those it suggested before the others.
+    // The rest follow in order.
+    fn next_piece(&self, remaining_pieces: &[u32]) -> Option<u32> {
+        let requestable_piece = self.connection.as_ref().and_then(|connection| {
+            let requestable = |piece_index: &&u32| connection.can_request(**piece_index);
+            connection
+                .suggested_pieces
+                .iter()
+                .filter(|piece_index| remaining_pieces.contains(piece_index))
+                .find(requestable)
+                .or_else(|| remaining_pieces.iter().find(requestable))
+                .copied()
+        });
+        requestable_piece.or_else(|| remaining_pieces.first().copied())
+    }
 
-            // Read a message
-            let message = Self::read_message(stream).await?;
-            println!("> Received message: {message}");
+    /// Downloads a piece from the web seeds or the connected peer, retrying until it
+    /// passes its hash check
+    pub async fn download_verified_piece(&mut self, piece_index: u32) -> Result<Vec<u8>, Error> {
+        let events = self.events.for_piece(piece_index);
+        if self.torrent_metainfo.info.is_v2() {
+            self.ensure_piece_layers().await?;
+        }
+        let piece_length = self.torrent_metainfo.info.piece_size(piece_index);
 
-            let PeerMessage::Piece { begin, block, .. } = message else {
+        // Only pieces failing their hash check use up attempts
+        let mut attempt = 0;
+        while attempt < MAX_PIECE_ATTEMPTS {
+            if let Some(web_seed_index) = self.next_web_seed().await {
+                let url = self.web_seeds[web_seed_index].url.clone();
+                match self
+                    .download_piece_from_web_seed(web_seed_index, piece_index)
+                    .await
+                {
+                    Ok(piece_bytes) => return Ok(piece_bytes),
+                    // A busy seed is not failing, the piece goes to another source meanwhile
+                    Err(error @ Error::WebSeed(WebSeedError::Busy { .. })) => {
+                        events.debug(error.to_string());
+                        continue;
+                    }
+                    Err(error @ Error::PieceHashNotValid) => {
+                        attempt += 1;
+                        events.emit(EventKind::HashFailed {
+                            index: piece_index,
+                            attempt,
+                            reason: format!("{error} (from {url})"),
+                        });
+                    }
+                    Err(Error::WebSeed(error)) => {
+                        events.emit(EventKind::WebSeedFailed {
+                            index: piece_index,
+                            url,
+                            reason: error.to_string(),
+                        });
+                    }
+                    Err(error) => return Err(error),
+                }
+                let web_seed = &mut self.web_seeds[web_seed_index];
+                if web_seed.record_failure() {
+                    events.warn(format!(
+                        "Dropped web seed {} after repeated failures",
+                        web_seed.url
+                    ));
+                }
                 continue;
-            };
+            }
 
-            // Append the block's bytes to the already downloaded bytes
-            piece_bytes.extend(block.clone());
+            attempt += 1;
+            let mut piece_buffer = PieceBuffer::new(piece_index, piece_length);
+            self.fill_piece_buffer(&mut piece_buffer).await?;
 
-            // If it has downloaded all blocks in the piece, break the loop and end
-            let begin_offset = begin as usize + block.len();
-            if begin_offset >= piece_length {
-                // Verify the piece
-                let metainfo_piece_hash =
-                    torrent_metainfo.info.pieces_hashes()[piece_index as usize].clone();
-                Self::verify_piece(&piece_bytes, metainfo_piece_hash.as_str())?;
+            match self.verify_piece(piece_index, &piece_buffer.bytes) {
+                Ok(()) => {
+                    self.stats.pieces_downloaded += 1;
+                    for contributor in &piece_buffer.contributors {
+                        self.stats.peer_mut(contributor.peer).bytes_downloaded +=
+                            contributor.length as usize;
+                    }
+                    self.record_peer_client();
+                    let source = piece_buffer
+                        .contributing_peers()
+                        .iter()
+                        .map(SocketAddr::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    events.emit(EventKind::PieceVerified {
+                        index: piece_index,
+                        source,
+                    });
+                    return Ok(piece_buffer.bytes);
+                }
+                // A hybrid torrent whose v1 hashes pass where its v2 ones fail cannot be trusted
+                Err(error @ Error::HybridHashesInconsistent { .. }) => return Err(error),
+                Err(error) => {
+                    events.emit(EventKind::HashFailed {
+                        index: piece_index,
+                        attempt,
+                        reason: error.to_string(),
+                    });
+                    self.handle_hash_failure(&piece_buffer).await?;
+                }
+            }
+        }
 
-                // Finished
-                println!("> Successfully downloaded piece {piece_index}");
-                break Ok(piece_bytes);
+        Err(Error::PieceDownloadFailed {
+            index: piece_index,
+            attempts: MAX_PIECE_ATTEMPTS,
+        })
+    }
+
+    // Spreads pieces over the peer connection and the available web seeds in turn.
+    // Returns None when the turn goes to the peer connection.
+    async fn next_web_seed(&mut self) -> Option<usize> {
+        // Without a peer, wait for the first busy web seed to take requests again
+        if self.connection.is_none() && !self.web_seeds.iter().any(WebSeed::is_available) {
+            if let Some(retry_at) = self.web_seeds.iter().filter_map(WebSeed::retry_at).min() {
+                self.events.debug("Waiting for a busy web seed");
+                tokio::time::sleep_until(retry_at).await;
             }
+        }
 
-            // Send followup request messages
-            Self::send_download_piece_block_message(
-                stream,
+        let available_web_seeds: Vec<usize> = (0..self.web_seeds.len())
+            .filter(|index| self.web_seeds[*index].is_available())
+            .collect();
+        let sources_count = available_web_seeds.len() + self.connection.is_some() as usize;
+        if sources_count == 0 {
+            return None;
+        }
+        self.source_turn = (self.source_turn + 1) % sources_count;
+        available_web_seeds.get(self.source_turn).copied()
+    }
+
+    async fn download_piece_from_web_seed(
+        &mut self,
+        web_seed_index: usize,
+        piece_index: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let events = self.events.for_piece(piece_index);
+        let web_seed = &mut self.web_seeds[web_seed_index];
+        events.debug(format!(
+            "Downloading piece {piece_index} from {}",
+            web_seed.url
+        ));
+        let piece_bytes = web_seed
+            .fetch_piece(
+                &self.torrent_metainfo.info,
                 piece_index,
-                begin_offset as u32,
-                piece_length,
+                self.rate_limiter.as_deref(),
             )
             .await?;
+
+        if let Err(error) = self.verify_piece(piece_index, &piece_bytes) {
+            self.stats.hash_failures += 1;
+            return Err(error);
         }
+
+        self.stats.pieces_downloaded += 1;
+        let web_seed = &mut self.web_seeds[web_seed_index];
+        web_seed.bytes_downloaded += piece_bytes.len();
+        events.emit(EventKind::PieceVerified {
+            index: piece_index,
+            source: web_seed.url.to_string(),
+        });
+        Ok(piece_bytes)
     }
 
-    fn get_piece_length(torrent_metainfo: &TorrentMetainfo, piece_index: u32) -> usize {
-        let file_length = torrent_metainfo.info.length;
-        let piece_length = torrent_metainfo.info.piece_length;
-        let pieces_count = torrent_metainfo.info.pieces_count();
-        let piece_length = if piece_index == pieces_count as u32 - 1 {
-            // Last piece
-            file_length % piece_length as usize
-        } else {
-            piece_length
-        };
-        piece_length
+    // Downloads the missing blocks of a piece, moving them to another peer if the current one fails
+    async fn fill_piece_buffer(&mut self, piece_buffer: &mut PieceBuffer) -> Result<(), Error> {
+        let events = self.events.for_piece(piece_buffer.piece_index);
+        loop {
+            let connection = self
+                .connection
+                .as_mut()
+                .ok_or(Error::TcpStreamNotAvailable)?;
+            let address = connection.address;
+
+            let rate_limiter = self.rate_limiter.as_deref();
+            match Self::download_piece(connection, piece_buffer, &self.pieces_bytes, rate_limiter)
+                .await
+            {
+                Ok(()) => break Ok(()),
+                Err(error) => {
+                    let missing_blocks_count = piece_buffer.missing_blocks().len();
+                    events.warn(format!(
+                        "Peer {address} failed ({error}), reassigning {missing_blocks_count} blocks of piece {} to another peer",
+                        piece_buffer.piece_index
+                    ));
+                    self.stats.reissued_requests += missing_blocks_count;
+                    self.mark_unresponsive(address);
+                    self.reconnect().await?;
+                }
+            }
+        }
     }
 
-    async fn read_message(stream: &mut TcpStream) -> anyhow::Result<PeerMessage> {
-        // Read the message size (first 4 bytes)
-        let message_size = stream.read_u32().await;
-        let Ok(message_size) = message_size else {
-            return Err(anyhow::Error::msg(Error::PeerClosedConnection));
+    async fn handle_hash_failure(&mut self, piece_buffer: &PieceBuffer) -> Result<(), Error> {
+        let events = self.events.for_piece(piece_buffer.piece_index);
+        self.stats.hash_failures += 1;
+
+        for contributor in &piece_buffer.contributors {
+            events.debug(format!(
+                "Piece {} block (begin: {}, length: {}) came from {}",
+                piece_buffer.piece_index, contributor.begin, contributor.length, contributor.peer
+            ));
+        }
+
+        // Peers that sent a bad block take a strike. When block hashes are not available,
+        // every peer that sent a block of the bad piece does.
+        let suspects = match self.find_bad_blocks_v2(piece_buffer).await {
+            Some(bad_blocks) => {
+                events.debug(format!(
+                    "Piece {} has {} bad blocks",
+                    piece_buffer.piece_index,
+                    bad_blocks.len()
+                ));
+                piece_buffer.contributing_peers_of(&bad_blocks)
+            }
+            None => piece_buffer.contributing_peers(),
         };
-        if message_size == 0 {
-            return Err(anyhow::Error::msg(Error::PeerClosedConnection));
-        }
-
-        // Read the message id (following 1 byte)
-        let message_id = stream.read_u8().await?;
-
-        // Define the expected body length
-        let expected_body_length =
-            PeerMessage::get_expected_message_length(message_id, message_size as usize);
-        let mut message_body = vec![0u8; expected_body_length];
-
-        if expected_body_length > 0 {
-            // Read the message body
-            let read_body_length = stream.read_exact(&mut message_body).await?;
-            if expected_body_length != read_body_length {
-                println!(
-                    "{}",
-                    Error::MessageBodyNotReadCorrect {
-                        expected: expected_body_length,
-                        actual: read_body_length
+        for peer in suspects {
+            if self.peer_bans.record_hash_failure(peer) {
+                self.stats.banned_peers += 1;
+                self.events
+                    .warn(format!("Banned peer {peer} for sending bad data"));
+            }
+        }
+
+        // Move to another peer if the current one just got banned
+        let Some(connection) = self.connection.as_ref() else {
+            return Ok(());
+        };
+        if self.peer_bans.is_banned(&connection.address) {
+            self.reconnect().await?;
+        }
+        Ok(())
+    }
+
+    // Drops the current peer and sets up a session with the next usable one
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        if self.connection.is_some() {
+            if let Err(error) = self.disconnect().await {
+                self.events
+                    .warn(format!("Could not disconnect cleanly: {error}"));
+            }
+        }
+
+        loop {
+            self.connect().await?;
+
+            let session = match self.handshake().await {
+                Ok(_) => self.prepare_for_download().await,
+                Err(error) => Err(error),
+            };
+
+            match session {
+                Ok(()) => break Ok(()),
+                Err(error) if error.is_retryable() => {
+                    if let Some(connection) = self.connection.take() {
+                        self.events
+                            .warn(format!("Peer {} failed: {error}", connection.address));
+                        self.mark_unresponsive(connection.address);
                     }
-                )
+                }
+                Err(error) => break Err(error),
             }
         }
+    }
+
+    // A peer telling its DHT port runs a node worth adding to the routing table
+    fn add_peer_dht_node(&mut self) {
+        let (Some(dht), Some(connection)) = (self.dht.as_ref(), self.connection.as_mut()) else {
+            return;
+        };
+        if let Some(port) = connection.dht_port.take() {
+            dht.add_node(SocketAddr::new(connection.address.ip(), port));
+        }
+    }
+
+    // Keeps the per-peer stats in sync with what the connected peer told about itself
+    fn record_peer_client(&mut self) {
+        let Some(connection) = self.connection.as_ref() else {
+            return;
+        };
+        if connection.client.is_some() {
+            self.stats.peer_mut(connection.address).client = connection.client.clone();
+        }
+    }
 
-        // Return a peer message with the id and body read
-        PeerMessage::from_bytes(message_id, &message_body)
+    fn mark_unresponsive(&mut self, peer: SocketAddr) {
+        self.peer_bans.mark_unresponsive(peer);
+        self.stats.unresponsive_peers += 1;
     }
 
-    async fn send_message(stream: &mut TcpStream, message: PeerMessage) -> anyhow::Result<()> {
-        if let Some(message_bytes) = message.to_bytes() {
-            stream.write_all(&message_bytes).await?;
-            println!("> Sent message: {:?}", message);
+    /// Writes the downloaded pieces to the output path, in a directory for
+    /// multi-file torrents
+    pub async fn save(&mut self, output_file_path: &str) -> Result<(), Error> {
+        let file_bytes = self.pieces_bytes.concat();
+        let info = &self.torrent_metainfo.info;
+        if !info.is_multi_file()? {
+            file_storage::write_file(Path::new(output_file_path), &file_bytes)?;
+            return Ok(());
         }
+        file_storage::write_files(
+            Path::new(output_file_path),
+            &info.files()?,
+            &file_bytes,
+            &self.events,
+        )?;
         Ok(())
     }
+}
 
-    async fn send_download_piece_block_message(
-        stream: &mut TcpStream,
-        piece_index: u32,
-        begin_offset: u32,
-        piece_length: usize,
-    ) -> anyhow::Result<()> {
-        let next_block_length = (piece_length as u32) - begin_offset;
-        let next_block_length = if next_block_length > PIECE_BLOCK_SIZE {
-            PIECE_BLOCK_SIZE
-        } else {
-            next_block_length
+impl TorrentClient {
+    pub(crate) async fn download_piece(
+        connection: &mut PeerConnection,
+        piece_buffer: &mut PieceBuffer,
+        completed_pieces: &[Vec<u8>],
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<(), PeerError> {
+        let piece_index = piece_buffer.piece_index;
+        let events = connection.events.for_piece(piece_index);
+        events.debug(format!("Starting to download piece {piece_index}"));
+        events.debug(format!("Piece length: {} bytes", piece_buffer.bytes.len()));
+
+        let mut pending_blocks: VecDeque<(u32, u32)> = piece_buffer.missing_blocks().into();
+        let mut outstanding_requests: Vec<BlockRequest> = vec![];
+        let mut last_block_at = Instant::now();
+
+        while !piece_buffer.is_complete() {
+            // Keep the requests pipeline full while the peer lets us
+            let can_request = connection.can_request(piece_index);
+            while can_request && outstanding_requests.len() < MAX_OUTSTANDING_REQUESTS {
+                let Some((begin, length)) = pending_blocks.pop_front() else {
+                    break;
+                };
+                if let Some(rate_limiter) = rate_limiter {
+                    rate_limiter.acquire(length as usize).await;
+                }
+                Self::send_block_request(connection, piece_index, begin, length).await?;
+                outstanding_requests.push(BlockRequest {
+                    begin,
+                    length,
+                    requested_at: Instant::now(),
+                });
+            }
+
+            // A peer that stopped delivering blocks is snubbing us
+            let idle_time = last_block_at.elapsed();
+            if idle_time >= SNUB_TIMEOUT {
+                return Err(PeerError::Snubbed {
+                    address: connection.address,
+                });
+            }
+
+            // Read a message, waking up when the oldest request times out
+            let request_wait = outstanding_requests
+                .iter()
+                .map(|request| REQUEST_TIMEOUT.saturating_sub(request.requested_at.elapsed()))
+                .min()
+                .unwrap_or(REQUEST_TIMEOUT);
+            let wait = (SNUB_TIMEOUT - idle_time).min(request_wait);
+            if let Some(message) = connection.wait_for_message(wait).await? {
+                events.trace(format!("Received message: {message}"));
+
+                match message {
+                    PeerMessage::Piece {
+                        index,
+                        begin,
+                        block,
+                    } if index == piece_index => {
+                        // Store the block's bytes, remembering which peer sent them
+                        outstanding_requests.retain(|request| request.begin != begin);
+                        piece_buffer.add_block(begin, &block, connection.address);
+                        last_block_at = Instant::now();
+                    }
+                    // Without the fast extension a choking peer silently discards our
+                    // requests, with it each of them gets explicitly rejected
+                    PeerMessage::Choke if !connection.fast_extension => {
+                        pending_blocks.extend(
+                            outstanding_requests
+                                .drain(..)
+                                .map(|request| (request.begin, request.length)),
+                        );
+                    }
+                    PeerMessage::RejectRequest { index, begin, .. } if index == piece_index => {
+                        // Ask again later, possibly once unchoked
+                        if let Some(position) = outstanding_requests
+                            .iter()
+                            .position(|request| request.begin == begin)
+                        {
+                            let request = outstanding_requests.remove(position);
+                            pending_blocks.push_back((request.begin, request.length));
+                        }
+                    }
+                    PeerMessage::Request {
+                        index,
+                        begin,
+                        length,
+                    } => {
+                        Self::answer_request(connection, completed_pieces, index, begin, length)
+                            .await?;
+                    }
+                    _ => {}
+                }
+            }
+
+            // A block the peer sat on for too long goes back to the missing ones of the piece,
+            // to be requested from another peer
+            if let Some(request) = outstanding_requests
+                .iter()
+                .find(|request| request.requested_at.elapsed() >= REQUEST_TIMEOUT)
+            {
+                events.debug(format!(
+                    "Request for piece {piece_index} block {} timed out",
+                    request.begin
+                ));
+                return Err(PeerError::RequestTimeout);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tells the peer which pieces we have, in the most compact form it understands
+    async fn send_availability(
+        connection: &mut PeerConnection,
+        completed_pieces: &[Vec<u8>],
+        pieces_count: u32,
+    ) -> Result<(), PeerError> {
+        let mut bitfield = vec![0u8; (pieces_count as usize).div_ceil(8)];
+        let mut have_count = 0;
+        for (piece_index, piece_bytes) in completed_pieces.iter().enumerate() {
+            if !piece_bytes.is_empty() {
+                bitfield[piece_index / 8] |= 0x80 >> (piece_index % 8);
+                have_count += 1;
+            }
+        }
+
+        let message = match (connection.fast_extension, have_count) {
+            (true, 0) => PeerMessage::HaveNone,
+            (true, count) if count == pieces_count => PeerMessage::HaveAll,
+            // Without the fast extension, having no pieces is told by not sending a bitfield
+            (false, 0) => return Ok(()),
+            _ => PeerMessage::Bitfield { bitfield },
         };
+        connection.send_message(message).await
+    }
+
+    // We never unchoke peers, so only pieces of their allowed fast set are served
+    async fn answer_request(
+        connection: &mut PeerConnection,
+        completed_pieces: &[Vec<u8>],
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<(), PeerError> {
+        let block = completed_pieces
+            .get(index as usize)
+            .and_then(|piece_bytes| {
+                let end = begin.checked_add(length)? as usize;
+                piece_bytes.get(begin as usize..end)
+            });
 
-        Self::send_message(
-            stream,
-            PeerMessage::Request {
+        match block {
+            Some(block)
+                if connection.granted_fast_pieces.contains(&index)
+                    && length <= MAX_SERVED_BLOCK_LENGTH =>
+            {
+                let block = block.to_vec();
+                connection
+                    .send_message(PeerMessage::Piece {
+                        index,
+                        begin,
+                        block,
+                    })
+                    .await
+            }
+            _ if connection.fast_extension => {
+                connection
+                    .send_message(PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    })
+                    .await
+            }
+            // Choked requests are silently dropped without the fast extension
+            _ => Ok(()),
+        }
+    }
+
+    async fn send_block_request(
+        connection: &mut PeerConnection,
+        piece_index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<(), PeerError> {
+        connection
+            .send_message(PeerMessage::Request {
                 index: piece_index,
-                begin: begin_offset,
-                length: next_block_length,
-            },
-        )
-        .await
+                begin,
+                length,
+            })
+            .await
+    }
+
+    fn verify_piece(&self, piece_index: u32, piece_bytes: &[u8]) -> Result<(), Error> {
+        let info = &self.torrent_metainfo.info;
+        if !info.is_hybrid() {
+            return match info.is_v2() {
+                true => self.verify_piece_v2(piece_index, piece_bytes),
+                false => {
+                    let metainfo_piece_hash = &info.pieces_hashes()[piece_index as usize];
+                    Self::verify_piece_v1(piece_bytes, metainfo_piece_hash)
+                }
+            };
+        }
+
+        // Hybrid torrents: the piece must match both hashes. Failing only the v1 one is bad
+        // data, like non-zero padding which v2 does not hash. Failing only the v2 one means
+        // the torrent itself is inconsistent.
+        let metainfo_piece_hash = &info.pieces_hashes()[piece_index as usize];
+        let v1_result = Self::verify_piece_v1(piece_bytes, metainfo_piece_hash);
+        let v2_result = self.verify_piece_v2(piece_index, piece_bytes);
+        match (v1_result, v2_result) {
+            (Ok(()), Ok(())) => Ok(()),
+            (_, Err(error)) if !matches!(error, Error::PieceHashNotValid) => Err(error),
+            (Err(error), _) => Err(error),
+            (Ok(()), Err(_)) => Err(Error::HybridHashesInconsistent { index: piece_index }),
+        }
     }
 
-    fn verify_piece(piece_bytes: &[u8], metainfo_piece_hash: &str) -> anyhow::Result<()> {
+    fn verify_piece_v1(piece_bytes: &[u8], metainfo_piece_hash: &str) -> Result<(), Error> {
         let mut hasher = Sha1::new();
         hasher.update(piece_bytes);
         let piece_hash: String = hasher.finalize().encode_hex::<String>();
 
         match piece_hash == metainfo_piece_hash {
             true => Ok(()),
-            false => Err(anyhow::Error::msg(Error::PieceHashNotValid)),
+            false => Err(Error::PieceHashNotValid),
+        }
+    }
+}
+
+// v2 related
+impl TorrentClient {
+    fn verify_piece_v2(&self, piece_index: u32, piece_bytes: &[u8]) -> Result<(), Error> {
+        let (file, piece_in_file) = self.locate_piece_v2(piece_index)?;
+        let expected_hash = self.expected_piece_hash_v2(&file, piece_in_file)?;
+        let info = &self.torrent_metainfo.info;
+        let leaves_count = info.piece_leaves_count(&file);
+        // Hybrid pieces may end with padding, which v2 does not hash
+        let piece_size = self
+            .torrent_metainfo
+            .info
+            .piece_size_v2(&file, piece_in_file)
+            .min(piece_bytes.len());
+        let piece_hash = merkle::root(
+            &merkle::block_hashes(&piece_bytes[..piece_size]),
+            leaves_count,
+        );
+
+        match piece_hash == expected_hash {
+            true => Ok(()),
+            false => Err(Error::PieceHashNotValid),
+        }
+    }
+
+    fn locate_piece_v2(&self, piece_index: u32) -> Result<(FileV2, u32), Error> {
+        self.torrent_metainfo
+            .info
+            .piece_location_v2(piece_index)
+            .ok_or_else(|| {
+                MetainfoError::NotValid {
+                    reason: format!("piece {piece_index} is outside of the file tree"),
+                }
+                .into()
+            })
+    }
+
+    // Files fitting in a single piece have no piece layer, their root is the piece hash
+    fn expected_piece_hash_v2(
+        &self,
+        file: &FileV2,
+        piece_in_file: u32,
+    ) -> Result<MerkleHash, Error> {
+        let file_name = file.path.join("/");
+        let pieces_root = file
+            .pieces_root
+            .ok_or_else(|| MetainfoError::PieceLayerMissing {
+                file: file_name.clone(),
+            })?;
+        if file.length <= self.torrent_metainfo.info.piece_length {
+            return Ok(pieces_root);
+        }
+
+        let piece_layer = self
+            .torrent_metainfo
+            .piece_layer(&pieces_root)
+            .ok_or_else(|| MetainfoError::PieceLayerMissing {
+                file: file_name.clone(),
+            })?;
+        piece_layer
+            .get(piece_in_file as usize)
+            .copied()
+            .ok_or_else(|| MetainfoError::PieceLayerNotValid { file: file_name }.into())
+    }
+
+    // Makes sure every file spanning several pieces has a piece layer matching its root,
+    // fetching the missing ones from the connected peer
+    async fn ensure_piece_layers(&mut self) -> Result<(), Error> {
+        if self.piece_layers_checked {
+            return Ok(());
+        }
+
+        let info = &self.torrent_metainfo.info;
+        let piece_length = info.piece_length;
+        let padding = merkle::padding_root(info.blocks_per_piece());
+
+        for file in info.files_v2()? {
+            let Some(pieces_root) = file.pieces_root else {
+                continue;
+            };
+            if file.length <= piece_length {
+                continue;
+            }
+
+            let file_name = file.path.join("/");
+            let pieces_count = file.length.div_ceil(piece_length);
+            let (piece_layer, from_peer) = match self.torrent_metainfo.piece_layer(&pieces_root) {
+                Some(piece_layer) => (piece_layer, false),
+                None => {
+                    self.events.debug(format!(
+                        "Requesting the piece layer of '{file_name}' from the peer"
+                    ));
+                    let piece_layer = self.request_piece_layer(&pieces_root, pieces_count).await?;
+                    (piece_layer, true)
+                }
+            };
+
+            let piece_layer_root = merkle::root_with_padding(&piece_layer, pieces_count, padding);
+            if piece_layer.len() != pieces_count || piece_layer_root != pieces_root {
+                return Err(MetainfoError::PieceLayerNotValid { file: file_name }.into());
+            }
+            self.torrent_metainfo
+                .set_piece_layer(&pieces_root, &piece_layer);
+            if from_peer {
+                self.events
+                    .emit(EventKind::MetadataReceived { file: file_name });
+            }
         }
+
+        self.piece_layers_checked = true;
+        Ok(())
+    }
+
+    async fn request_piece_layer(
+        &mut self,
+        pieces_root: &MerkleHash,
+        pieces_count: usize,
+    ) -> Result<Vec<MerkleHash>, Error> {
+        let blocks_per_piece = self.torrent_metainfo.info.blocks_per_piece();
+        let length = (pieces_count.next_power_of_two().max(2) as u32).min(MAX_HASHES_PER_REQUEST);
+        let mut piece_layer = vec![];
+
+        // Requests are aligned on their length, the last one may cover padding hashes
+        while piece_layer.len() < pieces_count {
+            let range = HashRange {
+                pieces_root: pieces_root.to_vec(),
+                base_layer: blocks_per_piece.trailing_zeros(),
+                index: piece_layer.len() as u32,
+                length,
+                proof_layers: 0,
+            };
+            let hashes = self.request_hashes(range).await?;
+            // A short reply leaves the layer incomplete, the caller rejects it
+            let is_short = hashes.len() < length as usize;
+            piece_layer.extend(hashes);
+            if is_short {
+                break;
+            }
+        }
+        piece_layer.truncate(pieces_count);
+        Ok(piece_layer)
+    }
+
+    // Uses the block hashes of a piece, when the peer provides them, to tell the bad blocks.
+    // Returns the begin offsets of the bad blocks.
+    async fn find_bad_blocks_v2(&mut self, piece_buffer: &PieceBuffer) -> Option<Vec<u32>> {
+        let events = self.events.for_piece(piece_buffer.piece_index);
+        let info = &self.torrent_metainfo.info;
+        if !info.is_v2() {
+            return None;
+        }
+        let (file, piece_in_file) = self.locate_piece_v2(piece_buffer.piece_index).ok()?;
+        let expected_hash = self.expected_piece_hash_v2(&file, piece_in_file).ok()?;
+        let leaves_count = info.piece_leaves_count(&file);
+        if leaves_count < 2 {
+            return None;
+        }
+
+        let range = HashRange {
+            pieces_root: file.pieces_root?.to_vec(),
+            base_layer: 0,
+            index: piece_in_file * leaves_count as u32,
+            length: leaves_count as u32,
+            proof_layers: 0,
+        };
+        let block_hashes = match self.request_hashes(range).await {
+            Ok(block_hashes) => block_hashes,
+            Err(error) => {
+                events.debug(format!(
+                    "Could not get block hashes of piece {}: {error}",
+                    piece_buffer.piece_index
+                ));
+                return None;
+            }
+        };
+
+        // Block hashes are only trusted if they add up to the known piece hash
+        if block_hashes.len() != leaves_count
+            || merkle::root(&block_hashes, leaves_count) != expected_hash
+        {
+            return None;
+        }
+
+        let piece_size = self
+            .torrent_metainfo
+            .info
+            .piece_size_v2(&file, piece_in_file)
+            .min(piece_buffer.bytes.len());
+        let bad_blocks = merkle::block_hashes(&piece_buffer.bytes[..piece_size])
+            .iter()
+            .zip(&block_hashes)
+            .enumerate()
+            .filter(|(_, (received, expected))| received != expected)
+            .map(|(block_index, _)| (block_index * MERKLE_BLOCK_SIZE) as u32)
+            .collect();
+        Some(bad_blocks)
+    }
+
+    async fn request_hashes(&mut self, range: HashRange) -> Result<Vec<MerkleHash>, Error> {
+        let connection = self
+            .connection
+            .as_mut()
+            .ok_or(Error::TcpStreamNotAvailable)?;
+        if !connection.v2_hashes {
+            return Err(PeerError::HashRequestNotSupported {
+                address: connection.address,
+            }
+            .into());
+        }
+
+        connection
+            .send_message(PeerMessage::HashRequest {
+                range: range.clone(),
+            })
+            .await?;
+
+        loop {
+            let Some(message) = connection.wait_for_message(REQUEST_TIMEOUT).await? else {
+                break Err(PeerError::RequestTimeout.into());
+            };
+            connection
+                .events
+                .trace(format!("Received message: {message}"));
+
+            match message {
+                PeerMessage::Hashes {
+                    range: hashes_range,
+                    hashes,
+                } if hashes_range == range => break Ok(merkle::hashes_from_bytes(&hashes)),
+                PeerMessage::HashReject {
+                    range: rejected_range,
+                } if rejected_range == range => break Err(PeerError::HashRequestRejected.into()),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_bencode::value::Value;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::torrent_client::torrent_builder::TorrentMetainfoBuilder;
+
+    const PIECE_LENGTH: usize = 2 * MERKLE_BLOCK_SIZE;
+
+    // Three pieces: two of "a", the second one short, then "b" on its own
+    fn files() -> Vec<(&'static str, Vec<u8>)> {
+        let a = (0..3 * MERKLE_BLOCK_SIZE + 100)
+            .map(|index| index as u8)
+            .collect();
+        vec![("a", a), ("b", vec![7u8; 100])]
+    }
+
+    // A v2 torrent, its hashes computed from the whole files as BEP 52 defines them
+    fn v2_metainfo(piece_layer_of_a: Option<Vec<MerkleHash>>) -> TorrentMetainfo {
+        let mut file_tree = HashMap::new();
+        let mut piece_layers = HashMap::new();
+        for (name, bytes) in files() {
+            let block_hashes = merkle::block_hashes(&bytes);
+            let pieces_root = merkle::root(&block_hashes, block_hashes.len());
+            if bytes.len() > PIECE_LENGTH {
+                let piece_layer = piece_layer_of_a.clone().unwrap_or_else(|| {
+                    bytes
+                        .chunks(PIECE_LENGTH)
+                        .map(|piece| merkle::root(&merkle::block_hashes(piece), 2))
+                        .collect()
+                });
+                piece_layers.insert(pieces_root.to_vec(), Value::Bytes(piece_layer.concat()));
+            }
+            let properties = HashMap::from([
+                (b"length".to_vec(), Value::Int(bytes.len() as i64)),
+                (b"pieces root".to_vec(), Value::Bytes(pieces_root.to_vec())),
+            ]);
+            let file = HashMap::from([(vec![], Value::Dict(properties))]);
+            file_tree.insert(name.as_bytes().to_vec(), Value::Dict(file));
+        }
+        let info = HashMap::from([
+            (b"file tree".to_vec(), Value::Dict(file_tree)),
+            (b"meta version".to_vec(), Value::Int(2)),
+            (b"name".to_vec(), Value::Bytes(b"v2".to_vec())),
+            (b"piece length".to_vec(), Value::Int(PIECE_LENGTH as i64)),
+        ]);
+        let torrent = Value::Dict(HashMap::from([
+            (b"info".to_vec(), Value::Dict(info)),
+            (b"piece layers".to_vec(), Value::Dict(piece_layers)),
+        ]));
+        TorrentMetainfo::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap()).unwrap()
+    }
+
+    fn pieces() -> Vec<Vec<u8>> {
+        let files = files();
+        let mut pieces: Vec<Vec<u8>> = files[0].1.chunks(PIECE_LENGTH).map(Vec::from).collect();
+        pieces.push(files[1].1.clone());
+        pieces
+    }
+
+    // A hybrid torrent announcing to a local tracker
+    fn hybrid_metainfo(announce: String) -> TorrentMetainfo {
+        let mut metainfo = v2_metainfo(None);
+        metainfo.info.pieces = vec![0; 3 * 20];
+        metainfo.announce = announce;
+        metainfo
+    }
+
+    // A tracker only answering announces of the given swarm, with a single peer
+    async fn tracker_serving(info_hash: Option<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let encoded_info_hash = info_hash.map(|info_hash| {
+            info_hash
+                .iter()
+                .map(|byte| format!("%{byte:02x}"))
+                .collect::<String>()
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0u8; 4096];
+                let read = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..read]).to_string();
+                let serves = encoded_info_hash
+                    .as_ref()
+                    .is_some_and(|encoded| request.contains(encoded.as_str()));
+                // Announces of other swarms get the connection closed on them
+                if serves {
+                    let body = [&b"d5:peers6:"[..], &[10, 0, 0, 1, 0x1a, 0xe1], b"e"].concat();
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                    stream.write_all(&body).await.unwrap();
+                }
+            }
+        });
+        format!("http://{address}/announce")
+    }
+
+    #[tokio::test]
+    async fn peers_of_a_hybrid_torrent_are_kept_when_one_swarm_announce_fails() {
+        let swarm_hashes = hybrid_metainfo(String::new())
+            .info
+            .swarm_hashes_bytes()
+            .unwrap();
+        assert_eq!(swarm_hashes.len(), 2);
+
+        for info_hash in swarm_hashes {
+            let tracker_url = tracker_serving(Some(info_hash.clone())).await;
+            let mut client = TorrentClient::new(hybrid_metainfo(tracker_url));
+            client.fetch_peers().await.unwrap();
+            let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+            assert_eq!(client.peers, vec![peer]);
+            assert_eq!(client.peer_swarms[&peer], info_hash);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetching_peers_fails_when_every_announce_fails() {
+        let tracker_url = tracker_serving(None).await;
+        let mut client = TorrentClient::new(hybrid_metainfo(tracker_url));
+        assert!(client.fetch_peers().await.is_err());
+        assert!(client.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_dht_lookups_do_not_hold_up_peers_from_the_tracker() {
+        let (dht, _silent_socket) = dht::tests::node_knowing_a_silent_node().await;
+        let mut metainfo = v2_metainfo(None);
+        let info_hash = metainfo.info.swarm_hashes_bytes().unwrap().remove(0);
+        metainfo.announce = tracker_serving(Some(info_hash)).await;
+        let mut client = TorrentClient::new(metainfo).with_dht(Some(dht));
+
+        let started_at = Instant::now();
+        client.fetch_peers().await.unwrap();
+        assert!(started_at.elapsed() < 2 * DHT_LOOKUP_GRACE);
+        assert_eq!(client.peers, vec![SocketAddr::from(([10, 0, 0, 1], 6881))]);
+    }
+
+    // A client of the v2 torrent connected to a peer with the fast extension, which
+    // sends the given messages
+    async fn client_receiving(messages: &[PeerMessage]) -> TorrentClient {
+        let bytes = messages.iter().flat_map(PeerMessage::to_bytes).collect();
+        let mut connection = peer_connection::tests::connection_receiving(bytes).await;
+        connection.fast_extension = true;
+        let mut client = TorrentClient::new(v2_metainfo(None));
+        client.connection = Some(connection);
+        client
+    }
+
+    #[tokio::test]
+    async fn choked_peers_serve_their_allowed_fast_pieces() {
+        let mut client =
+            client_receiving(&[PeerMessage::HaveAll, PeerMessage::AllowedFast { index: 1 }]).await;
+        client.prepare_for_download().await.unwrap();
+        assert_eq!(client.next_piece(&[0, 1, 2]), Some(1));
+    }
+
+    #[tokio::test]
+    async fn peers_having_none_are_kept_until_they_get_a_piece() {
+        let mut client = client_receiving(&[
+            PeerMessage::HaveNone,
+            PeerMessage::Unchoke,
+            PeerMessage::Have { index: 2 },
+        ])
+        .await;
+        client.prepare_for_download().await.unwrap();
+        assert_eq!(client.next_piece(&[0, 1, 2]), Some(2));
+    }
+
+    // A single piece torrent of `bytes`, along with the events it emits
+    fn web_seeded_client(
+        bytes: &[u8],
+        directory: &Path,
+        seed: WebSeed,
+    ) -> (TorrentClient, Arc<Mutex<Vec<EventKind>>>) {
+        let path = directory.join("data.bin");
+        std::fs::write(&path, bytes).unwrap();
+        let metainfo = TorrentMetainfoBuilder::new(&path).build().unwrap();
+        let events = Events::default();
+        let emitted = Arc::new(Mutex::new(vec![]));
+        let recorded = emitted.clone();
+        events.on_event(move |event| recorded.lock().unwrap().push(event.kind.clone()));
+        let mut client = TorrentClient::new(metainfo).with_events(&events);
+        client.web_seeds = vec![seed];
+        (client, emitted)
+    }
+
+    #[tokio::test]
+    async fn busy_web_seeds_do_not_use_up_piece_attempts() {
+        let directory = tempfile::tempdir().unwrap();
+        let bytes = vec![9u8; 100];
+        let served = bytes.clone();
+        let requests = Arc::new(Mutex::new(0));
+        let address = web_seed::tests::serve(move |_| {
+            let mut requests = requests.lock().unwrap();
+            *requests += 1;
+            match *requests > 2 * MAX_PIECE_ATTEMPTS {
+                true => (200, served.clone()),
+                false => (503, b"0".to_vec()),
+            }
+        })
+        .await;
+
+        let seed = WebSeed::new(format!("http://{address}/seed"), WebSeedKind::HttpSeed);
+        let (mut client, emitted) = web_seeded_client(&bytes, directory.path(), seed);
+        assert_eq!(client.download_verified_piece(0).await.unwrap(), bytes);
+        let emitted = emitted.lock().unwrap();
+        assert!(!emitted
+            .iter()
+            .any(|kind| matches!(kind, EventKind::HashFailed { .. })));
+    }
+
+    #[tokio::test]
+    async fn web_seed_request_failures_are_not_hash_failures() {
+        let directory = tempfile::tempdir().unwrap();
+        let bytes = vec![9u8; 100];
+        let served = bytes.clone();
+        let requests = Arc::new(Mutex::new(0));
+        let address = web_seed::tests::serve(move |_| {
+            let mut requests = requests.lock().unwrap();
+            *requests += 1;
+            match *requests {
+                1 => (500, vec![]),
+                2 => (206, vec![0u8; 100]),
+                _ => (206, served.clone()),
+            }
+        })
+        .await;
+
+        let seed = WebSeed::new(format!("http://{address}/data.bin"), WebSeedKind::UrlList);
+        let (mut client, emitted) = web_seeded_client(&bytes, directory.path(), seed);
+        assert_eq!(client.download_verified_piece(0).await.unwrap(), bytes);
+        assert_eq!(client.stats.hash_failures, 1);
+        let emitted = emitted.lock().unwrap();
+        let failures: Vec<&EventKind> = emitted
+            .iter()
+            .filter(|kind| !matches!(kind, EventKind::Log { .. }))
+            .collect();
+        assert!(matches!(
+            failures[..],
+            [
+                EventKind::WebSeedFailed { index: 0, .. },
+                EventKind::HashFailed {
+                    index: 0,
+                    attempt: 1,
+                    ..
+                },
+                EventKind::PieceVerified { index: 0, .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn v2_pieces_are_located_in_their_file() {
+        let info = v2_metainfo(None).info;
+        assert!(info.is_v2() && !info.is_hybrid());
+        let (file, piece_in_file) = info.piece_location_v2(1).unwrap();
+        assert_eq!((file.path, piece_in_file), (vec!["a".to_string()], 1));
+        let (file, piece_in_file) = info.piece_location_v2(2).unwrap();
+        assert_eq!(
+            (file.path.clone(), piece_in_file),
+            (vec!["b".to_string()], 0)
+        );
+        assert_eq!(info.piece_leaves_count(&file), 1);
+        assert!(info.piece_location_v2(3).is_none());
+    }
+
+    #[test]
+    fn v2_pieces_are_verified_against_the_piece_layer_and_pieces_root() {
+        let client = TorrentClient::new(v2_metainfo(None));
+        for (piece_index, piece) in pieces().iter().enumerate() {
+            assert!(client.verify_piece(piece_index as u32, piece).is_ok());
+        }
+    }
+
+    #[test]
+    fn v2_pieces_with_a_bad_block_are_rejected() {
+        let client = TorrentClient::new(v2_metainfo(None));
+        for (piece_index, mut piece) in pieces().into_iter().enumerate() {
+            *piece.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                client.verify_piece(piece_index as u32, &piece),
+                Err(Error::PieceHashNotValid)
+            ));
+        }
+    }
+
+    // A hybrid torrent whose v1 hashes are those of `v1_pieces`
+    fn hybrid_metainfo_hashing(v1_pieces: &[Vec<u8>]) -> TorrentMetainfo {
+        let mut metainfo = v2_metainfo(None);
+        metainfo.info.pieces = v1_pieces.iter().flat_map(Sha1::digest).collect();
+        metainfo
+    }
+
+    // v1 pieces of a hybrid torrent, the short piece of "a" padded with `padding`
+    fn padded_pieces(padding: u8) -> Vec<Vec<u8>> {
+        let mut pieces = pieces();
+        pieces[1].resize(PIECE_LENGTH, padding);
+        pieces
+    }
+
+    #[test]
+    fn hybrid_pieces_failing_only_their_v1_hash_are_not_valid() {
+        let client = TorrentClient::new(hybrid_metainfo_hashing(&padded_pieces(0)));
+        assert!(client.verify_piece(1, &padded_pieces(0)[1]).is_ok());
+        assert!(matches!(
+            client.verify_piece(1, &padded_pieces(1)[1]),
+            Err(Error::PieceHashNotValid)
+        ));
+    }
+
+    #[test]
+    fn hybrid_pieces_failing_only_their_v2_hash_are_inconsistent() {
+        let mut v1_pieces = padded_pieces(0);
+        *v1_pieces[0].last_mut().unwrap() ^= 1;
+        let client = TorrentClient::new(hybrid_metainfo_hashing(&v1_pieces));
+        assert!(matches!(
+            client.verify_piece(0, &v1_pieces[0]),
+            Err(Error::HybridHashesInconsistent { index: 0 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn piece_layers_not_matching_the_pieces_root_are_rejected() {
+        let mut client = TorrentClient::new(v2_metainfo(None));
+        assert!(client.ensure_piece_layers().await.is_ok());
+
+        let mut client = TorrentClient::new(v2_metainfo(Some(vec![[1; 32], [2; 32]])));
+        assert!(matches!(
+            client.ensure_piece_layers().await,
+            Err(Error::Metainfo(MetainfoError::PieceLayerNotValid { .. }))
+        ));
     }
 }
@@ -0,0 +1,694 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::oneshot,
+    task::JoinSet,
+    time::{self, Instant},
+};
+
+use self::krpc::{Body, Message, Query, Response, METHOD_UNKNOWN, PROTOCOL_ERROR};
+use self::routing_table::{NodeId, NodeInfo, RoutingTable, BUCKET_SIZE};
+use super::{error::DhtError, events::Events};
+
+mod krpc;
+mod routing_table;
+
+// Well known nodes to join the network through
+pub(crate) const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// Queries a lookup keeps in flight (BEP 5 calls it alpha)
+const LOOKUP_PARALLELISM: usize = 3;
+const MAX_LOOKUP_ROUNDS: usize = 32;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// KRPC messages fit in a datagram
+const MAX_DATAGRAM_LENGTH: usize = 65_536;
+// A token is accepted until the secret it was made from rotated twice
+const TOKEN_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const TOKEN_SECRET_LENGTH: usize = 16;
+// Peers announced to us expire unless announced again
+const PEER_TIME_TO_LIVE: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_INFO_HASH: usize = 256;
+const MAX_STORED_INFO_HASHES: usize = 4_096;
+const MAX_PEERS_PER_REPLY: usize = 50;
+
+type PendingQueries = HashMap<(SocketAddr, Vec<u8>), oneshot::Sender<Result<Response, DhtError>>>;
+
+// A node of the mainline DHT (BEP 5), over IPv4. It answers the queries of
+// other nodes, and finds the peers of an info hash by asking nodes ever
+// closer to it. Clones share the node.
+#[derive(Clone)]
+pub(crate) struct Dht {
+    node: Arc<DhtNode>,
+}
+
+struct DhtNode {
+    id: NodeId,
+    socket: UdpSocket,
+    routing_table: Mutex<RoutingTable>,
+    // Queries waiting for their response, by node and transaction id
+    pending_queries: Mutex<PendingQueries>,
+    next_transaction_id: AtomicU16,
+    // Peers announced to us, by info hash
+    peers: Mutex<HashMap<NodeId, Vec<(SocketAddr, Instant)>>>,
+    token_secrets: Mutex<TokenSecrets>,
+    events: Events,
+}
+
+// Tokens prove that a node announcing a peer asked us for the peers first,
+// from the same IP address
+struct TokenSecrets {
+    current: [u8; TOKEN_SECRET_LENGTH],
+    previous: [u8; TOKEN_SECRET_LENGTH],
+    rotated_at: Instant,
+}
+
+// What a lookup found: peers, and the closest nodes that answered, with the
+// token to announce to them
+#[derive(Debug, Default)]
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    closest_nodes: Vec<(NodeInfo, Option<Vec<u8>>)>,
+}
+
+#[derive(Debug)]
+struct LookupCandidate {
+    node: NodeInfo,
+    queried: bool,
+    failed: bool,
+    token: Option<Vec<u8>>,
+}
+
+impl Dht {
+    // Binds the UDP socket, messages are handled once `receive_messages` runs
+    pub(crate) async fn bind(port: u16, events: Events) -> Result<Self, DhtError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        let id = NodeId::generate();
+        Ok(Self {
+            node: Arc::new(DhtNode {
+                id,
+                socket,
+                routing_table: Mutex::new(RoutingTable::new(id)),
+                pending_queries: Mutex::new(HashMap::new()),
+                next_transaction_id: AtomicU16::new(rand::thread_rng().gen()),
+                peers: Mutex::new(HashMap::new()),
+                token_secrets: Mutex::new(TokenSecrets::new()),
+                events,
+            }),
+        })
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.node
+            .socket
+            .local_addr()
+            .map(|address| address.port())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn nodes_count(&self) -> usize {
+        self.lock_routing_table().len()
+    }
+
+    // Joins the network through the bootstrap nodes, then keeps the routing
+    // table fresh by looking up our own id again from time to time
+    pub(crate) async fn join_network(self, bootstrap_nodes: Vec<String>) {
+        for bootstrap_node in bootstrap_nodes {
+            let addresses = match lookup_host(bootstrap_node.as_str()).await {
+                Ok(addresses) => addresses,
+                Err(error) => {
+                    self.node
+                        .events
+                        .debug(format!("Could not resolve {bootstrap_node}: {error}"));
+                    continue;
+                }
+            };
+            for address in addresses.filter(SocketAddr::is_ipv4) {
+                if let Err(error) = self.ping(address).await {
+                    self.node
+                        .events
+                        .debug(format!("Bootstrap node {bootstrap_node} failed: {error}"));
+                }
+            }
+        }
+
+        loop {
+            let target = self.node.id;
+            self.lookup(target, || Query::FindNode { target }).await;
+            self.node.events.debug(format!(
+                "DHT routing table holds {} nodes",
+                self.nodes_count()
+            ));
+            time::sleep(REFRESH_INTERVAL).await;
+        }
+    }
+
+    // Pings a node, it joins the routing table if it answers. Peers tell their
+    // DHT port with a Port message.
+    pub(crate) fn add_node(&self, address: SocketAddr) {
+        let dht = self.clone();
+        tokio::spawn(async move {
+            if let Err(error) = dht.ping(address).await {
+                dht.node
+                    .events
+                    .debug(format!("DHT node {address} failed: {error}"));
+            }
+        });
+    }
+
+    // Peers of an info hash, from the nodes closest to it. With a port, we are
+    // announced to them as a peer too.
+    pub(crate) async fn find_peers(
+        &self,
+        info_hash: &[u8],
+        announce_port: Option<u16>,
+    ) -> Result<Vec<SocketAddr>, DhtError> {
+        let info_hash = NodeId::from_bytes(info_hash)?;
+        let lookup = self
+            .lookup(info_hash, || Query::GetPeers { info_hash })
+            .await;
+
+        if let Some(port) = announce_port {
+            let mut announces = JoinSet::new();
+            for (node, token) in lookup.closest_nodes {
+                let Some(token) = token else {
+                    continue;
+                };
+                let dht = self.clone();
+                announces.spawn(async move {
+                    let query = Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port: false,
+                        token,
+                    };
+                    (node, dht.query(node.address, query).await)
+                });
+            }
+            while let Some(announce) = announces.join_next().await {
+                if let Ok((node, Err(error))) = announce {
+                    self.node
+                        .events
+                        .debug(format!("Could not announce to {}: {error}", node.address));
+                }
+            }
+        }
+
+        Ok(lookup.peers)
+    }
+
+    // Answers queries and hands responses to the queries waiting for them
+    pub(crate) async fn receive_messages(self) {
+        let mut buffer = vec![0; MAX_DATAGRAM_LENGTH];
+        loop {
+            let (length, address) = match self.node.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(error) => {
+                    self.node
+                        .events
+                        .debug(format!("Could not receive a DHT message: {error}"));
+                    continue;
+                }
+            };
+            let message = match Message::from_bytes(&buffer[..length]) {
+                Ok(message) => message,
+                Err(error) => {
+                    self.node
+                        .events
+                        .trace(format!("Dropping a DHT message from {address}: {error}"));
+                    continue;
+                }
+            };
+
+            let result = match message.body {
+                Body::Query { sender_id, query } => {
+                    self.lock_routing_table().insert(NodeInfo {
+                        id: sender_id,
+                        address,
+                    });
+                    let reply = Message {
+                        transaction_id: message.transaction_id,
+                        body: self.answer(query, address),
+                    };
+                    self.send(address, &reply).await
+                }
+                Body::Response(response) => {
+                    self.resolve_query(address, message.transaction_id, Ok(response));
+                    Ok(())
+                }
+                Body::Error {
+                    code,
+                    message: error,
+                } => {
+                    let error = DhtError::ErrorReply {
+                        address,
+                        code,
+                        message: error,
+                    };
+                    self.resolve_query(address, message.transaction_id, Err(error));
+                    Ok(())
+                }
+            };
+            if let Err(error) = result {
+                self.node
+                    .events
+                    .debug(format!("Could not answer DHT node {address}: {error}"));
+            }
+        }
+    }
+}
+
+// Queries
+impl Dht {
+    async fn ping(&self, address: SocketAddr) -> Result<NodeId, DhtError> {
+        Ok(self.query(address, Query::Ping).await?.id)
+    }
+
+    // Nodes answering join the routing table, those not answering get closer
+    // to being dropped from it
+    async fn query(&self, address: SocketAddr, query: Query) -> Result<Response, DhtError> {
+        let transaction_id = self
+            .node
+            .next_transaction_id
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let key = (address, transaction_id.clone());
+        let (sender, receiver) = oneshot::channel();
+        self.lock_pending_queries().insert(key.clone(), sender);
+
+        let message = Message {
+            transaction_id,
+            body: Body::Query {
+                sender_id: self.node.id,
+                query,
+            },
+        };
+        let result = match self.send(address, &message).await {
+            Ok(()) => match time::timeout(QUERY_TIMEOUT, receiver).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) => Err(DhtError::Stopped),
+                Err(_) => Err(DhtError::QueryTimeout { address }),
+            },
+            Err(error) => Err(error),
+        };
+        self.lock_pending_queries().remove(&key);
+
+        match &result {
+            Ok(response) => {
+                self.lock_routing_table().insert(NodeInfo {
+                    id: response.id,
+                    address,
+                });
+            }
+            // A node replying with an error is still there
+            Err(DhtError::ErrorReply { .. }) => {}
+            Err(_) => self.lock_routing_table().record_failure(address),
+        }
+        result
+    }
+
+    // Walks towards `target`, querying the closest nodes not queried yet until
+    // the closest ones all answered or failed
+    async fn lookup(&self, target: NodeId, query: impl Fn() -> Query) -> Lookup {
+        // Candidates by their distance to the target
+        let mut candidates: BTreeMap<NodeId, LookupCandidate> = self
+            .lock_routing_table()
+            .closest(&target, BUCKET_SIZE)
+            .into_iter()
+            .map(|node| (target.distance(&node.id), LookupCandidate::new(node)))
+            .collect();
+        let mut lookup = Lookup::default();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            let mut queries = JoinSet::new();
+            candidates
+                .values_mut()
+                .filter(|candidate| !candidate.failed)
+                .take(BUCKET_SIZE)
+                .filter(|candidate| !candidate.queried)
+                .take(LOOKUP_PARALLELISM)
+                .for_each(|candidate| {
+                    candidate.queried = true;
+                    let (dht, node, query) = (self.clone(), candidate.node, query());
+                    queries.spawn(async move { (node, dht.query(node.address, query).await) });
+                });
+            if queries.is_empty() {
+                break;
+            }
+
+            while let Some(joined) = queries.join_next().await {
+                let Ok((node, result)) = joined else {
+                    continue;
+                };
+                let Some(candidate) = candidates.get_mut(&target.distance(&node.id)) else {
+                    continue;
+                };
+                let response = match result {
+                    Ok(response) => response,
+                    Err(_) => {
+                        candidate.failed = true;
+                        continue;
+                    }
+                };
+
+                candidate.token = response.token;
+                for peer in response.peers {
+                    if !lookup.peers.contains(&peer) {
+                        lookup.peers.push(peer);
+                    }
+                }
+                for found in response.nodes {
+                    if found.id != self.node.id {
+                        candidates
+                            .entry(target.distance(&found.id))
+                            .or_insert_with(|| LookupCandidate::new(found));
+                    }
+                }
+            }
+        }
+
+        lookup.closest_nodes = candidates
+            .into_values()
+            .filter(|candidate| candidate.queried && !candidate.failed)
+            .take(BUCKET_SIZE)
+            .map(|candidate| (candidate.node, candidate.token))
+            .collect();
+        lookup
+    }
+
+    async fn send(&self, address: SocketAddr, message: &Message) -> Result<(), DhtError> {
+        self.node
+            .socket
+            .send_to(&message.to_bytes()?, address)
+            .await?;
+        Ok(())
+    }
+
+    fn resolve_query(
+        &self,
+        address: SocketAddr,
+        transaction_id: Vec<u8>,
+        result: Result<Response, DhtError>,
+    ) {
+        let sender = self
+            .lock_pending_queries()
+            .remove(&(address, transaction_id));
+        match sender {
+            // The query may have timed out in the meantime
+            Some(sender) => {
+                let _ = sender.send(result);
+            }
+            None => self
+                .node
+                .events
+                .trace(format!("Dropping an unexpected DHT reply from {address}")),
+        }
+    }
+}
+
+// Answers
+impl Dht {
+    fn answer(&self, query: Query, address: SocketAddr) -> Body {
+        let mut response = Response::new(self.node.id);
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.lock_routing_table().closest(&target, BUCKET_SIZE);
+            }
+            // Nodes closer to the info hash are given when we know no peer
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.lock_token_secrets().token(address.ip()));
+                response.peers = self.stored_peers(&info_hash);
+                if response.peers.is_empty() {
+                    response.nodes = self.lock_routing_table().closest(&info_hash, BUCKET_SIZE);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.lock_token_secrets().is_valid(&token, address.ip()) {
+                    return Body::Error {
+                        code: PROTOCOL_ERROR,
+                        message: "Bad token".into(),
+                    };
+                }
+                let port = if implied_port { address.port() } else { port };
+                self.store_peer(info_hash, SocketAddr::new(address.ip(), port));
+            }
+            Query::Unknown { method } => {
+                return Body::Error {
+                    code: METHOD_UNKNOWN,
+                    message: format!("Method Unknown: {method}"),
+                };
+            }
+        }
+        Body::Response(response)
+    }
+
+    fn stored_peers(&self, info_hash: &NodeId) -> Vec<SocketAddr> {
+        let mut peers = self.lock_peers();
+        let Some(info_hash_peers) = peers.get_mut(info_hash) else {
+            return vec![];
+        };
+        info_hash_peers.retain(|(_, announced_at)| announced_at.elapsed() < PEER_TIME_TO_LIVE);
+        info_hash_peers
+            .iter()
+            .rev()
+            .take(MAX_PEERS_PER_REPLY)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    fn store_peer(&self, info_hash: NodeId, peer: SocketAddr) {
+        let mut peers = self.lock_peers();
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_STORED_INFO_HASHES {
+            return;
+        }
+        let info_hash_peers = peers.entry(info_hash).or_default();
+        info_hash_peers.retain(|(stored_peer, announced_at)| {
+            *stored_peer != peer && announced_at.elapsed() < PEER_TIME_TO_LIVE
+        });
+        if info_hash_peers.len() >= MAX_PEERS_PER_INFO_HASH {
+            info_hash_peers.remove(0);
+        }
+        info_hash_peers.push((peer, Instant::now()));
+    }
+
+    fn lock_routing_table(&self) -> MutexGuard<'_, RoutingTable> {
+        self.node
+            .routing_table
+            .lock()
+            .expect("DHT routing table lock poisoned")
+    }
+
+    fn lock_pending_queries(&self) -> MutexGuard<'_, PendingQueries> {
+        self.node
+            .pending_queries
+            .lock()
+            .expect("DHT pending queries lock poisoned")
+    }
+
+    fn lock_peers(&self) -> MutexGuard<'_, HashMap<NodeId, Vec<(SocketAddr, Instant)>>> {
+        self.node.peers.lock().expect("DHT peers lock poisoned")
+    }
+
+    fn lock_token_secrets(&self) -> MutexGuard<'_, TokenSecrets> {
+        self.node
+            .token_secrets
+            .lock()
+            .expect("DHT token secrets lock poisoned")
+    }
+}
+
+impl TokenSecrets {
+    fn new() -> Self {
+        Self {
+            current: rand::thread_rng().gen(),
+            previous: rand::thread_rng().gen(),
+            rotated_at: Instant::now(),
+        }
+    }
+
+    fn token(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate_if_due();
+        Self::token_from(&self.current, ip)
+    }
+
+    fn is_valid(&mut self, token: &[u8], ip: IpAddr) -> bool {
+        self.rotate_if_due();
+        [self.current, self.previous]
+            .iter()
+            .any(|secret| Self::token_from(secret, ip) == token)
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated_at.elapsed() >= TOKEN_ROTATION_INTERVAL {
+            self.rotate();
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.previous = self.current;
+        self.current = rand::thread_rng().gen();
+        self.rotated_at = Instant::now();
+    }
+
+    fn token_from(secret: &[u8], ip: IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.finalize().to_vec()
+    }
+}
+
+impl LookupCandidate {
+    fn new(node: NodeInfo) -> Self {
+        Self {
+            node,
+            queried: false,
+            failed: false,
+            token: None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    async fn node() -> Dht {
+        let dht = Dht::bind(0, Events::default()).await.unwrap();
+        tokio::spawn(dht.clone().receive_messages());
+        dht
+    }
+
+    // A node whose only known node never answers, along with the socket of the latter
+    pub(in crate::torrent_client) async fn node_knowing_a_silent_node() -> (Dht, UdpSocket) {
+        let dht = node().await;
+        let silent_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        dht.lock_routing_table().insert(NodeInfo {
+            id: NodeId::generate(),
+            address: silent_socket.local_addr().unwrap(),
+        });
+        (dht, silent_socket)
+    }
+
+    fn address(dht: &Dht) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, dht.port()))
+    }
+
+    #[tokio::test]
+    async fn nodes_answering_a_ping_join_the_routing_tables() {
+        let (a, b) = (node().await, node().await);
+        assert_eq!(a.ping(address(&b)).await.unwrap(), b.node.id);
+        assert_eq!(a.nodes_count(), 1);
+        // The queried node learned about us too
+        assert_eq!(b.nodes_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_found_through_closer_nodes() {
+        // a only knows b, b only knows c, c gets the announce
+        let (a, b, c) = (node().await, node().await, node().await);
+        a.ping(address(&b)).await.unwrap();
+        b.ping(address(&c)).await.unwrap();
+
+        let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 51413));
+        c.store_peer(NodeId(INFO_HASH), peer);
+
+        let peers = a.find_peers(&INFO_HASH, Some(6881)).await.unwrap();
+        assert_eq!(peers, vec![peer]);
+        // We were announced to the nodes that answered with a token
+        for dht in [&b, &c] {
+            assert!(dht
+                .stored_peers(&NodeId(INFO_HASH))
+                .contains(&SocketAddr::from((Ipv4Addr::LOCALHOST, 6881))));
+        }
+    }
+
+    #[tokio::test]
+    async fn announces_need_a_token_from_a_get_peers_reply() {
+        let (a, b) = (node().await, node().await);
+        let announce = Query::AnnouncePeer {
+            info_hash: NodeId(INFO_HASH),
+            port: 6881,
+            implied_port: false,
+            token: b"made up".to_vec(),
+        };
+        match a.query(address(&b), announce).await {
+            Err(DhtError::ErrorReply { code, .. }) => assert_eq!(code, PROTOCOL_ERROR),
+            result => panic!("expected a bad token error, got {result:?}"),
+        }
+
+        let token = a
+            .query(
+                address(&b),
+                Query::GetPeers {
+                    info_hash: NodeId(INFO_HASH),
+                },
+            )
+            .await
+            .unwrap()
+            .token
+            .unwrap();
+        let announce = Query::AnnouncePeer {
+            info_hash: NodeId(INFO_HASH),
+            port: 0,
+            implied_port: true,
+            token,
+        };
+        a.query(address(&b), announce).await.unwrap();
+        // The implied port is the one the query came from
+        assert_eq!(b.stored_peers(&NodeId(INFO_HASH)), vec![address(&a)]);
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_get_an_error() {
+        let (a, b) = (node().await, node().await);
+        let query = Query::Unknown {
+            method: "vote".into(),
+        };
+        match a.query(address(&b), query).await {
+            Err(DhtError::ErrorReply { code, .. }) => assert_eq!(code, METHOD_UNKNOWN),
+            result => panic!("expected a method unknown error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn tokens_outlive_one_secret_rotation() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut secrets = TokenSecrets::new();
+        let token = secrets.token(ip);
+        assert!(secrets.is_valid(&token, ip));
+        assert!(!secrets.is_valid(&token, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))));
+
+        secrets.rotate();
+        assert!(secrets.is_valid(&token, ip));
+        secrets.rotate();
+        assert!(!secrets.is_valid(&token, ip));
+    }
+}
@@ -0,0 +1,519 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use super::routing_table::{NodeId, NodeInfo, NODE_ID_LENGTH};
+use crate::torrent_client::error::DhtError;
+
+pub(crate) const PROTOCOL_ERROR: i64 = 203;
+pub(crate) const METHOD_UNKNOWN: i64 = 204;
+
+const QUERY: &[u8] = b"q";
+const RESPONSE: &[u8] = b"r";
+const ERROR: &[u8] = b"e";
+const COMPACT_PEER_LENGTH: usize = 6;
+const COMPACT_NODE_LENGTH: usize = NODE_ID_LENGTH + COMPACT_PEER_LENGTH;
+
+// A KRPC message as it is bencoded. Keys are declared in the sorted order
+// bencode dictionaries are written in.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct KrpcMessage {
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    arguments: Option<KrpcArguments>,
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    // The error code and message, in a list
+    error: Option<Vec<Value>>,
+    #[serde(rename = "q", default, skip_serializing_if = "Option::is_none")]
+    method: Option<ByteBuf>,
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    response: Option<KrpcResponse>,
+    #[serde(rename = "t")]
+    transaction_id: ByteBuf,
+    #[serde(rename = "y")]
+    kind: ByteBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct KrpcArguments {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct KrpcResponse {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+}
+
+// A message between DHT nodes, matched to its query by the transaction id
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Message {
+    pub transaction_id: Vec<u8>,
+    pub body: Body,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Body {
+    Query { sender_id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: NodeId,
+    },
+    // With `implied_port`, the peer listens on the port the query came from
+    AnnouncePeer {
+        info_hash: NodeId,
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    // Answered with a method unknown error
+    Unknown {
+        method: String,
+    },
+}
+
+// Every response holds the id of the node, the other keys depend on the query
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub peers: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            ..Self::default()
+        }
+    }
+}
+
+impl Message {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DhtError> {
+        let message: KrpcMessage = serde_bencode::from_bytes(bytes)?;
+        let body = match message.kind.as_slice() {
+            QUERY => {
+                let method = message.method.ok_or_else(|| missing("q"))?;
+                let arguments = message.arguments.ok_or_else(|| missing("a"))?;
+                Body::Query {
+                    sender_id: NodeId::from_bytes(&arguments.id)?,
+                    query: Query::from_arguments(&method, arguments)?,
+                }
+            }
+            RESPONSE => Body::Response(Response::from_krpc(
+                message.response.ok_or_else(|| missing("r"))?,
+            )?),
+            ERROR => match message.error.ok_or_else(|| missing("e"))?.as_slice() {
+                [Value::Int(code), Value::Bytes(message)] => Body::Error {
+                    code: *code,
+                    message: String::from_utf8_lossy(message).into(),
+                },
+                _ => return Err(not_valid("error is not a code and a message")),
+            },
+            kind => {
+                return Err(not_valid(format!(
+                    "unknown message type '{}'",
+                    String::from_utf8_lossy(kind)
+                )))
+            }
+        };
+
+        Ok(Self {
+            transaction_id: message.transaction_id.into_vec(),
+            body,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DhtError> {
+        let mut message = KrpcMessage {
+            transaction_id: ByteBuf::from(self.transaction_id.clone()),
+            ..KrpcMessage::default()
+        };
+        match &self.body {
+            Body::Query { sender_id, query } => {
+                message.kind = ByteBuf::from(QUERY);
+                message.method = Some(ByteBuf::from(query.method()));
+                message.arguments = Some(query.to_arguments(sender_id));
+            }
+            Body::Response(response) => {
+                message.kind = ByteBuf::from(RESPONSE);
+                message.response = Some(response.to_krpc());
+            }
+            Body::Error {
+                code,
+                message: error,
+            } => {
+                message.kind = ByteBuf::from(ERROR);
+                message.error = Some(vec![Value::Int(*code), Value::Bytes(error.clone().into())]);
+            }
+        }
+        Ok(serde_bencode::to_bytes(&message)?)
+    }
+}
+
+impl Query {
+    fn method(&self) -> &[u8] {
+        match self {
+            Self::Ping => b"ping",
+            Self::FindNode { .. } => b"find_node",
+            Self::GetPeers { .. } => b"get_peers",
+            Self::AnnouncePeer { .. } => b"announce_peer",
+            Self::Unknown { method } => method.as_bytes(),
+        }
+    }
+
+    fn from_arguments(method: &[u8], arguments: KrpcArguments) -> Result<Self, DhtError> {
+        let query = match method {
+            b"ping" => Self::Ping,
+            b"find_node" => Self::FindNode {
+                target: NodeId::from_bytes(&arguments.target.ok_or_else(|| missing("target"))?)?,
+            },
+            b"get_peers" => Self::GetPeers {
+                info_hash: NodeId::from_bytes(
+                    &arguments.info_hash.ok_or_else(|| missing("info_hash"))?,
+                )?,
+            },
+            b"announce_peer" => Self::AnnouncePeer {
+                info_hash: NodeId::from_bytes(
+                    &arguments.info_hash.ok_or_else(|| missing("info_hash"))?,
+                )?,
+                port: arguments.port.ok_or_else(|| missing("port"))?,
+                implied_port: arguments.implied_port.is_some_and(|implied| implied != 0),
+                token: arguments.token.ok_or_else(|| missing("token"))?.into_vec(),
+            },
+            method => Self::Unknown {
+                method: String::from_utf8_lossy(method).into(),
+            },
+        };
+        Ok(query)
+    }
+
+    fn to_arguments(&self, sender_id: &NodeId) -> KrpcArguments {
+        let mut arguments = KrpcArguments {
+            id: ByteBuf::from(sender_id.0),
+            ..KrpcArguments::default()
+        };
+        match self {
+            Self::Ping | Self::Unknown { .. } => {}
+            Self::FindNode { target } => arguments.target = Some(ByteBuf::from(target.0)),
+            Self::GetPeers { info_hash } => arguments.info_hash = Some(ByteBuf::from(info_hash.0)),
+            Self::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                arguments.info_hash = Some(ByteBuf::from(info_hash.0));
+                arguments.port = Some(*port);
+                arguments.implied_port = Some(*implied_port as u8);
+                arguments.token = Some(ByteBuf::from(token.clone()));
+            }
+        }
+        arguments
+    }
+}
+
+impl Response {
+    fn from_krpc(response: KrpcResponse) -> Result<Self, DhtError> {
+        Ok(Self {
+            id: NodeId::from_bytes(&response.id)?,
+            nodes: decode_compact_nodes(response.nodes.as_deref().map_or(&[], Vec::as_slice))?,
+            // Peers that are not IPv4 are skipped, other nodes may know better ones
+            peers: response
+                .values
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|peer| decode_compact_peer(peer))
+                .collect(),
+            token: response.token.map(ByteBuf::into_vec),
+        })
+    }
+
+    fn to_krpc(&self) -> KrpcResponse {
+        KrpcResponse {
+            id: ByteBuf::from(self.id.0),
+            nodes: (!self.nodes.is_empty())
+                .then(|| ByteBuf::from(encode_compact_nodes(&self.nodes))),
+            token: self.token.clone().map(ByteBuf::from),
+            values: (!self.peers.is_empty()).then(|| {
+                self.peers
+                    .iter()
+                    .filter_map(|peer| encode_compact_peer(peer).map(ByteBuf::from))
+                    .collect()
+            }),
+        }
+    }
+}
+
+// 4 bytes of IPv4 address and 2 bytes of port, the DHT does not carry IPv6 peers
+pub(crate) fn decode_compact_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    let bytes: [u8; COMPACT_PEER_LENGTH] = bytes.try_into().ok()?;
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    Some(SocketAddr::new(
+        IpAddr::V4(ip),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+pub(crate) fn encode_compact_peer(peer: &SocketAddr) -> Option<Vec<u8>> {
+    let IpAddr::V4(ip) = peer.ip() else {
+        return None;
+    };
+    let mut bytes = ip.octets().to_vec();
+    bytes.extend_from_slice(&peer.port().to_be_bytes());
+    Some(bytes)
+}
+
+// Node ids followed by their compact address, 26 bytes per node
+pub(crate) fn decode_compact_nodes(bytes: &[u8]) -> Result<Vec<NodeInfo>, DhtError> {
+    if !bytes.len().is_multiple_of(COMPACT_NODE_LENGTH) {
+        return Err(not_valid(format!(
+            "compact nodes of {} bytes are not a multiple of {COMPACT_NODE_LENGTH}",
+            bytes.len()
+        )));
+    }
+    bytes
+        .chunks_exact(COMPACT_NODE_LENGTH)
+        .map(|chunk| {
+            let (id, address) = chunk.split_at(NODE_ID_LENGTH);
+            Ok(NodeInfo {
+                id: NodeId::from_bytes(id)?,
+                address: decode_compact_peer(address).ok_or_else(|| not_valid("node address"))?,
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    nodes
+        .iter()
+        .filter_map(|node| {
+            let address = encode_compact_peer(&node.address)?;
+            Some([node.id.0.as_slice(), &address].concat())
+        })
+        .flatten()
+        .collect()
+}
+
+fn missing(key: &str) -> DhtError {
+    not_valid(format!("'{key}' missing"))
+}
+
+fn not_valid(reason: impl Into<String>) -> DhtError {
+    DhtError::MessageNotValid {
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> NodeId {
+        NodeId([byte; NODE_ID_LENGTH])
+    }
+
+    // The examples of BEP 5
+    #[test]
+    fn queries_are_encoded_like_the_specification() {
+        let ping = Message {
+            transaction_id: b"aa".to_vec(),
+            body: Body::Query {
+                sender_id: NodeId(*b"abcdefghij0123456789"),
+                query: Query::Ping,
+            },
+        };
+        assert_eq!(
+            ping.to_bytes().unwrap(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+
+        let find_node = Message {
+            transaction_id: b"aa".to_vec(),
+            body: Body::Query {
+                sender_id: NodeId(*b"abcdefghij0123456789"),
+                query: Query::FindNode {
+                    target: NodeId(*b"mnopqrstuvwxyz123456"),
+                },
+            },
+        };
+        assert_eq!(
+            find_node.to_bytes().unwrap(),
+            b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe"
+        );
+
+        let announce_peer = Message {
+            transaction_id: b"aa".to_vec(),
+            body: Body::Query {
+                sender_id: NodeId(*b"abcdefghij0123456789"),
+                query: Query::AnnouncePeer {
+                    info_hash: NodeId(*b"mnopqrstuvwxyz123456"),
+                    port: 6881,
+                    implied_port: true,
+                    token: b"aoeusnth".to_vec(),
+                },
+            },
+        };
+        assert_eq!(
+            announce_peer.to_bytes().unwrap(),
+            b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe"
+        );
+    }
+
+    #[test]
+    fn messages_are_decoded_like_the_specification() {
+        let get_peers = Message::from_bytes(
+            b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe",
+        )
+        .unwrap();
+        assert_eq!(
+            get_peers,
+            Message {
+                transaction_id: b"aa".to_vec(),
+                body: Body::Query {
+                    sender_id: NodeId(*b"abcdefghij0123456789"),
+                    query: Query::GetPeers {
+                        info_hash: NodeId(*b"mnopqrstuvwxyz123456"),
+                    },
+                },
+            }
+        );
+
+        let peers = Message::from_bytes(
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re",
+        )
+        .unwrap();
+        let Body::Response(response) = peers.body else {
+            panic!("expected a response, got {:?}", peers.body);
+        };
+        assert_eq!(response.token, Some(b"aoeusnth".to_vec()));
+        assert_eq!(
+            response.peers,
+            vec![
+                "97.120.106.101:11893".parse().unwrap(),
+                "105.100.104.116:28269".parse().unwrap()
+            ]
+        );
+
+        let error =
+            Message::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            error.body,
+            Body::Error {
+                code: 201,
+                message: "A Generic Error Ocurred".into()
+            }
+        );
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let message = Message {
+            transaction_id: vec![0, 1],
+            body: Body::Response(Response {
+                id: id(1),
+                nodes: vec![
+                    NodeInfo {
+                        id: id(2),
+                        address: "10.0.0.2:6881".parse().unwrap(),
+                    },
+                    NodeInfo {
+                        id: id(3),
+                        address: "10.0.0.3:51413".parse().unwrap(),
+                    },
+                ],
+                peers: vec!["192.168.1.4:6882".parse().unwrap()],
+                token: Some(vec![9; 4]),
+            }),
+        };
+        assert_eq!(
+            Message::from_bytes(&message.to_bytes().unwrap()).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn unknown_methods_are_kept_to_be_answered() {
+        let message =
+            Message::from_bytes(b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe")
+                .unwrap();
+        assert!(matches!(
+            message.body,
+            Body::Query {
+                query: Query::Unknown { ref method },
+                ..
+            } if method == "vote"
+        ));
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        // find_node without a target
+        assert!(matches!(
+            Message::from_bytes(b"d1:ad2:id20:abcdefghij0123456789e1:q9:find_node1:t2:aa1:y1:qe"),
+            Err(DhtError::MessageNotValid { .. })
+        ));
+        // A node id that is not 20 bytes
+        assert!(matches!(
+            Message::from_bytes(b"d1:rd2:id3:abce1:t2:aa1:y1:re"),
+            Err(DhtError::MessageNotValid { .. })
+        ));
+        // Compact nodes cut short
+        assert!(matches!(
+            Message::from_bytes(b"d1:rd2:id20:abcdefghij01234567895:nodes3:abce1:t2:aa1:y1:re"),
+            Err(DhtError::MessageNotValid { .. })
+        ));
+        assert!(matches!(
+            Message::from_bytes(b"d1:t2:aa1:y1:xe"),
+            Err(DhtError::MessageNotValid { .. })
+        ));
+        assert!(matches!(
+            Message::from_bytes(b"not bencode"),
+            Err(DhtError::Bencode(_))
+        ));
+    }
+
+    #[test]
+    fn ipv6_nodes_and_peers_are_left_out() {
+        let nodes = [
+            NodeInfo {
+                id: id(2),
+                address: "[::1]:6881".parse().unwrap(),
+            },
+            NodeInfo {
+                id: id(3),
+                address: "10.0.0.3:6881".parse().unwrap(),
+            },
+        ];
+        let decoded = decode_compact_nodes(&encode_compact_nodes(&nodes)).unwrap();
+        assert_eq!(decoded, nodes[1..]);
+        assert_eq!(encode_compact_peer(&"[::1]:6881".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,216 @@
+use std::net::SocketAddr;
+
+use rand::Rng;
+
+use crate::torrent_client::error::DhtError;
+
+pub(crate) const NODE_ID_LENGTH: usize = 20;
+// Nodes kept per bucket (BEP 5)
+pub(crate) const BUCKET_SIZE: usize = 8;
+// Nodes failing this many queries in a row are dropped
+const MAX_NODE_FAILURES: u32 = 3;
+
+// Identifies a DHT node. Info hashes live in the same space, the nodes whose
+// ids are the closest to an info hash keep its peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub(crate) struct NodeId(pub [u8; NODE_ID_LENGTH]);
+
+impl NodeId {
+    pub fn generate() -> Self {
+        Self(rand::thread_rng().gen())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DhtError> {
+        let bytes = bytes.try_into().map_err(|_| DhtError::MessageNotValid {
+            reason: format!("id of {} bytes", bytes.len()),
+        })?;
+        Ok(Self(bytes))
+    }
+
+    // XOR metric, compared as big-endian numbers: the smaller, the closer
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0; NODE_ID_LENGTH];
+        for (byte, (a, b)) in distance.iter_mut().zip(self.0.iter().zip(other.0)) {
+            *byte = a ^ b;
+        }
+        Self(distance)
+    }
+
+    fn leading_zeros(&self) -> usize {
+        self.0
+            .iter()
+            .position(|byte| *byte != 0)
+            .map_or(NODE_ID_LENGTH * 8, |index| {
+                index * 8 + self.0[index].leading_zeros() as usize
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct NodeInfo {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+#[derive(Debug)]
+struct Entry {
+    node: NodeInfo,
+    // Queries it failed to answer since its last answer
+    failures: u32,
+}
+
+// The nodes we know, in buckets by the length of the prefix their id shares
+// with ours. Buckets near our own id hold a smaller part of the space, so the
+// table knows the neighbourhood of our id best.
+#[derive(Debug)]
+pub(crate) struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: (0..NODE_ID_LENGTH * 8).map(|_| vec![]).collect(),
+        }
+    }
+
+    // Returns false if the node was left out, its bucket being full of nodes
+    // that answer
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        if node.id == self.own_id {
+            return false;
+        }
+        let bucket_index = self.own_id.distance(&node.id).leading_zeros();
+        let bucket = &mut self.buckets[bucket_index];
+
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.address = node.address;
+            entry.failures = 0;
+            return true;
+        }
+        // Nodes that stopped answering make room, those that answer are kept
+        if bucket.len() >= BUCKET_SIZE {
+            let Some(position) = bucket.iter().position(|entry| entry.failures > 0) else {
+                return false;
+            };
+            bucket.remove(position);
+        }
+        bucket.push(Entry { node, failures: 0 });
+        true
+    }
+
+    pub fn record_failure(&mut self, address: SocketAddr) {
+        for bucket in &mut self.buckets {
+            bucket
+                .iter_mut()
+                .filter(|entry| entry.node.address == address)
+                .for_each(|entry| entry.failures += 1);
+            bucket.retain(|entry| entry.failures < MAX_NODE_FAILURES);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.node)
+            .collect();
+        nodes.sort_by_key(|node| target.distance(&node.id));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: NodeId, port: u16) -> NodeInfo {
+        NodeInfo {
+            id,
+            address: SocketAddr::from(([10, 0, 0, 1], port)),
+        }
+    }
+
+    // An id sharing exactly `prefix_length` bits with the all zeros id
+    fn id_with_prefix(prefix_length: usize, suffix: u8) -> NodeId {
+        let mut id = [0; NODE_ID_LENGTH];
+        id[prefix_length / 8] = 0x80 >> (prefix_length % 8);
+        id[NODE_ID_LENGTH - 1] |= suffix;
+        NodeId(id)
+    }
+
+    #[test]
+    fn distance_is_the_xor_of_the_ids() {
+        let a = NodeId([0b1010; NODE_ID_LENGTH]);
+        let b = NodeId([0b0110; NODE_ID_LENGTH]);
+        assert_eq!(a.distance(&b), NodeId([0b1100; NODE_ID_LENGTH]));
+        assert_eq!(a.distance(&a), NodeId::default());
+        assert_eq!(id_with_prefix(0, 0).leading_zeros(), 0);
+        assert_eq!(id_with_prefix(13, 0).leading_zeros(), 13);
+        assert_eq!(NodeId::default().leading_zeros(), 160);
+    }
+
+    #[test]
+    fn closest_nodes_come_first() {
+        let mut table = RoutingTable::new(NodeId::default());
+        for (port, prefix_length) in [(1, 3), (2, 40), (3, 12), (4, 159)] {
+            table.insert(node(id_with_prefix(prefix_length, 0), port));
+        }
+        let ports: Vec<u16> = table
+            .closest(&NodeId::default(), 3)
+            .iter()
+            .map(|node| node.address.port())
+            .collect();
+        assert_eq!(ports, vec![4, 2, 3]);
+    }
+
+    #[test]
+    fn full_buckets_only_make_room_by_dropping_failing_nodes() {
+        let mut table = RoutingTable::new(NodeId::default());
+        for suffix in 0..BUCKET_SIZE as u8 {
+            assert!(table.insert(node(id_with_prefix(0, suffix), suffix as u16)));
+        }
+        let newcomer = node(id_with_prefix(0, 0x7f), 100);
+        assert!(!table.insert(newcomer));
+
+        table.record_failure(SocketAddr::from(([10, 0, 0, 1], 3)));
+        assert!(table.insert(newcomer));
+        assert_eq!(table.len(), BUCKET_SIZE);
+        assert!(table
+            .closest(&newcomer.id, BUCKET_SIZE)
+            .iter()
+            .all(|node| node.address.port() != 3));
+    }
+
+    #[test]
+    fn nodes_failing_repeatedly_are_dropped() {
+        let mut table = RoutingTable::new(NodeId::default());
+        let failing = node(id_with_prefix(5, 0), 1);
+        table.insert(failing);
+        table.record_failure(failing.address);
+        table.record_failure(failing.address);
+        // An answer clears the failures
+        table.insert(failing);
+        table.record_failure(failing.address);
+        table.record_failure(failing.address);
+        assert_eq!(table.len(), 1);
+        table.record_failure(failing.address);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn our_own_id_is_not_kept() {
+        let own_id = NodeId::generate();
+        let mut table = RoutingTable::new(own_id);
+        assert!(!table.insert(node(own_id, 1)));
+        assert_eq!(table.len(), 0);
+    }
+}
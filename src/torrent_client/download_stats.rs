@@ -0,0 +1,80 @@
+use std::{
+    fmt::{Display, Formatter, Result},
+    net::SocketAddr,
+};
+
+use super::peer_client::PeerClient;
+
+/// What was downloaded from a peer
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    /// Address of the peer
+    pub address: SocketAddr,
+    /// Client the peer runs, when its peer id or extension handshake tells
+    pub client: Option<PeerClient>,
+    /// Bytes of verified pieces the peer sent
+    pub bytes_downloaded: usize,
+}
+
+/// What a download went through so far
+#[derive(Debug, Default, Clone)]
+pub struct DownloadStats {
+    /// Pieces that passed their hash check
+    pub pieces_downloaded: usize,
+    /// Pieces that failed their hash check
+    pub hash_failures: usize,
+    /// Peers banned after sending bad blocks
+    pub banned_peers: usize,
+    /// Peers given up on after they stopped answering
+    pub unresponsive_peers: usize,
+    /// Block requests moved to another peer
+    pub reissued_requests: usize,
+    /// Every peer something was downloaded from
+    pub peers: Vec<PeerStats>,
+}
+
+impl DownloadStats {
+    pub(crate) fn peer_mut(&mut self, address: SocketAddr) -> &mut PeerStats {
+        let position = match self.peers.iter().position(|peer| peer.address == address) {
+            Some(position) => position,
+            None => {
+                self.peers.push(PeerStats {
+                    address,
+                    client: None,
+                    bytes_downloaded: 0,
+                });
+                self.peers.len() - 1
+            }
+        };
+        &mut self.peers[position]
+    }
+}
+
+impl Display for DownloadStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "Pieces downloaded: {}, hash failures: {}, banned peers: {}, unresponsive peers: {}, reissued requests: {}",
+            self.pieces_downloaded,
+            self.hash_failures,
+            self.banned_peers,
+            self.unresponsive_peers,
+            self.reissued_requests
+        )
+    }
+}
+
+impl Display for PeerStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let client = self
+            .client
+            .as_ref()
+            .map(|client| client.to_string())
+            .unwrap_or_else(|| "unknown client".into());
+        write!(
+            f,
+            "Peer {} ({}): {} bytes downloaded",
+            self.address, client, self.bytes_downloaded
+        )
+    }
+}
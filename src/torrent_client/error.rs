@@ -1,34 +1,343 @@
-use std::fmt;
+//! Error types, one per subsystem
 
-#[derive(Debug)]
+use std::{array::TryFromSliceError, io, net::SocketAddr, path::PathBuf};
+
+use thiserror::Error;
+
+use crate::bencode::{parser::ParseError, BencodeError};
+
+/// Everything the client can fail with. Peer and web seed errors only concern one source
+/// of data, the download goes on with another. The others are fatal to the torrent.
+#[derive(Debug, Error)]
 pub enum Error {
+    /// Bencode that does not parse or does not fit its type
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    /// A torrent file that cannot be read, used or created
+    #[error(transparent)]
+    Metainfo(#[from] MetainfoError),
+    /// A failed tracker announce
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    /// A peer misbehaving, or the connection to it failing
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+    /// A web seed failing to serve a piece
+    #[error(transparent)]
+    WebSeed(#[from] WebSeedError),
+    /// Downloaded files that cannot be written
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    /// Every peer was tried, or none was found
+    #[error("No peer available")]
     NoPeerAvailable,
+    /// The operation needs a peer connection
+    #[error("Tcp stream not available")]
     TcpStreamNotAvailable,
-    PeerClosedConnection,
-    MessageBodyNotReadCorrect { expected: usize, actual: usize },
-    PeerMessageIdNotRecognized { id: u8 },
+    /// A piece does not match its hash
+    #[error("Piece hash not valid")]
     PieceHashNotValid,
+    /// A piece kept failing its hash check
+    #[error("Piece {index} could not be downloaded after {attempts} attempts")]
+    PieceDownloadFailed {
+        /// Index of the piece
+        index: u32,
+        /// Attempts made
+        attempts: u32,
+    },
+    /// A piece of a hybrid torrent matching its v1 hash but not its v2 one
+    #[error("Piece {index} matches its v1 hash but not its v2 one")]
+    HybridHashesInconsistent {
+        /// Index of the piece
+        index: u32,
+    },
+    /// A peer id prefix that leaves no room for the random part
+    #[error("Peer id prefix is {length} bytes long, at most 20 are allowed")]
+    PeerIdPrefixTooLong {
+        /// Length of the prefix
+        length: usize,
+    },
+    /// The session could not listen for incoming peers
+    #[error("Could not listen on port {port}")]
+    ListenFailed {
+        /// Port listened on
+        port: u16,
+        /// Why listening failed
+        #[source]
+        source: io::Error,
+    },
+    /// The session is not running anymore
+    #[error("Session closed")]
+    SessionClosed,
+    /// No torrent of the session has this info hash
+    #[error("Torrent {info_hash} not found")]
+    TorrentNotFound {
+        /// Hex info hash
+        info_hash: String,
+    },
+    /// The session already has a torrent with this info hash
+    #[error("Torrent {info_hash} was already added")]
+    TorrentAlreadyAdded {
+        /// Hex info hash
+        info_hash: String,
+    },
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.to_message())
+impl Error {
+    /// Worth trying again with another peer or web seed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Peer(_) | Self::WebSeed(_))
     }
 }
 
-impl Error {
-    fn to_message(&self) -> String {
-        match self {
-            Self::NoPeerAvailable => "No peer available".into(),
-            Self::TcpStreamNotAvailable => "Tcp stream not available".into(),
-            Self::PeerClosedConnection => "Peer has closed connection".into(),
-            Self::MessageBodyNotReadCorrect { expected, actual } => format!(
-                "Message body was not read correct. Expected {expected} bytes, got {actual} bytes"
-            ),
-            Self::PeerMessageIdNotRecognized { id } => {
-                format!("Peer message id '{}' not recognized", id)
+/// A torrent file that cannot be read, used or created
+#[derive(Debug, Error)]
+pub enum MetainfoError {
+    /// The torrent file does not follow the specification
+    #[error("Metainfo not valid: {reason}")]
+    NotValid {
+        /// What is wrong
+        reason: String,
+    },
+    /// A v2 file spanning several pieces has no piece layer
+    #[error("Piece layer of '{file}' missing")]
+    PieceLayerMissing {
+        /// Path of the file
+        file: String,
+    },
+    /// A v2 piece layer whose merkle root is not the pieces root of its file
+    #[error("Piece layer of '{file}' does not match its pieces root")]
+    PieceLayerNotValid {
+        /// Path of the file
+        file: String,
+    },
+    /// A torrent could not be created from files
+    #[error("Torrent could not be created: {reason}")]
+    CreationFailed {
+        /// What went wrong
+        reason: String,
+    },
+    /// Bencode that does not parse or does not fit a torrent file
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    /// A query string that cannot be encoded
+    #[error(transparent)]
+    UrlEncoding(#[from] serde_urlencoded::ser::Error),
+    /// A file that cannot be read
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A failed tracker announce
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    /// The request failed
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    /// The response is not a valid announce response
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    /// The announce URL could not be built
+    #[error(transparent)]
+    UrlEncoding(#[from] serde_urlencoded::ser::Error),
+}
+
+/// What a remote peer did wrong, or what went wrong talking to it
+#[derive(Debug, Error)]
+pub enum PeerError {
+    /// The peer closed the connection
+    #[error("Peer has closed connection")]
+    ClosedConnection,
+    /// A message body shorter than its length prefix
+    #[error("Message body was not read correct. Expected {expected} bytes, got {actual} bytes")]
+    MessageBodyNotReadCorrect {
+        /// Bytes expected
+        expected: usize,
+        /// Bytes read
+        actual: usize,
+    },
+    /// A message body too short for its message
+    #[error("Message body too short")]
+    MessageBodyTooShort(#[from] TryFromSliceError),
+    /// A length prefix longer than any message we accept
+    #[error("Peer message of {length} bytes is longer than the {max_length} bytes allowed")]
+    MessageTooLong {
+        /// Length announced by the prefix
+        length: usize,
+        /// Longest message accepted
+        max_length: usize,
+    },
+    /// The peer did not accept the connection in time
+    #[error("Connecting to {address} timed out")]
+    ConnectTimeout {
+        /// Address of the peer
+        address: SocketAddr,
+    },
+    /// The peer did not handshake in time
+    #[error("Handshake timed out")]
+    HandshakeTimeout,
+    /// The peer did not answer a request in time
+    #[error("Request timed out")]
+    RequestTimeout,
+    /// The peer stopped sending blocks
+    #[error("Peer {address} stopped sending data")]
+    Snubbed {
+        /// Address of the peer
+        address: SocketAddr,
+    },
+    /// A handshake with an empty protocol string, or of the wrong length
+    #[error("Handshake protocol string length '{length}' is not valid")]
+    HandshakeProtocolLengthInvalid {
+        /// The protocol string length byte
+        length: u8,
+    },
+    /// A handshake whose protocol string is not "BitTorrent protocol"
+    #[error("Handshake protocol '{protocol}' not supported")]
+    HandshakeProtocolNotSupported {
+        /// The protocol string, lossily decoded
+        protocol: String,
+    },
+    /// A handshake for another torrent
+    #[error("Handshake info hash mismatch. Expected {expected}, got {actual}")]
+    HandshakeInfoHashMismatch {
+        /// Hex info hash we sent
+        expected: String,
+        /// Hex info hash the peer sent
+        actual: String,
+    },
+    /// The peer has our peer id, we connected to ourselves
+    #[error("Handshake peer id is our own, connected to ourselves")]
+    HandshakeWithSelf,
+    /// The peer rejected a v2 hash request
+    #[error("Hash request rejected by peer")]
+    HashRequestRejected,
+    /// The peer cannot answer v2 hash requests
+    #[error("Peer {address} does not support v2 hash requests")]
+    HashRequestNotSupported {
+        /// Address of the peer
+        address: SocketAddr,
+    },
+    /// An extension message that is not valid bencode
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    /// The connection failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A web seed failing to serve a piece
+#[derive(Debug, Error)]
+pub enum WebSeedError {
+    /// The seed answered with an error or with the wrong bytes
+    #[error("Web seed request to {url} failed: {reason}")]
+    RequestFailed {
+        /// URL of the web seed
+        url: String,
+        /// What went wrong
+        reason: String,
+    },
+    /// A BEP 17 seed asking to come back later
+    #[error("Web seed {url} is busy, retry after {retry_after} seconds")]
+    Busy {
+        /// URL of the web seed
+        url: String,
+        /// Seconds to wait
+        retry_after: u64,
+    },
+    /// The request failed
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+/// A DHT query that failed, or a KRPC message that makes no sense
+#[derive(Debug, Error)]
+pub enum DhtError {
+    /// The node did not answer in time
+    #[error("DHT node {address} did not answer")]
+    QueryTimeout {
+        /// Address of the node
+        address: SocketAddr,
+    },
+    /// The node answered with a KRPC error
+    #[error("DHT node {address} replied with error {code}: {message}")]
+    ErrorReply {
+        /// Address of the node
+        address: SocketAddr,
+        /// KRPC error code, 201 to 204
+        code: i64,
+        /// Error message of the node
+        message: String,
+    },
+    /// A KRPC message missing keys or holding values of the wrong length
+    #[error("KRPC message not valid: {reason}")]
+    MessageNotValid {
+        /// What is wrong
+        reason: String,
+    },
+    /// The node is not running anymore
+    #[error("DHT node stopped")]
+    Stopped,
+    /// A message that is not bencode
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    /// The UDP socket failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Downloaded files that cannot be written
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// A file path escaping the output directory, or otherwise unusable
+    #[error("File path '{path}' not valid")]
+    FilePathNotValid {
+        /// The path, as found in the torrent
+        path: String,
+    },
+    /// A file not matching the sha1 given by the torrent
+    #[error("File '{path}' does not match its sha1")]
+    FileHashNotValid {
+        /// The path, as found in the torrent
+        path: String,
+    },
+    /// A file extending past the downloaded data
+    #[error("File '{path}' is {length} bytes long, only {available} bytes are left for it")]
+    FileDataMissing {
+        /// The path, as found in the torrent
+        path: String,
+        /// The length of the file
+        length: usize,
+        /// The bytes of the file that were downloaded
+        available: usize,
+    },
+    /// A file that cannot be written
+    #[error("Could not write '{}'", path.display())]
+    WriteFailed {
+        /// Path of the file
+        path: PathBuf,
+        /// Why writing failed
+        #[source]
+        source: io::Error,
+    },
+}
+
+// Bencode failures reach the subsystems through BencodeError
+macro_rules! from_bencode_errors {
+    ($($error:ty),*) => {
+        $(
+            impl From<serde_bencode::Error> for $error {
+                fn from(error: serde_bencode::Error) -> Self {
+                    BencodeError::from(error).into()
+                }
             }
-            Self::PieceHashNotValid => "Piece hash not valid".into(),
-        }
-    }
+
+            impl From<ParseError> for $error {
+                fn from(error: ParseError) -> Self {
+                    BencodeError::from(error).into()
+                }
+            }
+        )*
+    };
 }
+
+from_bencode_errors!(Error, MetainfoError, TrackerError, PeerError, DhtError);
@@ -0,0 +1,358 @@
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Serialize, Serializer};
+use tokio::sync::broadcast;
+use tracing::{Level, Span};
+
+// Events a slow subscriber may fall behind before it misses some
+const EVENTS_CAPACITY: usize = 1_024;
+
+/// Something a client or a session did, tagged with what it relates to: a torrent,
+/// a peer and a piece
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    /// Hex info hash of the torrent, None for events of a whole session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<String>,
+    /// Peer the event relates to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<SocketAddr>,
+    /// Index of the piece the event relates to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub piece: Option<u32>,
+    /// What happened
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// What happened
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A torrent was added to a session
+    TorrentAdded {
+        /// Name of the torrent
+        name: String,
+    },
+    /// Metadata missing from the torrent file came from a peer: a v2 piece layer
+    MetadataReceived {
+        /// Path of the file the piece layer belongs to
+        file: String,
+    },
+    /// A piece passed its hash check
+    PieceVerified {
+        /// Index of the piece
+        index: u32,
+        /// Peers or web seed the piece came from
+        source: String,
+    },
+    /// A piece failed its hash check and is downloaded again
+    HashFailed {
+        /// Index of the piece
+        index: u32,
+        /// Attempts made so far
+        attempt: u32,
+        /// Why the piece was discarded
+        reason: String,
+    },
+    /// A web seed could not serve a piece, the piece goes to another source
+    WebSeedFailed {
+        /// Index of the piece
+        index: u32,
+        /// URL of the web seed
+        url: String,
+        /// Why the request failed
+        reason: String,
+    },
+    /// A peer connection was opened
+    PeerConnected {
+        /// Address of the peer
+        address: SocketAddr,
+        /// Whether the peer connected to us
+        incoming: bool,
+    },
+    /// A peer connection was closed
+    PeerDisconnected {
+        /// Address of the peer
+        address: SocketAddr,
+    },
+    /// A tracker announce succeeded
+    TrackerReplied {
+        /// Announce URL
+        url: String,
+        /// Peers in the reply
+        peers_count: usize,
+    },
+    /// A DHT lookup for the peers of the torrent finished
+    DhtReplied {
+        /// Peers the lookup found
+        peers_count: usize,
+    },
+    /// A tracker announce failed
+    TrackerFailed {
+        /// Announce URL
+        url: String,
+        /// Why the announce failed
+        reason: String,
+    },
+    /// Every piece of the torrent was downloaded
+    Finished,
+    /// The download of the torrent stopped
+    Failed {
+        /// Why the download stopped
+        reason: String,
+    },
+    /// Anything else worth telling
+    Log {
+        /// How much the message matters
+        #[serde(serialize_with = "serialize_level")]
+        level: Level,
+        /// The message
+        message: String,
+    },
+}
+
+impl Event {
+    /// How much the event matters
+    pub fn level(&self) -> Level {
+        self.kind.level()
+    }
+}
+
+impl EventKind {
+    /// How much the event matters
+    pub fn level(&self) -> Level {
+        match self {
+            Self::Failed { .. } => Level::ERROR,
+            Self::HashFailed { .. } | Self::WebSeedFailed { .. } | Self::TrackerFailed { .. } => {
+                Level::WARN
+            }
+            Self::TorrentAdded { .. }
+            | Self::MetadataReceived { .. }
+            | Self::PieceVerified { .. }
+            | Self::PeerConnected { .. }
+            | Self::PeerDisconnected { .. }
+            | Self::TrackerReplied { .. }
+            | Self::DhtReplied { .. }
+            | Self::Finished => Level::INFO,
+            Self::Log { level, .. } => *level,
+        }
+    }
+
+    /// Name of the event, as tagged in its serialized form
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TorrentAdded { .. } => "torrent_added",
+            Self::MetadataReceived { .. } => "metadata_received",
+            Self::PieceVerified { .. } => "piece_verified",
+            Self::HashFailed { .. } => "hash_failed",
+            Self::WebSeedFailed { .. } => "web_seed_failed",
+            Self::PeerConnected { .. } => "peer_connected",
+            Self::PeerDisconnected { .. } => "peer_disconnected",
+            Self::TrackerReplied { .. } => "tracker_replied",
+            Self::DhtReplied { .. } => "dht_replied",
+            Self::TrackerFailed { .. } => "tracker_failed",
+            Self::Finished => "finished",
+            Self::Failed { .. } => "failed",
+            Self::Log { .. } => "log",
+        }
+    }
+}
+
+// "warn" rather than the "WARN" of its Display
+fn serialize_level<S: Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&level.as_str().to_lowercase())
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TorrentAdded { name } => write!(f, "Added {name}"),
+            Self::MetadataReceived { file } => write!(f, "Received the piece layer of '{file}'"),
+            Self::PieceVerified { index, source } => {
+                write!(f, "Successfully downloaded piece {index} from {source}")
+            }
+            Self::HashFailed {
+                index,
+                attempt,
+                reason,
+            } => write!(f, "Discarding piece {index} (attempt {attempt}): {reason}"),
+            Self::WebSeedFailed { index, url, reason } => {
+                write!(f, "Web seed {url} could not serve piece {index}: {reason}")
+            }
+            Self::PeerConnected {
+                address,
+                incoming: false,
+            } => write!(f, "Connected to {address}"),
+            Self::PeerConnected {
+                address,
+                incoming: true,
+            } => write!(f, "Accepted {address}"),
+            Self::PeerDisconnected { address } => write!(f, "Disconnected from {address}"),
+            Self::TrackerReplied { url, peers_count } => {
+                write!(f, "Tracker {url} replied with {peers_count} peers")
+            }
+            Self::TrackerFailed { url, reason } => write!(f, "Tracker {url} failed: {reason}"),
+            Self::DhtReplied { peers_count } => write!(f, "DHT found {peers_count} peers"),
+            Self::Finished => write!(f, "Successfully downloaded file"),
+            Self::Failed { reason } => write!(f, "Download failed: {reason}"),
+            Self::Log { message, .. } => write!(f, "{message}"),
+        }
+    }
+}
+
+type EventCallback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+/// Where a client reports what it does. Clones share their subscribers, so a session
+/// and its torrents report to the same place, each clone tagging its own torrent.
+///
+/// Every event is also recorded with `tracing`, within the spans of its torrent, peer
+/// and piece.
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+    callbacks: Arc<Mutex<Vec<EventCallback>>>,
+    info_hash: Option<String>,
+    peer: Option<SocketAddr>,
+    piece: Option<u32>,
+    span: Span,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(EVENTS_CAPACITY).0,
+            callbacks: Default::default(),
+            info_hash: None,
+            peer: None,
+            piece: None,
+            span: Span::none(),
+        }
+    }
+}
+
+impl Events {
+    pub(crate) fn for_torrent(&self, info_hash: Option<String>) -> Self {
+        let span = match &info_hash {
+            Some(info_hash) => tracing::info_span!(parent: &self.span, "torrent", %info_hash),
+            None => self.span.clone(),
+        };
+        Self {
+            info_hash,
+            span,
+            ..self.clone()
+        }
+    }
+
+    // The events of a peer connection, within the current torrent
+    pub(crate) fn for_peer(&self, address: SocketAddr) -> Self {
+        Self {
+            peer: Some(address),
+            span: tracing::info_span!(parent: &self.span, "peer", %address),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn for_piece(&self, index: u32) -> Self {
+        Self {
+            piece: Some(index),
+            span: tracing::info_span!(parent: &self.span, "piece", index),
+            ..self.clone()
+        }
+    }
+
+    /// Hex info hash of the torrent the events are tagged with
+    pub fn info_hash(&self) -> Option<&String> {
+        self.info_hash.as_ref()
+    }
+
+    /// A stream of the events to come, for async consumers
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Calls `callback` with every event to come. It is called right away from the
+    /// task emitting the event, keep it short.
+    pub fn on_event(&self, callback: impl Fn(&Event) + Send + Sync + 'static) {
+        self.lock_callbacks().push(Arc::new(callback));
+    }
+
+    pub(crate) fn emit(&self, kind: EventKind) {
+        self.record(&kind);
+        let event = Event {
+            info_hash: self.info_hash.clone(),
+            peer: self.peer,
+            piece: self.piece,
+            kind,
+        };
+        let callbacks = self.lock_callbacks().clone();
+        callbacks.iter().for_each(|callback| callback(&event));
+        // Nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    // Levels have to be known at compile time by the tracing macros
+    fn record(&self, kind: &EventKind) {
+        let _entered = self.span.enter();
+        let name = kind.name();
+        match kind.level() {
+            Level::ERROR => tracing::error!(event = name, "{kind}"),
+            Level::WARN => tracing::warn!(event = name, "{kind}"),
+            Level::INFO => tracing::info!(event = name, "{kind}"),
+            Level::DEBUG => tracing::debug!(event = name, "{kind}"),
+            _ => tracing::trace!(event = name, "{kind}"),
+        }
+    }
+
+    pub(crate) fn log(&self, level: Level, message: impl Into<String>) {
+        self.emit(EventKind::Log {
+            level,
+            message: message.into(),
+        });
+    }
+
+    /// Emits a message at the warn level
+    pub fn warn(&self, message: impl Into<String>) {
+        self.log(Level::WARN, message);
+    }
+
+    pub(crate) fn info(&self, message: impl Into<String>) {
+        self.log(Level::INFO, message);
+    }
+
+    pub(crate) fn debug(&self, message: impl Into<String>) {
+        self.log(Level::DEBUG, message);
+    }
+
+    // Every message on the wire, the noisiest level
+    pub(crate) fn trace(&self, message: impl Into<String>) {
+        self.log(Level::TRACE, message);
+    }
+
+    fn lock_callbacks(&self) -> std::sync::MutexGuard<'_, Vec<EventCallback>> {
+        self.callbacks
+            .lock()
+            .expect("Event callbacks lock poisoned")
+    }
+}
+
+impl Debug for Events {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events")
+            .field("info_hash", &self.info_hash)
+            .field("peer", &self.peer)
+            .field("piece", &self.piece)
+            .field("subscribers", &self.sender.receiver_count())
+            .finish()
+    }
+}
@@ -0,0 +1,39 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::PeerError;
+
+pub(crate) const EXTENSION_HANDSHAKE_ID: u8 = 0;
+const CLIENT_VERSION: &str = concat!("Basic BitTorrent Client ", env!("CARGO_PKG_VERSION"));
+
+/// BEP 10 extension handshake, only the keys we make use of
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ExtensionHandshake {
+    /// Extension names and the message ids the peer gave them
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Name and version of the peer's client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+}
+
+impl ExtensionHandshake {
+    /// The handshake we send: no extension messages, our client version
+    pub fn ours() -> Self {
+        Self {
+            m: BTreeMap::new(),
+            v: Some(CLIENT_VERSION.into()),
+        }
+    }
+
+    /// Decodes the payload of an extension handshake message
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PeerError> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    /// Encodes the payload of an extension handshake message
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PeerError> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+}
@@ -0,0 +1,84 @@
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+// Canonical allowed fast set generation from BEP 6. Only defined for IPv4 peers.
+pub fn allowed_fast_set(
+    peer_ip: IpAddr,
+    info_hash: &[u8],
+    pieces_count: u32,
+    set_size: usize,
+) -> Vec<u32> {
+    let IpAddr::V4(peer_ip) = peer_ip else {
+        return vec![];
+    };
+    let set_size = set_size.min(pieces_count as usize);
+    let mut allowed_fast_set: Vec<u32> = Vec::with_capacity(set_size);
+
+    // Only the /24 network of the peer is taken into account
+    let masked_ip = u32::from(peer_ip) & 0xFFFF_FF00;
+    let mut x = [&masked_ip.to_be_bytes()[..], info_hash].concat();
+
+    while allowed_fast_set.len() < set_size {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if allowed_fast_set.len() >= set_size {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().expect("Chunks are 4 bytes long"));
+            let index = y % pieces_count;
+            if !allowed_fast_set.contains(&index) {
+                allowed_fast_set.push(index);
+            }
+        }
+    }
+
+    allowed_fast_set
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    // The example of BEP 6
+    const PEER_IP: [u8; 4] = [80, 4, 4, 200];
+    const INFO_HASH: [u8; 20] = [0xaa; 20];
+
+    #[test]
+    fn allowed_fast_set_matches_the_specification() {
+        let peer_ip = IpAddr::from(PEER_IP);
+        assert_eq!(
+            allowed_fast_set(peer_ip, &INFO_HASH, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(peer_ip, &INFO_HASH, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    #[test]
+    fn allowed_fast_set_only_depends_on_the_peer_network() {
+        assert_eq!(
+            allowed_fast_set(IpAddr::from([80, 4, 4, 1]), &INFO_HASH, 1313, 7),
+            allowed_fast_set(IpAddr::from(PEER_IP), &INFO_HASH, 1313, 7)
+        );
+    }
+
+    #[test]
+    fn allowed_fast_set_is_capped_by_the_pieces_count() {
+        let mut set = allowed_fast_set(IpAddr::from(PEER_IP), &INFO_HASH, 3, ALLOWED_FAST_SET_SIZE);
+        set.sort();
+        assert_eq!(set, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn ipv6_peers_have_no_allowed_fast_set() {
+        let peer_ip = IpAddr::from(Ipv6Addr::LOCALHOST);
+        assert!(allowed_fast_set(peer_ip, &INFO_HASH, 1313, ALLOWED_FAST_SET_SIZE).is_empty());
+    }
+}
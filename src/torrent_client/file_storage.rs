@@ -0,0 +1,170 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+use super::{error::StorageError, events::Events, torrent_metainfo::File};
+
+// Writes the files of a multi-file torrent under `root`, `bytes` being all the pieces
+pub fn write_files(
+    root: &Path,
+    files: &[File],
+    bytes: &[u8],
+    events: &Events,
+) -> Result<(), StorageError> {
+    let mut offset = 0;
+
+    for file in files {
+        let file_offset = offset;
+        offset += file.length;
+        if file.is_padding() {
+            continue;
+        }
+        // Pieces ending early would otherwise leave truncated or empty files
+        let Some(file_bytes) = bytes.get(file_offset..offset) else {
+            return Err(StorageError::FileDataMissing {
+                path: file.path.join("/"),
+                length: file.length,
+                available: bytes.len().saturating_sub(file_offset),
+            });
+        };
+
+        let path = root.join(relative_path(&file.path)?);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(write_failed(parent))?;
+        }
+
+        if file.is_symlink() {
+            let target = symlink_target(file)?;
+            create_symlink(&target, &path, events)?;
+            events.info(format!("Linked {} to {}", path.display(), target.display()));
+            continue;
+        }
+
+        if let Some(sha1) = &file.sha1 {
+            if Sha1::digest(file_bytes).as_slice() != sha1.as_slice() {
+                return Err(StorageError::FileHashNotValid {
+                    path: file.path.join("/"),
+                });
+            }
+        }
+
+        write_file(&path, file_bytes)?;
+        if file.is_executable() {
+            set_executable(&path)?;
+        }
+        events.info(format!("Saved {}", path.display()));
+    }
+
+    Ok(())
+}
+
+pub fn write_file(path: &Path, bytes: &[u8]) -> Result<(), StorageError> {
+    fs::write(path, bytes).map_err(write_failed(path))
+}
+
+fn write_failed(path: &Path) -> impl FnOnce(io::Error) -> StorageError + '_ {
+    |source| StorageError::WriteFailed {
+        path: path.into(),
+        source,
+    }
+}
+
+// Paths come from the torrent, they must stay below the output directory
+fn relative_path(components: &[String]) -> Result<PathBuf, StorageError> {
+    let path: PathBuf = components.iter().collect();
+    let is_valid = !components.is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+
+    match is_valid && path.components().count() == components.len() {
+        true => Ok(path),
+        false => Err(StorageError::FilePathNotValid {
+            path: components.join("/"),
+        }),
+    }
+}
+
+// Symlink paths are relative to the torrent root, links are made relative to their directory
+fn symlink_target(file: &File) -> Result<PathBuf, StorageError> {
+    let symlink_path = file.symlink_path.as_deref().unwrap_or_default();
+    let mut target: PathBuf = (1..file.path.len()).map(|_| "..").collect();
+    target.push(relative_path(symlink_path)?);
+    Ok(target)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path, _events: &Events) -> Result<(), StorageError> {
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(path).map_err(write_failed(path))?;
+    }
+    std::os::unix::fs::symlink(target, path).map_err(write_failed(path))
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, path: &Path, events: &Events) -> Result<(), StorageError> {
+    events.warn(format!(
+        "Symlinks not supported, skipping {}",
+        path.display()
+    ));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<(), StorageError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(path)
+        .map_err(write_failed(path))?
+        .permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    fs::set_permissions(path, permissions).map_err(write_failed(path))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<(), StorageError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, length: usize) -> File {
+        File {
+            length,
+            path: vec![name.into()],
+            attr: None,
+            symlink_path: None,
+            sha1: None,
+        }
+    }
+
+    #[test]
+    fn files_are_split_out_of_the_pieces() {
+        let directory = tempfile::tempdir().unwrap();
+        let files = [file("a", 3), file("b", 2)];
+        write_files(directory.path(), &files, b"aaabb", &Events::default()).unwrap();
+        assert_eq!(fs::read(directory.path().join("a")).unwrap(), b"aaa");
+        assert_eq!(fs::read(directory.path().join("b")).unwrap(), b"bb");
+    }
+
+    #[test]
+    fn files_past_the_end_of_the_pieces_are_not_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let files = [file("a", 3), file("b", 2)];
+        let error = write_files(directory.path(), &files, b"aaab", &Events::default());
+        assert!(matches!(
+            error,
+            Err(StorageError::FileDataMissing {
+                length: 2,
+                available: 1,
+                ..
+            })
+        ));
+        assert!(!directory.path().join("b").exists());
+    }
+}
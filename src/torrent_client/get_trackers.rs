@@ -1,62 +1,82 @@
 use serde::{Deserialize, Serialize};
 
-use super::torrent_metainfo::TorrentMetainfo;
+use super::{error::TrackerError, peer_id::PeerId, torrent_metainfo::TorrentMetainfo};
 
+/// An announce to the HTTP tracker of a torrent
 #[derive(Debug)]
 pub struct GetTrackersRequest {
-    pub peer_id: String,
+    /// Our peer id
+    pub peer_id: PeerId,
+    /// The swarm to announce on, hybrid torrents have two
+    pub info_hash: Vec<u8>,
+    /// The torrent announced
     pub torrent: TorrentMetainfo,
+    /// Where we accept peers
+    pub port: u16,
 }
 
 impl GetTrackersRequest {
-    pub fn new(peer_id: &str, torrent: TorrentMetainfo) -> Self {
+    /// An announce of `info_hash`, as a peer that downloaded nothing yet
+    pub fn new(peer_id: PeerId, info_hash: Vec<u8>, torrent: TorrentMetainfo, port: u16) -> Self {
         Self {
-            peer_id: peer_id.into(),
+            peer_id,
+            info_hash,
             torrent,
+            port,
         }
     }
 }
 
 impl GetTrackersRequest {
-    pub fn to_url(&self) -> anyhow::Result<String> {
+    /// The announce URL with its query string
+    pub fn to_url(&self) -> Result<String, TrackerError> {
         let params = vec![
-            ("peer_id", self.peer_id.to_string()),
-            ("port", "6881".to_string()),
+            ("port", self.port.to_string()),
             ("uploaded", "0".to_string()),
             ("downloaded", "0".to_string()),
-            ("left", format!("{}", self.torrent.info.length)),
+            ("left", format!("{}", self.torrent.info.total_length())),
             ("compact", "1".to_string()),
         ];
         let encoded_params = serde_urlencoded::to_string(params)?;
-        let info_hash = self.torrent.info.hash_string()?;
+        let info_hash: String = self
+            .info_hash
+            .iter()
+            .map(|byte| format!("%{:02x}", byte))
+            .collect();
 
+        // Raw bytes are percent-encoded by hand, like the info hash
         let url = format!(
-            "{}?info_hash={}&{}",
-            self.torrent.announce, info_hash, encoded_params
+            "{}?info_hash={}&peer_id={}&{}",
+            self.torrent.announce,
+            info_hash,
+            self.peer_id.url_encoded(),
+            encoded_params
         );
 
         Ok(url)
     }
 }
 
+/// A tracker reply, with peers in the compact form
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetTrackersResponse {
+    /// Six bytes per peer: the IPv4 address and the port
     #[serde(rename = "peers", with = "serde_bytes")]
     pub raw_peers_string: Vec<u8>,
 }
 
 impl GetTrackersResponse {
+    /// The peers as "address:port" strings
     pub fn peers(&self) -> Vec<String> {
-        return self
-            .raw_peers_string
+        self.raw_peers_string
             .chunks_exact(6)
             .map(|chunk| {
                 let port: u16 = ((chunk[4] as u16) << 8) | (chunk[5] as u16);
-                return format!(
+                format!(
                     "{}.{}.{}.{}:{}",
                     chunk[0], chunk[1], chunk[2], chunk[3], port
-                );
+                )
             })
-            .collect();
+            .collect()
     }
 }
@@ -1,26 +1,223 @@
+use crate::torrent_client::error::PeerError;
+use crate::torrent_client::peer_id::{PeerId, PEER_ID_LENGTH};
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+const RESERVED_LENGTH: usize = 8;
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+const DHT_BYTE: usize = 7;
+const DHT_BIT: u8 = 0x01;
+const FAST_EXTENSION_BYTE: usize = 7;
+const FAST_EXTENSION_BIT: u8 = 0x04;
+const V2_UPGRADE_BYTE: usize = 7;
+const V2_UPGRADE_BIT: u8 = 0x10;
+const INFO_HASH_LENGTH: usize = 20;
+
+// Bytes following the protocol string: reserved, info hash and peer id
+pub(crate) const HANDSHAKE_TAIL_LENGTH: usize = RESERVED_LENGTH + INFO_HASH_LENGTH + PEER_ID_LENGTH;
+
+/// The first message on a peer connection, telling the torrent and the extensions
+/// supported
 pub struct HandshakeMessage {
+    /// Protocol string, "BitTorrent protocol" for us
+    pub protocol: Vec<u8>,
+    /// Bits advertising the supported extensions
+    pub reserved: [u8; RESERVED_LENGTH],
+    /// Info hash of the swarm
     pub info_hash: Vec<u8>,
-    pub peer_id: String,
+    /// Peer id of the sender
+    pub peer_id: PeerId,
+}
+
+impl HandshakeMessage {
+    /// Our handshake, advertising the extension protocol and the fast extension
+    pub fn new(info_hash: Vec<u8>, peer_id: PeerId) -> Self {
+        let mut reserved = [0; RESERVED_LENGTH];
+        reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+        reserved[FAST_EXTENSION_BYTE] |= FAST_EXTENSION_BIT;
+        Self {
+            protocol: PROTOCOL.to_vec(),
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// BEP 10 extension protocol support is advertised with a reserved bit
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    /// BEP 52: tells peers of a hybrid swarm that we can speak v2
+    pub fn enable_v2_upgrade(&mut self) {
+        self.reserved[V2_UPGRADE_BYTE] |= V2_UPGRADE_BIT;
+    }
+
+    /// Whether the sender of a hybrid swarm can speak v2
+    pub fn supports_v2_upgrade(&self) -> bool {
+        self.reserved[V2_UPGRADE_BYTE] & V2_UPGRADE_BIT != 0
+    }
+
+    /// BEP 5: tells peers we run a DHT node, they may send us its port
+    pub fn enable_dht(&mut self) {
+        self.reserved[DHT_BYTE] |= DHT_BIT;
+    }
+
+    /// Whether the sender runs a DHT node
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[DHT_BYTE] & DHT_BIT != 0
+    }
+
+    /// BEP 6 fast extension support is advertised with a reserved bit
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[FAST_EXTENSION_BYTE] & FAST_EXTENSION_BIT != 0
+    }
+
+    /// Parses a full handshake, including the leading protocol string length byte
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PeerError> {
+        let Some(&protocol_length) = bytes.first() else {
+            return Err(PeerError::HandshakeProtocolLengthInvalid { length: 0 });
+        };
+        let protocol_end = 1 + protocol_length as usize;
+        if protocol_length == 0 || bytes.len() != protocol_end + HANDSHAKE_TAIL_LENGTH {
+            return Err(PeerError::HandshakeProtocolLengthInvalid {
+                length: protocol_length,
+            });
+        }
+
+        let reserved_end = protocol_end + RESERVED_LENGTH;
+        let info_hash_end = reserved_end + INFO_HASH_LENGTH;
+
+        let protocol = Vec::from(&bytes[1..protocol_end]);
+        let reserved = bytes[protocol_end..reserved_end].try_into()?;
+        let info_hash = Vec::from(&bytes[reserved_end..info_hash_end]);
+        let peer_id = PeerId::from_bytes(&bytes[info_hash_end..])?;
+        Ok(Self {
+            protocol,
+            reserved,
+            info_hash,
+            peer_id,
+        })
+    }
+
+    /// Encodes the full handshake
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(1 + self.protocol.len() + HANDSHAKE_TAIL_LENGTH);
+        message.push(self.protocol.len() as u8); // Length of the protocol string
+        message.extend_from_slice(&self.protocol); // Protocol string
+        message.extend_from_slice(&self.reserved); // The next 8 bytes are reserved
+        message.extend_from_slice(&self.info_hash); // The next 20 bytes are the sha1 infohash
+        message.extend_from_slice(self.peer_id.as_bytes()); // The next 20 bytes are the peer id
+        message
+    }
 }
 
 impl HandshakeMessage {
-    pub fn new(info_hash: Vec<u8>, peer_id: String) -> Self {
-        Self { info_hash, peer_id }
+    /// Checks that a reply speaks the BitTorrent protocol, belongs to our torrent and
+    /// does not come from ourselves
+    pub fn validate_reply(&self, sent: &HandshakeMessage) -> Result<(), PeerError> {
+        if self.protocol != PROTOCOL {
+            return Err(PeerError::HandshakeProtocolNotSupported {
+                protocol: String::from_utf8_lossy(&self.protocol).into(),
+            });
+        }
+        if self.info_hash != sent.info_hash {
+            return Err(PeerError::HandshakeInfoHashMismatch {
+                expected: hex::encode(&sent.info_hash),
+                actual: hex::encode(&self.info_hash),
+            });
+        }
+        if self.peer_id == sent.peer_id {
+            return Err(PeerError::HandshakeWithSelf);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent() -> HandshakeMessage {
+        HandshakeMessage::new(vec![1; INFO_HASH_LENGTH], PeerId([b'a'; PEER_ID_LENGTH]))
+    }
+
+    fn reply(protocol: &[u8]) -> HandshakeMessage {
+        let mut reply =
+            HandshakeMessage::new(vec![1; INFO_HASH_LENGTH], PeerId([b'b'; PEER_ID_LENGTH]));
+        reply.protocol = protocol.to_vec();
+        reply
+    }
+
+    #[test]
+    fn handshake_round_trips_through_bytes() {
+        let bytes = sent().to_bytes();
+        assert_eq!(bytes.len(), 68);
+        let parsed = HandshakeMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.protocol, PROTOCOL);
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert!(parsed.supports_extension_protocol());
+        assert!(parsed.supports_fast_extension());
+        assert!(!parsed.supports_v2_upgrade());
+    }
+
+    #[test]
+    fn other_protocol_string_lengths_are_parsed() {
+        let bytes = reply(b"Other protocol").to_bytes();
+        let parsed = HandshakeMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.protocol, b"Other protocol");
+        assert_eq!(parsed.peer_id, PeerId([b'b'; PEER_ID_LENGTH]));
     }
 
-    pub fn from_bytes(bytes: &[u8; 68]) -> Self {
-        let info_hash = Vec::from(&bytes[28..48]);
-        let peer_id = hex::encode(&bytes[48..68]);
-        Self::new(info_hash, peer_id)
+    #[test]
+    fn empty_or_truncated_handshakes_are_rejected() {
+        let mut bytes = sent().to_bytes();
+        bytes.pop();
+        assert!(matches!(
+            HandshakeMessage::from_bytes(&bytes),
+            Err(PeerError::HandshakeProtocolLengthInvalid { length: 19 })
+        ));
+        assert!(matches!(
+            HandshakeMessage::from_bytes(&[0; 1 + HANDSHAKE_TAIL_LENGTH]),
+            Err(PeerError::HandshakeProtocolLengthInvalid { length: 0 })
+        ));
     }
 
-    pub fn to_bytes(&self) -> [u8; 68] {
-        let mut message = [0; 68];
-        message[0] = 19; // Length of the protocol string
-        message[1..20].copy_from_slice(b"BitTorrent protocol"); // Protocol string
-                                                                // The next 8 bytes are already set to zero by default
-        message[28..48].copy_from_slice(&self.info_hash[..]); // The next 20 bytes are the sha1 infohash
-        message[48..68].copy_from_slice(self.peer_id.as_bytes()); // The next 20 bytes are the peer id
-        return message;
+    #[test]
+    fn binary_protocol_strings_are_not_supported() {
+        assert!(matches!(
+            reply(&[0x16, 0x03, 0x01]).validate_reply(&sent()),
+            Err(PeerError::HandshakeProtocolNotSupported { .. })
+        ));
+    }
+
+    #[test]
+    fn other_printable_protocol_strings_are_not_supported() {
+        assert!(reply(PROTOCOL).validate_reply(&sent()).is_ok());
+        for protocol in [&b"Other protocol"[..], b"BitTorrent protocol v2"] {
+            let parsed = HandshakeMessage::from_bytes(&reply(protocol).to_bytes()).unwrap();
+            assert!(matches!(
+                parsed.validate_reply(&sent()),
+                Err(PeerError::HandshakeProtocolNotSupported { protocol: ref name })
+                    if name.as_bytes() == protocol
+            ));
+        }
+    }
+
+    #[test]
+    fn replies_for_another_torrent_or_from_ourselves_are_rejected() {
+        let mut other_torrent = reply(PROTOCOL);
+        other_torrent.info_hash = vec![2; INFO_HASH_LENGTH];
+        assert!(matches!(
+            other_torrent.validate_reply(&sent()),
+            Err(PeerError::HandshakeInfoHashMismatch { .. })
+        ));
+
+        let mut ourselves = reply(PROTOCOL);
+        ourselves.peer_id = sent().peer_id;
+        assert!(matches!(
+            ourselves.validate_reply(&sent()),
+            Err(PeerError::HandshakeWithSelf)
+        ));
     }
 }
@@ -0,0 +1,104 @@
+use sha2::{Digest, Sha256};
+
+pub const MERKLE_BLOCK_SIZE: usize = 16_384; // 16 KiB
+pub const HASH_LENGTH: usize = 32;
+
+pub type MerkleHash = [u8; HASH_LENGTH];
+
+// Leaf hashes of the 16 KiB blocks of some data, the last block may be shorter
+pub fn block_hashes(bytes: &[u8]) -> Vec<MerkleHash> {
+    bytes
+        .chunks(MERKLE_BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect()
+}
+
+// Root of a subtree of `leaves_count` leaves, missing leaves being zero hashes
+pub fn root(hashes: &[MerkleHash], leaves_count: usize) -> MerkleHash {
+    root_with_padding(hashes, leaves_count, [0; HASH_LENGTH])
+}
+
+// Root of a tree whose missing nodes at the base layer are `padding`
+pub fn root_with_padding(
+    hashes: &[MerkleHash],
+    nodes_count: usize,
+    padding: MerkleHash,
+) -> MerkleHash {
+    let nodes_count = nodes_count.max(hashes.len()).next_power_of_two();
+    let mut layer: Vec<MerkleHash> = hashes.to_vec();
+    layer.resize(nodes_count, padding);
+
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+// Root of a subtree made only of zero leaves
+pub fn padding_root(leaves_count: usize) -> MerkleHash {
+    root(&[], leaves_count)
+}
+
+pub fn hash_pair(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+pub fn hashes_from_bytes(bytes: &[u8]) -> Vec<MerkleHash> {
+    bytes
+        .chunks_exact(HASH_LENGTH)
+        .map(|chunk| chunk.try_into().expect("Chunks are 32 bytes long"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_single_block_is_its_own_root() {
+        let hashes = block_hashes(b"data");
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(
+            root(&hashes, 1),
+            <MerkleHash>::from(Sha256::digest(b"data"))
+        );
+    }
+
+    #[test]
+    fn missing_leaves_are_zero_hashes() {
+        let bytes = vec![1u8; 3 * MERKLE_BLOCK_SIZE];
+        let hashes = block_hashes(&bytes);
+        let expected = hash_pair(
+            &hash_pair(&hashes[0], &hashes[1]),
+            &hash_pair(&hashes[2], &[0; HASH_LENGTH]),
+        );
+        assert_eq!(root(&hashes, 3), expected);
+        assert_eq!(root(&hashes, 4), expected);
+    }
+
+    #[test]
+    fn padding_pieces_hash_like_zero_leaves() {
+        // Two pieces of two blocks, only the first one being present
+        let hashes = block_hashes(&vec![1u8; 2 * MERKLE_BLOCK_SIZE]);
+        let piece_hash = root(&hashes, 2);
+        assert_eq!(
+            root_with_padding(&[piece_hash], 2, padding_root(2)),
+            root(&hashes, 4)
+        );
+    }
+
+    #[test]
+    fn hashes_are_read_in_32_bytes_chunks() {
+        let bytes = [[1u8; HASH_LENGTH], [2u8; HASH_LENGTH]].concat();
+        assert_eq!(
+            hashes_from_bytes(&bytes),
+            vec![[1u8; HASH_LENGTH], [2u8; HASH_LENGTH]]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+const MAX_HASH_FAILURES_PER_PEER: u32 = 2;
+
+#[derive(Debug, Default)]
+pub struct PeerBans {
+    hash_failures: HashMap<SocketAddr, u32>,
+    unresponsive: HashSet<SocketAddr>,
+}
+
+impl PeerBans {
+    // Returns true if the peer got banned by this failure
+    pub fn record_hash_failure(&mut self, peer: SocketAddr) -> bool {
+        let was_banned = self.is_banned(&peer);
+        *self.hash_failures.entry(peer).or_insert(0) += 1;
+        !was_banned && self.is_banned(&peer)
+    }
+
+    pub fn is_banned(&self, peer: &SocketAddr) -> bool {
+        self.hash_failures
+            .get(peer)
+            .is_some_and(|failures| *failures >= MAX_HASH_FAILURES_PER_PEER)
+    }
+
+    // Unreachable, timed out or snubbing peers are skipped for the rest of the session
+    pub fn mark_unresponsive(&mut self, peer: SocketAddr) {
+        self.unresponsive.insert(peer);
+    }
+
+    // Whether it is worth connecting to the peer again
+    pub fn is_usable(&self, peer: &SocketAddr) -> bool {
+        !self.is_banned(peer) && !self.unresponsive.contains(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_is_banned_once_it_reaches_the_strike_threshold() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut bans = PeerBans::default();
+
+        for _ in 1..MAX_HASH_FAILURES_PER_PEER {
+            assert!(!bans.record_hash_failure(peer));
+            assert!(bans.is_usable(&peer));
+        }
+        assert!(bans.record_hash_failure(peer));
+        assert!(bans.is_banned(&peer));
+        assert!(!bans.is_usable(&peer));
+
+        // The ban is only reported the first time
+        assert!(!bans.record_hash_failure(peer));
+    }
+
+    #[test]
+    fn strikes_are_counted_per_peer() {
+        let first = SocketAddr::from(([127, 0, 0, 1], 1));
+        let second = SocketAddr::from(([127, 0, 0, 1], 2));
+        let mut bans = PeerBans::default();
+
+        for _ in 0..MAX_HASH_FAILURES_PER_PEER {
+            bans.record_hash_failure(first);
+        }
+        assert!(bans.is_banned(&first));
+        assert!(!bans.is_banned(&second));
+    }
+
+    #[test]
+    fn unresponsive_peers_are_not_usable_but_not_banned() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut bans = PeerBans::default();
+        bans.mark_unresponsive(peer);
+        assert!(!bans.is_banned(&peer));
+        assert!(!bans.is_usable(&peer));
+    }
+}
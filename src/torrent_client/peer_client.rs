@@ -0,0 +1,190 @@
+use std::fmt::{Display, Formatter, Result};
+
+use super::peer_id::PeerId;
+
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent (Rasterbar)"),
+    ("lt", "libTorrent (Rakshasa)"),
+    ("qB", "qBittorrent"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+    ("XX", "Basic BitTorrent Client"),
+];
+
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// The client software a peer runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerClient {
+    /// Name of the client, like "qBittorrent"
+    pub name: String,
+    /// Version of the client, empty when unknown
+    pub version: String,
+}
+
+impl Display for PeerClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+impl PeerClient {
+    /// The client telling itself in its peer id, in any of the common styles
+    pub fn from_peer_id(peer_id: &PeerId) -> Option<Self> {
+        let bytes = peer_id.as_bytes();
+        Self::from_azureus_style(bytes)
+            .or_else(|| Self::from_shadow_style(bytes))
+            .or_else(|| Self::from_mainline_style(bytes))
+    }
+
+    /// The `v` key of the extension handshake, e.g. "qBittorrent/4.5.2" or "Transmission 3.00"
+    pub fn from_extension_version(version: &str) -> Self {
+        let version = version.trim();
+        match version.rfind([' ', '/']) {
+            Some(separator) => Self {
+                name: version[..separator].trim().into(),
+                version: version[separator + 1..].trim_start_matches('v').into(),
+            },
+            None => Self {
+                name: version.into(),
+                version: String::new(),
+            },
+        }
+    }
+}
+
+impl PeerClient {
+    // "-AZ2060-": client code and four version characters between dashes.
+    // Anything else between the dashes is not this style, the client stays unknown.
+    fn from_azureus_style(bytes: &[u8]) -> Option<Self> {
+        if bytes[0] != b'-' || bytes[7] != b'-' || !bytes[1..7].is_ascii() {
+            return None;
+        }
+        let code = std::str::from_utf8(&bytes[1..3]).ok()?;
+        let version_chars = std::str::from_utf8(&bytes[3..7]).ok()?;
+        let name = AZUREUS_CLIENTS
+            .iter()
+            .find(|(client_code, _)| *client_code == code)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| format!("Unknown ({code})"));
+
+        let version = match code {
+            // Transmission encodes the minor version on two digits, e.g. "2940" is 2.94
+            "TR" => format!("{}.{}", &version_chars[0..1], &version_chars[1..3]),
+            _ => version_chars
+                .chars()
+                .map(|char| char.to_digit(36).unwrap_or(0).to_string())
+                .collect::<Vec<String>>()
+                .join("."),
+        };
+        Some(Self { name, version })
+    }
+
+    // "S58B-----": client letter, up to five version characters, then dashes
+    fn from_shadow_style(bytes: &[u8]) -> Option<Self> {
+        let (_, name) = SHADOW_CLIENTS
+            .iter()
+            .find(|(client_code, _)| *client_code == bytes[0])?;
+        if &bytes[6..9] != b"---" {
+            return None;
+        }
+
+        let version = bytes[1..6]
+            .iter()
+            .take_while(|byte| **byte != b'-')
+            .map(|byte| Self::shadow_version_digit(*byte).map(|digit| digit.to_string()))
+            .collect::<Option<Vec<String>>>()?
+            .join(".");
+        Some(Self {
+            name: name.to_string(),
+            version,
+        })
+    }
+
+    // "M4-3-6--": mainline BitTorrent, version numbers separated by dashes
+    fn from_mainline_style(bytes: &[u8]) -> Option<Self> {
+        if bytes[0] != b'M' {
+            return None;
+        }
+        let text = std::str::from_utf8(&bytes[1..8]).ok()?;
+        let numbers: Vec<&str> = text.split('-').filter(|part| !part.is_empty()).collect();
+        if numbers.is_empty()
+            || !numbers
+                .iter()
+                .all(|n| n.chars().all(|c| c.is_ascii_digit()))
+        {
+            return None;
+        }
+        Some(Self {
+            name: "BitTorrent (Mainline)".into(),
+            version: numbers.join("."),
+        })
+    }
+
+    fn shadow_version_digit(byte: u8) -> Option<u8> {
+        match byte {
+            b'0'..=b'9' => Some(byte - b'0'),
+            b'A'..=b'Z' => Some(byte - b'A' + 10),
+            b'a'..=b'z' => Some(byte - b'a' + 36),
+            b'.' => Some(62),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_id(prefix: &[u8]) -> PeerId {
+        let mut bytes = [b'0'; 20];
+        bytes[..prefix.len()].copy_from_slice(prefix);
+        PeerId(bytes)
+    }
+
+    #[test]
+    fn azureus_style_versions_are_decoded() {
+        let client = PeerClient::from_peer_id(&peer_id(b"-qB4520-")).unwrap();
+        assert_eq!(client.to_string(), "qBittorrent 4.5.2.0");
+        let client = PeerClient::from_peer_id(&peer_id(b"-TR2940-")).unwrap();
+        assert_eq!(client.to_string(), "Transmission 2.94");
+    }
+
+    #[test]
+    fn non_ascii_azureus_style_versions_are_unknown() {
+        // Latin-1 bytes in the version, the dashes where Azureus style puts them
+        assert_eq!(PeerClient::from_peer_id(&peer_id(b"-TR\xe9000-")), None);
+        assert_eq!(PeerClient::from_peer_id(&peer_id(b"-TR300\xff-")), None);
+    }
+
+    #[test]
+    fn shadow_and_mainline_styles_are_decoded() {
+        let client = PeerClient::from_peer_id(&peer_id(b"S58B-----")).unwrap();
+        assert_eq!(client.to_string(), "Shadow's client 5.8.11");
+        let client = PeerClient::from_peer_id(&peer_id(b"M4-3-6--")).unwrap();
+        assert_eq!(client.to_string(), "BitTorrent (Mainline) 4.3.6");
+    }
+}
@@ -0,0 +1,404 @@
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Instant},
+};
+
+use super::{
+    error::PeerError,
+    events::Events,
+    extension_handshake::{ExtensionHandshake, EXTENSION_HANDSHAKE_ID},
+    handshake_message::{HandshakeMessage, HANDSHAKE_TAIL_LENGTH},
+    peer_client::PeerClient,
+    peer_message::PeerMessage,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MESSAGE_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+/// A TCP connection to a peer speaking the peer wire protocol
+pub struct PeerConnection {
+    /// The address of the peer
+    pub address: SocketAddr,
+    stream: TcpStream,
+    /// The client the peer runs, known once it has handshaked
+    pub client: Option<PeerClient>,
+    /// Whether both sides support the fast extension (BEP 6)
+    pub fast_extension: bool,
+    /// Whether the peer answers v2 hash requests
+    pub v2_hashes: bool,
+    /// Pieces the peer lets us request while choked
+    pub allowed_fast_pieces: HashSet<u32>,
+    /// Pieces we let the peer request while we choke it
+    pub granted_fast_pieces: HashSet<u32>,
+    /// Pieces the peer suggested we download
+    pub suggested_pieces: Vec<u32>,
+    /// Whether the peer chokes us, as every peer does until it sends Unchoke
+    pub peer_choking: bool,
+    /// Pieces the peer got since it told it had none (BEP 6 HaveNone), None while it
+    /// may have any of them
+    pub peer_pieces: Option<HashSet<u32>>,
+    /// The port of the DHT node of the peer, once it told it (BEP 5)
+    pub dht_port: Option<u16>,
+    pub(crate) events: Events,
+    last_sent_at: Instant,
+    // Longer length prefixes get the peer dropped before anything is allocated
+    max_message_length: usize,
+}
+
+impl PeerConnection {
+    /// Connects to a peer, failing after a timeout
+    pub async fn connect(address: SocketAddr, events: Events) -> Result<Self, PeerError> {
+        let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| PeerError::ConnectTimeout { address })??;
+        Ok(Self::from_stream(stream, address, events))
+    }
+
+    /// A connection the peer opened to us
+    pub fn from_stream(stream: TcpStream, address: SocketAddr, events: Events) -> Self {
+        Self {
+            address,
+            stream,
+            client: None,
+            fast_extension: false,
+            v2_hashes: false,
+            allowed_fast_pieces: HashSet::new(),
+            granted_fast_pieces: HashSet::new(),
+            suggested_pieces: vec![],
+            peer_choking: true,
+            peer_pieces: None,
+            dht_port: None,
+            events: events.for_peer(address),
+            last_sent_at: Instant::now(),
+            max_message_length: PeerMessage::max_length(0),
+        }
+    }
+
+    /// The bitfield of the torrent bounds the length of messages
+    pub fn with_pieces_count(mut self, pieces_count: usize) -> Self {
+        self.max_message_length = PeerMessage::max_length(pieces_count);
+        self
+    }
+
+    /// Flushes and closes the connection
+    pub async fn shutdown(&mut self) -> Result<(), PeerError> {
+        self.stream.flush().await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+impl PeerConnection {
+    /// Sends a message to the peer
+    pub async fn send_message(&mut self, message: PeerMessage) -> Result<(), PeerError> {
+        self.stream.write_all(&message.to_bytes()).await?;
+        self.last_sent_at = Instant::now();
+        self.events.trace(format!("Sent message: {message}"));
+        Ok(())
+    }
+
+    /// Sends our handshake and reads the peer's, failing after a timeout
+    pub async fn exchange_handshake(
+        &mut self,
+        handshake_message: &HandshakeMessage,
+    ) -> Result<HandshakeMessage, PeerError> {
+        let exchange = async {
+            self.write_handshake(handshake_message).await?;
+            self.read_handshake().await
+        };
+
+        time::timeout(HANDSHAKE_TIMEOUT, exchange)
+            .await
+            .map_err(|_| PeerError::HandshakeTimeout)?
+    }
+
+    /// Reads the handshake of a peer that connected to us. Peers connecting
+    /// to us handshake first, we answer once we know the torrent.
+    pub async fn receive_handshake(&mut self) -> Result<HandshakeMessage, PeerError> {
+        time::timeout(HANDSHAKE_TIMEOUT, self.read_handshake())
+            .await
+            .map_err(|_| PeerError::HandshakeTimeout)?
+    }
+
+    /// Sends our handshake
+    pub async fn send_handshake(
+        &mut self,
+        handshake_message: &HandshakeMessage,
+    ) -> Result<(), PeerError> {
+        time::timeout(HANDSHAKE_TIMEOUT, self.write_handshake(handshake_message))
+            .await
+            .map_err(|_| PeerError::HandshakeTimeout)?
+    }
+
+    async fn write_handshake(
+        &mut self,
+        handshake_message: &HandshakeMessage,
+    ) -> Result<(), PeerError> {
+        self.stream.write_all(&handshake_message.to_bytes()).await?;
+        self.last_sent_at = Instant::now();
+        Ok(())
+    }
+
+    // Its length depends on the protocol string length
+    async fn read_handshake(&mut self) -> Result<HandshakeMessage, PeerError> {
+        let protocol_length = self.stream.read_u8().await?;
+        let mut buffer = vec![0u8; 1 + protocol_length as usize + HANDSHAKE_TAIL_LENGTH];
+        buffer[0] = protocol_length;
+        if protocol_length > 0 {
+            self.stream.read_exact(&mut buffer[1..]).await?;
+        }
+        HandshakeMessage::from_bytes(&buffer)
+    }
+
+    /// Whether the peer may have a piece, only a HaveNone rules pieces out
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.peer_pieces
+            .as_ref()
+            .is_none_or(|peer_pieces| peer_pieces.contains(&index))
+    }
+
+    /// Whether the peer would serve a request for a piece now, allowed fast pieces
+    /// even while it chokes us
+    pub fn can_request(&self, index: u32) -> bool {
+        self.has_piece(index) && (!self.peer_choking || self.allowed_fast_pieces.contains(&index))
+    }
+
+    /// Waits up to `wait` for the next message, sending keep-alives while idle.
+    /// Returns None if nothing arrived in time.
+    pub async fn wait_for_message(
+        &mut self,
+        wait: Duration,
+    ) -> Result<Option<PeerMessage>, PeerError> {
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let keep_alive_at = self.last_sent_at + KEEP_ALIVE_INTERVAL;
+
+            // Only waiting for readability is cancel safe, reading the message is not
+            match time::timeout_at(deadline.min(keep_alive_at), self.stream.readable()).await {
+                Ok(readable) => {
+                    readable?;
+                    return self.read_message().await.map(Some);
+                }
+                Err(_) if Instant::now() >= deadline => return Ok(None),
+                Err(_) => self.send_message(PeerMessage::KeepAlive).await?,
+            }
+        }
+    }
+
+    /// Reads the next message, failing if the peer stays silent too long
+    pub async fn read_message(&mut self) -> Result<PeerMessage, PeerError> {
+        let message = time::timeout(
+            MESSAGE_READ_TIMEOUT,
+            Self::read_message_from(&mut self.stream, self.max_message_length),
+        )
+        .await
+        .map_err(|_| PeerError::RequestTimeout)??;
+
+        match &message {
+            // The extension handshake tells more precisely which client the peer runs
+            PeerMessage::Extended {
+                id: EXTENSION_HANDSHAKE_ID,
+                payload,
+            } => {
+                if let Ok(ExtensionHandshake {
+                    v: Some(version), ..
+                }) = ExtensionHandshake::from_bytes(payload)
+                {
+                    self.client = Some(PeerClient::from_extension_version(&version));
+                }
+            }
+            PeerMessage::AllowedFast { index } if self.fast_extension => {
+                self.allowed_fast_pieces.insert(*index);
+            }
+            PeerMessage::SuggestPiece { index }
+                if self.fast_extension && !self.suggested_pieces.contains(index) =>
+            {
+                self.suggested_pieces.push(*index);
+            }
+            PeerMessage::Choke => self.peer_choking = true,
+            PeerMessage::Unchoke => self.peer_choking = false,
+            PeerMessage::HaveNone if self.fast_extension => self.peer_pieces = Some(HashSet::new()),
+            PeerMessage::Have { index } => {
+                if let Some(peer_pieces) = self.peer_pieces.as_mut() {
+                    peer_pieces.insert(*index);
+                }
+            }
+            // Bitfields are not kept, the peer may have any piece
+            PeerMessage::HaveAll | PeerMessage::Bitfield { .. } => self.peer_pieces = None,
+            PeerMessage::Port { port } => self.dht_port = Some(*port),
+            _ => {}
+        }
+
+        Ok(message)
+    }
+
+    async fn read_message_from(
+        stream: &mut TcpStream,
+        max_message_length: usize,
+    ) -> Result<PeerMessage, PeerError> {
+        // Read the message size (first 4 bytes)
+        let message_size = stream.read_u32().await;
+        let Ok(message_size) = message_size else {
+            return Err(PeerError::ClosedConnection);
+        };
+        if message_size == 0 {
+            return Ok(PeerMessage::KeepAlive);
+        }
+        if message_size as usize > max_message_length {
+            return Err(PeerError::MessageTooLong {
+                length: message_size as usize,
+                max_length: max_message_length,
+            });
+        }
+
+        // Read the message id (following 1 byte)
+        let message_id = stream.read_u8().await?;
+
+        // The body is whatever follows the id byte, read_exact fails on a short read
+        let mut message_body = vec![0u8; message_size as usize - 1];
+        if !message_body.is_empty() {
+            stream.read_exact(&mut message_body).await?;
+        }
+
+        // Return a peer message with the id and body read
+        PeerMessage::from_bytes(message_id, &message_body)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // A connection whose peer writes the given bytes
+    pub(in crate::torrent_client) async fn connection_receiving(bytes: Vec<u8>) -> PeerConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(&bytes).await.unwrap();
+            // Keep the stream open until the test is done with it
+            let _ = stream.read_to_end(&mut vec![]).await;
+        });
+        PeerConnection::connect(address, Events::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn oversized_length_prefix_is_rejected_before_reading_the_body() {
+        let mut connection = connection_receiving(u32::MAX.to_be_bytes().to_vec()).await;
+        let error = connection.read_message().await.unwrap_err();
+        assert!(matches!(error, PeerError::MessageTooLong { .. }));
+    }
+
+    #[tokio::test]
+    async fn bitfield_of_a_large_torrent_fits() {
+        let pieces_count = 1_000_000;
+        let bitfield = PeerMessage::Bitfield {
+            bitfield: vec![0xff; pieces_count / 8],
+        };
+        let mut connection = connection_receiving(bitfield.to_bytes())
+            .await
+            .with_pieces_count(pieces_count);
+        let message = connection.read_message().await.unwrap();
+        assert!(
+            matches!(message, PeerMessage::Bitfield { bitfield } if bitfield.len() == pieces_count / 8)
+        );
+    }
+
+    #[tokio::test]
+    async fn port_and_unknown_messages_do_not_end_the_connection() {
+        let bytes = [
+            PeerMessage::Port { port: 6881 }.to_bytes(),
+            // Unknown id 42 with a 3 bytes body
+            vec![0, 0, 0, 4, 42, 1, 2, 3],
+            PeerMessage::Unchoke.to_bytes(),
+        ]
+        .concat();
+        let mut connection = connection_receiving(bytes).await;
+
+        let message = connection.read_message().await.unwrap();
+        assert!(matches!(message, PeerMessage::Port { port: 6881 }));
+        assert_eq!(connection.dht_port, Some(6881));
+        let message = connection.read_message().await.unwrap();
+        assert!(matches!(message, PeerMessage::Unknown { id: 42 }));
+        let message = connection.read_message().await.unwrap();
+        assert!(matches!(message, PeerMessage::Unchoke));
+    }
+
+    #[tokio::test]
+    async fn allowed_fast_and_suggested_pieces_are_kept_with_the_fast_extension() {
+        let bytes = [
+            PeerMessage::AllowedFast { index: 4 }.to_bytes(),
+            PeerMessage::SuggestPiece { index: 2 }.to_bytes(),
+            PeerMessage::SuggestPiece { index: 2 }.to_bytes(),
+        ]
+        .concat();
+        let mut connection = connection_receiving(bytes).await;
+        connection.fast_extension = true;
+        for _ in 0..3 {
+            connection.read_message().await.unwrap();
+        }
+        assert!(connection.allowed_fast_pieces.contains(&4));
+        assert_eq!(connection.suggested_pieces, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn allowed_fast_pieces_can_be_requested_while_choked() {
+        let bytes = [
+            PeerMessage::AllowedFast { index: 4 }.to_bytes(),
+            PeerMessage::Unchoke.to_bytes(),
+            PeerMessage::Choke.to_bytes(),
+        ]
+        .concat();
+        let mut connection = connection_receiving(bytes).await;
+        connection.fast_extension = true;
+        assert!(!connection.can_request(4));
+
+        connection.read_message().await.unwrap();
+        assert!(connection.can_request(4));
+        assert!(!connection.can_request(2));
+        connection.read_message().await.unwrap();
+        assert!(connection.can_request(2));
+        connection.read_message().await.unwrap();
+        assert!(connection.peer_choking);
+        assert!(connection.can_request(4));
+        assert!(!connection.can_request(2));
+    }
+
+    #[tokio::test]
+    async fn peers_having_none_only_have_the_pieces_they_announce_after() {
+        let bytes = [
+            PeerMessage::HaveNone.to_bytes(),
+            PeerMessage::Have { index: 3 }.to_bytes(),
+            PeerMessage::HaveAll.to_bytes(),
+        ]
+        .concat();
+        let mut connection = connection_receiving(bytes).await;
+        connection.fast_extension = true;
+
+        connection.read_message().await.unwrap();
+        assert!(!connection.has_piece(3));
+        connection.read_message().await.unwrap();
+        assert!(connection.has_piece(3));
+        assert!(!connection.has_piece(0));
+        connection.read_message().await.unwrap();
+        assert!(connection.has_piece(0));
+    }
+
+    #[tokio::test]
+    async fn allowed_fast_pieces_are_ignored_without_the_fast_extension() {
+        let bytes = PeerMessage::AllowedFast { index: 4 }.to_bytes();
+        let mut connection = connection_receiving(bytes).await;
+        connection.read_message().await.unwrap();
+        assert!(connection.allowed_fast_pieces.is_empty());
+    }
+}
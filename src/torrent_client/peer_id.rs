@@ -0,0 +1,64 @@
+use std::fmt::{self, Display, Formatter};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::torrent_client::error::{Error, PeerError};
+
+pub(crate) const PEER_ID_LENGTH: usize = 20;
+const DEFAULT_CLIENT_PREFIX: &str = "-XX0100-";
+
+/// The 20 bytes a peer is known by in a swarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerId(pub [u8; PEER_ID_LENGTH]);
+
+impl PeerId {
+    /// An Azureus-style id: our client prefix followed by random characters
+    pub fn generate() -> Self {
+        Self::with_prefix(DEFAULT_CLIENT_PREFIX).expect("Default client prefix is valid")
+    }
+
+    /// `prefix` followed by random characters, like "-XX0100-" for Azureus-style ids
+    pub fn with_prefix(prefix: &str) -> Result<Self, Error> {
+        let prefix = prefix.as_bytes();
+        if prefix.len() > PEER_ID_LENGTH {
+            return Err(Error::PeerIdPrefixTooLong {
+                length: prefix.len(),
+            });
+        }
+
+        let mut bytes = [0u8; PEER_ID_LENGTH];
+        bytes[..prefix.len()].copy_from_slice(prefix);
+        let mut rng = rand::thread_rng();
+        for byte in bytes[prefix.len()..].iter_mut() {
+            *byte = rng.sample(Alphanumeric);
+        }
+        Ok(Self(bytes))
+    }
+
+    /// Fails unless there are exactly 20 bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PeerError> {
+        let bytes: [u8; PEER_ID_LENGTH] = bytes.try_into()?;
+        Ok(Self(bytes))
+    }
+
+    /// The raw bytes of the id
+    pub fn as_bytes(&self) -> &[u8; PEER_ID_LENGTH] {
+        &self.0
+    }
+
+    /// Every byte percent-encoded, for tracker announces
+    pub fn url_encoded(&self) -> String {
+        let mut str = String::new();
+        for byte in self.0 {
+            str.push('%');
+            str.push_str(&format!("{:02x}", byte));
+        }
+        str
+    }
+}
+
+impl Display for PeerId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
@@ -1,41 +1,207 @@
-use std::fmt::{Display, Formatter, Result};
+use std::fmt::{self, Display, Formatter};
 
-use crate::torrent_client::error::Error;
+use crate::torrent_client::{error::PeerError, PIECE_BLOCK_SIZE};
 
+const PEER_MESSAGE_CHOKE_ID: u8 = 0;
 const PEER_MESSAGE_UNCHOKE_ID: u8 = 1;
 const PEER_MESSAGE_INTERESTED_ID: u8 = 2;
+const PEER_MESSAGE_NOT_INTERESTED_ID: u8 = 3;
+const PEER_MESSAGE_HAVE_ID: u8 = 4;
 const PEER_MESSAGE_BITFIELD_ID: u8 = 5;
 const PEER_MESSAGE_REQUEST_ID: u8 = 6;
 const PEER_MESSAGE_PIECE_ID: u8 = 7;
+const PEER_MESSAGE_CANCEL_ID: u8 = 8;
+// DHT (BEP 5)
+const PEER_MESSAGE_PORT_ID: u8 = 9;
+// Fast extension (BEP 6)
+const PEER_MESSAGE_SUGGEST_PIECE_ID: u8 = 13;
+const PEER_MESSAGE_HAVE_ALL_ID: u8 = 14;
+const PEER_MESSAGE_HAVE_NONE_ID: u8 = 15;
+const PEER_MESSAGE_REJECT_REQUEST_ID: u8 = 16;
+const PEER_MESSAGE_ALLOWED_FAST_ID: u8 = 17;
+// Extension protocol (BEP 10)
+const PEER_MESSAGE_EXTENDED_ID: u8 = 20;
+// BitTorrent v2 (BEP 52)
+const PEER_MESSAGE_HASH_REQUEST_ID: u8 = 21;
+const PEER_MESSAGE_HASHES_ID: u8 = 22;
+const PEER_MESSAGE_HASH_REJECT_ID: u8 = 23;
 
+const PIECES_ROOT_LENGTH: usize = 32;
+const HASH_REQUEST_BODY_LENGTH: usize = PIECES_ROOT_LENGTH + 16;
+// BEP 52 caps the hashes of a single request
+pub(crate) const MAX_HASHES_PER_REQUEST: u32 = 512;
+
+/// Identifies a range of hashes of a v2 file merkle tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRange {
+    /// The root of the merkle tree of the file
+    pub pieces_root: Vec<u8>,
+    /// The layer of the hashes, 0 being the leaves
+    pub base_layer: u32,
+    /// The offset of the first hash in the layer
+    pub index: u32,
+    /// The number of hashes
+    pub length: u32,
+    /// The number of uncle hashes proving the range against the root
+    pub proof_layers: u32,
+}
+
+/// A message of the peer wire protocol
 #[derive(Debug)]
 pub enum PeerMessage {
+    /// Keeps an idle connection open
+    KeepAlive,
+    /// The sender will not answer requests
+    Choke,
+    /// The sender will answer requests
     Unchoke,
+    /// The sender wants pieces of the receiver
     Interested,
+    /// The sender wants no pieces of the receiver
+    NotInterested,
+    /// The sender has a new piece
+    Have {
+        /// The index of the piece
+        index: u32,
+    },
+    /// The pieces the sender has, one bit per piece
     Bitfield {
-        bitfield: u8,
+        /// The bits, the highest bit of the first byte being piece 0
+        bitfield: Vec<u8>,
     },
+    /// Asks for a block of a piece
     Request {
+        /// The index of the piece
         index: u32,
+        /// The offset of the block in the piece
         begin: u32,
+        /// The length of the block
         length: u32,
     },
+    /// A block of a piece
     Piece {
+        /// The index of the piece
         index: u32,
+        /// The offset of the block in the piece
         begin: u32,
+        /// The data of the block
         block: Vec<u8>,
     },
+    /// Withdraws a request
+    Cancel {
+        /// The index of the piece
+        index: u32,
+        /// The offset of the block in the piece
+        begin: u32,
+        /// The length of the block
+        length: u32,
+    },
+    /// The port of the DHT node of the sender (BEP 5)
+    Port {
+        /// The UDP port
+        port: u16,
+    },
+    /// Suggests a piece to download (BEP 6)
+    SuggestPiece {
+        /// The index of the piece
+        index: u32,
+    },
+    /// The sender has every piece (BEP 6)
+    HaveAll,
+    /// The sender has no piece (BEP 6)
+    HaveNone,
+    /// Refuses a request (BEP 6)
+    RejectRequest {
+        /// The index of the piece
+        index: u32,
+        /// The offset of the block in the piece
+        begin: u32,
+        /// The length of the block
+        length: u32,
+    },
+    /// A piece the receiver may request while choked (BEP 6)
+    AllowedFast {
+        /// The index of the piece
+        index: u32,
+    },
+    /// A message of the extension protocol (BEP 10)
+    Extended {
+        /// The extension message id, 0 being the extension handshake
+        id: u8,
+        /// The bencoded payload
+        payload: Vec<u8>,
+    },
+    /// Asks for a range of merkle tree hashes (BEP 52)
+    HashRequest {
+        /// The requested hashes
+        range: HashRange,
+    },
+    /// A range of merkle tree hashes followed by their proof (BEP 52)
+    Hashes {
+        /// The range the hashes answer
+        range: HashRange,
+        /// The concatenated 32 byte hashes
+        hashes: Vec<u8>,
+    },
+    /// Refuses a hash request (BEP 52)
+    HashReject {
+        /// The refused range
+        range: HashRange,
+    },
+    /// A message we do not use, its body is dropped
+    Unknown {
+        /// The message id
+        id: u8,
+    },
 }
 
 impl Display for PeerMessage {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            PeerMessage::Unchoke
+            PeerMessage::KeepAlive
+            | PeerMessage::Choke
+            | PeerMessage::Unchoke
             | PeerMessage::Interested
-            | PeerMessage::Bitfield { .. }
-            | PeerMessage::Request { .. } => {
+            | PeerMessage::NotInterested
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone
+            | PeerMessage::Request { .. }
+            | PeerMessage::Cancel { .. }
+            | PeerMessage::RejectRequest { .. } => {
                 write!(f, "{:?}", self)
             }
+            PeerMessage::HashRequest { range } => write!(
+                f,
+                "HashRequest (base layer: {}, index: {}, length: {})",
+                range.base_layer, range.index, range.length
+            ),
+            PeerMessage::HashReject { range } => write!(
+                f,
+                "HashReject (base layer: {}, index: {}, length: {})",
+                range.base_layer, range.index, range.length
+            ),
+            PeerMessage::Hashes { range, hashes } => write!(
+                f,
+                "Hashes (base layer: {}, index: {}, length: {}, hashes length: {})",
+                range.base_layer,
+                range.index,
+                range.length,
+                hashes.len()
+            ),
+            PeerMessage::Have { index } => write!(f, "Have (index: {})", index),
+            PeerMessage::Port { port } => write!(f, "Port ({port})"),
+            PeerMessage::Unknown { id } => write!(f, "Unknown (id: {id})"),
+            PeerMessage::SuggestPiece { index } => write!(f, "SuggestPiece (index: {})", index),
+            PeerMessage::AllowedFast { index } => write!(f, "AllowedFast (index: {})", index),
+            PeerMessage::Extended { id, payload } => write!(
+                f,
+                "Extended (id: {}, payload length: {})",
+                id,
+                payload.len()
+            ),
+            PeerMessage::Bitfield { bitfield } => {
+                write!(f, "Bitfield (length: {})", bitfield.len())
+            }
             PeerMessage::Piece {
                 index,
                 begin,
@@ -52,69 +218,230 @@ impl Display for PeerMessage {
 }
 
 impl PeerMessage {
-    pub fn id(&self) -> u8 {
+    /// The message id. Keep-alives have no id, they are just an empty
+    /// length prefix.
+    pub fn id(&self) -> Option<u8> {
         match self {
-            Self::Unchoke => PEER_MESSAGE_UNCHOKE_ID,
-            Self::Interested => PEER_MESSAGE_INTERESTED_ID,
-            Self::Bitfield { .. } => PEER_MESSAGE_BITFIELD_ID,
-            Self::Request { .. } => PEER_MESSAGE_REQUEST_ID,
-            Self::Piece { .. } => PEER_MESSAGE_PIECE_ID,
+            Self::KeepAlive => None,
+            Self::Choke => Some(PEER_MESSAGE_CHOKE_ID),
+            Self::Unchoke => Some(PEER_MESSAGE_UNCHOKE_ID),
+            Self::Interested => Some(PEER_MESSAGE_INTERESTED_ID),
+            Self::NotInterested => Some(PEER_MESSAGE_NOT_INTERESTED_ID),
+            Self::Have { .. } => Some(PEER_MESSAGE_HAVE_ID),
+            Self::Bitfield { .. } => Some(PEER_MESSAGE_BITFIELD_ID),
+            Self::Request { .. } => Some(PEER_MESSAGE_REQUEST_ID),
+            Self::Piece { .. } => Some(PEER_MESSAGE_PIECE_ID),
+            Self::Cancel { .. } => Some(PEER_MESSAGE_CANCEL_ID),
+            Self::Port { .. } => Some(PEER_MESSAGE_PORT_ID),
+            Self::SuggestPiece { .. } => Some(PEER_MESSAGE_SUGGEST_PIECE_ID),
+            Self::HaveAll => Some(PEER_MESSAGE_HAVE_ALL_ID),
+            Self::HaveNone => Some(PEER_MESSAGE_HAVE_NONE_ID),
+            Self::RejectRequest { .. } => Some(PEER_MESSAGE_REJECT_REQUEST_ID),
+            Self::AllowedFast { .. } => Some(PEER_MESSAGE_ALLOWED_FAST_ID),
+            Self::Extended { .. } => Some(PEER_MESSAGE_EXTENDED_ID),
+            Self::HashRequest { .. } => Some(PEER_MESSAGE_HASH_REQUEST_ID),
+            Self::Hashes { .. } => Some(PEER_MESSAGE_HASHES_ID),
+            Self::HashReject { .. } => Some(PEER_MESSAGE_HASH_REJECT_ID),
+            Self::Unknown { id } => Some(*id),
         }
     }
+
+    /// Length prefix of the longest message a peer has a reason to send: a piece
+    /// block, a full reply to a hash request, or the bitfield of the torrent
+    pub fn max_length(pieces_count: usize) -> usize {
+        let piece_length = 1 + 8 + PIECE_BLOCK_SIZE as usize;
+        let hashes_length =
+            1 + HASH_REQUEST_BODY_LENGTH + MAX_HASHES_PER_REQUEST as usize * PIECES_ROOT_LENGTH;
+        let bitfield_length = 1 + pieces_count.div_ceil(8);
+        piece_length.max(hashes_length).max(bitfield_length)
+    }
 }
 
 impl PeerMessage {
-    pub fn from_bytes(id: u8, body: &[u8]) -> anyhow::Result<Self> {
+    /// Parses the body of the message with the given id
+    pub fn from_bytes(id: u8, body: &[u8]) -> Result<Self, PeerError> {
         match id {
+            PEER_MESSAGE_CHOKE_ID => Ok(Self::Choke),
             PEER_MESSAGE_UNCHOKE_ID => Ok(Self::Unchoke),
-            PEER_MESSAGE_BITFIELD_ID => Ok(Self::Bitfield { bitfield: body[0] }),
+            PEER_MESSAGE_INTERESTED_ID => Ok(Self::Interested),
+            PEER_MESSAGE_NOT_INTERESTED_ID => Ok(Self::NotInterested),
+            PEER_MESSAGE_HAVE_ID => Ok(Self::Have {
+                index: Self::get_index_from_bytes(body)?,
+            }),
+            PEER_MESSAGE_BITFIELD_ID => Ok(Self::Bitfield {
+                bitfield: body.to_vec(),
+            }),
+            PEER_MESSAGE_REQUEST_ID => {
+                let (index, begin, length) = Self::get_request_from_bytes(body)?;
+                Ok(Self::Request {
+                    index,
+                    begin,
+                    length,
+                })
+            }
             PEER_MESSAGE_PIECE_ID => Self::get_piece_from_bytes(body),
-            _ => Err(anyhow::Error::msg(Error::PeerMessageIdNotRecognized { id })),
+            PEER_MESSAGE_CANCEL_ID => {
+                let (index, begin, length) = Self::get_request_from_bytes(body)?;
+                Ok(Self::Cancel {
+                    index,
+                    begin,
+                    length,
+                })
+            }
+            PEER_MESSAGE_PORT_ID => Ok(Self::Port {
+                port: u16::from_be_bytes(body.get(0..2).unwrap_or_default().try_into()?),
+            }),
+            PEER_MESSAGE_SUGGEST_PIECE_ID => Ok(Self::SuggestPiece {
+                index: Self::get_index_from_bytes(body)?,
+            }),
+            PEER_MESSAGE_HAVE_ALL_ID => Ok(Self::HaveAll),
+            PEER_MESSAGE_HAVE_NONE_ID => Ok(Self::HaveNone),
+            PEER_MESSAGE_REJECT_REQUEST_ID => {
+                let (index, begin, length) = Self::get_request_from_bytes(body)?;
+                Ok(Self::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                })
+            }
+            PEER_MESSAGE_ALLOWED_FAST_ID => Ok(Self::AllowedFast {
+                index: Self::get_index_from_bytes(body)?,
+            }),
+            PEER_MESSAGE_EXTENDED_ID => {
+                let Some((&id, payload)) = body.split_first() else {
+                    return Err(PeerError::MessageBodyNotReadCorrect {
+                        expected: 1,
+                        actual: 0,
+                    });
+                };
+                Ok(Self::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                })
+            }
+            PEER_MESSAGE_HASH_REQUEST_ID => Ok(Self::HashRequest {
+                range: Self::get_hash_range_from_bytes(body)?,
+            }),
+            PEER_MESSAGE_HASHES_ID => Ok(Self::Hashes {
+                range: Self::get_hash_range_from_bytes(body)?,
+                hashes: body[HASH_REQUEST_BODY_LENGTH..].to_vec(),
+            }),
+            PEER_MESSAGE_HASH_REJECT_ID => Ok(Self::HashReject {
+                range: Self::get_hash_range_from_bytes(body)?,
+            }),
+            _ => Ok(Self::Unknown { id }),
         }
     }
 
-    pub fn to_bytes(&self) -> Option<Vec<u8>> {
-        match self {
-            Self::Interested => Some(Self::get_empty_message_bytes(self.id())),
+    /// Encodes the message with its length prefix
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let Some(id) = self.id() else {
+            // Keep-alive
+            return vec![0u8; 4];
+        };
+
+        let body = match self {
+            Self::Have { index } | Self::SuggestPiece { index } | Self::AllowedFast { index } => {
+                index.to_be_bytes().to_vec()
+            }
+            Self::Bitfield { bitfield } => bitfield.clone(),
             Self::Request {
                 index,
                 begin,
                 length,
-            } => Some(Self::get_request_message_bytes(
-                self.id(),
-                *index,
-                *begin,
-                *length,
-            )),
-            _ => None,
-        }
+            }
+            | Self::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | Self::RejectRequest {
+                index,
+                begin,
+                length,
+            } => [
+                index.to_be_bytes(),
+                begin.to_be_bytes(),
+                length.to_be_bytes(),
+            ]
+            .concat(),
+            Self::Piece {
+                index,
+                begin,
+                block,
+            } => [&index.to_be_bytes()[..], &begin.to_be_bytes(), block].concat(),
+            Self::Port { port } => port.to_be_bytes().to_vec(),
+            Self::Extended { id, payload } => [&[*id][..], payload].concat(),
+            Self::HashRequest { range } | Self::HashReject { range } => {
+                Self::get_hash_range_bytes(range)
+            }
+            Self::Hashes { range, hashes } => {
+                [Self::get_hash_range_bytes(range), hashes.clone()].concat()
+            }
+            _ => vec![],
+        };
+
+        Self::get_message_bytes(id, &body)
     }
 }
 
 impl PeerMessage {
-    fn get_empty_message_bytes(id: u8) -> Vec<u8> {
-        let message_length: u32 = 1;
-        let mut bytes = vec![0u8; 5];
-        bytes[0..4].copy_from_slice(&message_length.to_be_bytes());
-        bytes[4] = id;
+    fn get_message_bytes(id: u8, body: &[u8]) -> Vec<u8> {
+        let message_length = 1 + body.len() as u32;
+        let mut bytes = Vec::with_capacity(4 + message_length as usize);
+        bytes.extend_from_slice(&message_length.to_be_bytes());
+        bytes.push(id);
+        bytes.extend_from_slice(body);
         bytes
     }
 
-    fn get_request_message_bytes(id: u8, index: u32, begin: u32, length: u32) -> Vec<u8> {
-        let message_length: u32 = 13;
-        let mut bytes = vec![0u8; 17];
-        bytes[0..4].copy_from_slice(&message_length.to_be_bytes());
-        bytes[4] = id;
-        bytes[5..9].copy_from_slice(&index.to_be_bytes());
-        bytes[9..13].copy_from_slice(&begin.to_be_bytes());
-        bytes[13..17].copy_from_slice(&length.to_be_bytes());
-        bytes
+    fn get_index_from_bytes(bytes: &[u8]) -> Result<u32, PeerError> {
+        Ok(u32::from_be_bytes(
+            bytes.get(0..4).unwrap_or_default().try_into()?,
+        ))
     }
 
-    fn get_piece_from_bytes(bytes: &[u8]) -> anyhow::Result<PeerMessage> {
-        let index = u32::from_be_bytes(bytes[0..4].try_into()?);
-        let begin = u32::from_be_bytes(bytes[4..8].try_into()?);
-        let block = bytes[8..].to_vec();
+    fn get_request_from_bytes(bytes: &[u8]) -> Result<(u32, u32, u32), PeerError> {
+        let index = u32::from_be_bytes(bytes.get(0..4).unwrap_or_default().try_into()?);
+        let begin = u32::from_be_bytes(bytes.get(4..8).unwrap_or_default().try_into()?);
+        let length = u32::from_be_bytes(bytes.get(8..12).unwrap_or_default().try_into()?);
+        Ok((index, begin, length))
+    }
+
+    fn get_hash_range_bytes(range: &HashRange) -> Vec<u8> {
+        [
+            &range.pieces_root[..],
+            &range.base_layer.to_be_bytes(),
+            &range.index.to_be_bytes(),
+            &range.length.to_be_bytes(),
+            &range.proof_layers.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn get_hash_range_from_bytes(bytes: &[u8]) -> Result<HashRange, PeerError> {
+        if bytes.len() < HASH_REQUEST_BODY_LENGTH {
+            return Err(PeerError::MessageBodyNotReadCorrect {
+                expected: HASH_REQUEST_BODY_LENGTH,
+                actual: bytes.len(),
+            });
+        }
+        let (pieces_root, rest) = bytes.split_at(PIECES_ROOT_LENGTH);
+        let (base_layer, index, length) = Self::get_request_from_bytes(rest)?;
+        let proof_layers = u32::from_be_bytes(rest[12..16].try_into()?);
+        Ok(HashRange {
+            pieces_root: pieces_root.to_vec(),
+            base_layer,
+            index,
+            length,
+            proof_layers,
+        })
+    }
+
+    fn get_piece_from_bytes(bytes: &[u8]) -> Result<PeerMessage, PeerError> {
+        let index = u32::from_be_bytes(bytes.get(0..4).unwrap_or_default().try_into()?);
+        let begin = u32::from_be_bytes(bytes.get(4..8).unwrap_or_default().try_into()?);
+        let block = bytes.get(8..).unwrap_or_default().to_vec();
         Ok(Self::Piece {
             index,
             begin,
@@ -123,13 +450,68 @@ impl PeerMessage {
     }
 }
 
-impl PeerMessage {
-    pub fn get_expected_message_length(id: u8, message_length_field_value: usize) -> usize {
-        match id {
-            PEER_MESSAGE_UNCHOKE_ID | PEER_MESSAGE_BITFIELD_ID | PEER_MESSAGE_PIECE_ID => {
-                message_length_field_value - 1
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses a message back from its length prefixed encoding
+    fn round_trip(message: &PeerMessage) -> PeerMessage {
+        let bytes = message.to_bytes();
+        let length = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        assert_eq!(bytes.len(), 4 + length);
+        PeerMessage::from_bytes(bytes[4], &bytes[5..]).unwrap()
+    }
+
+    #[test]
+    fn fast_extension_messages_round_trip() {
+        assert!(matches!(
+            round_trip(&PeerMessage::HaveAll),
+            PeerMessage::HaveAll
+        ));
+        assert!(matches!(
+            round_trip(&PeerMessage::HaveNone),
+            PeerMessage::HaveNone
+        ));
+        assert!(matches!(
+            round_trip(&PeerMessage::SuggestPiece { index: 7 }),
+            PeerMessage::SuggestPiece { index: 7 }
+        ));
+        assert!(matches!(
+            round_trip(&PeerMessage::AllowedFast { index: 1313 }),
+            PeerMessage::AllowedFast { index: 1313 }
+        ));
+        assert!(matches!(
+            round_trip(&PeerMessage::RejectRequest {
+                index: 3,
+                begin: PIECE_BLOCK_SIZE,
+                length: PIECE_BLOCK_SIZE,
+            }),
+            PeerMessage::RejectRequest {
+                index: 3,
+                begin: PIECE_BLOCK_SIZE,
+                length: PIECE_BLOCK_SIZE,
             }
-            _ => 0,
-        }
+        ));
+    }
+
+    #[test]
+    fn fast_extension_messages_use_the_ids_of_the_specification() {
+        assert_eq!(PeerMessage::SuggestPiece { index: 0 }.id(), Some(0x0D));
+        assert_eq!(PeerMessage::HaveAll.id(), Some(0x0E));
+        assert_eq!(PeerMessage::HaveNone.id(), Some(0x0F));
+        assert_eq!(PeerMessage::HaveNone.to_bytes(), vec![0, 0, 0, 1, 0x0F]);
+        let reject = PeerMessage::RejectRequest {
+            index: 0,
+            begin: 0,
+            length: 0,
+        };
+        assert_eq!(reject.id(), Some(0x10));
+        assert_eq!(PeerMessage::AllowedFast { index: 0 }.id(), Some(0x11));
+    }
+
+    #[test]
+    fn truncated_fast_extension_messages_are_rejected() {
+        assert!(PeerMessage::from_bytes(PEER_MESSAGE_ALLOWED_FAST_ID, &[0, 0]).is_err());
+        assert!(PeerMessage::from_bytes(PEER_MESSAGE_REJECT_REQUEST_ID, &[0; 8]).is_err());
     }
 }
@@ -0,0 +1,130 @@
+use std::net::SocketAddr;
+
+use super::PIECE_BLOCK_SIZE;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BlockContributor {
+    pub begin: u32,
+    pub length: u32,
+    pub peer: SocketAddr,
+}
+
+#[derive(Debug)]
+pub struct PieceBuffer {
+    pub piece_index: u32,
+    pub bytes: Vec<u8>,
+    pub contributors: Vec<BlockContributor>,
+    received_blocks: Vec<bool>,
+}
+
+impl PieceBuffer {
+    pub fn new(piece_index: u32, piece_length: usize) -> Self {
+        let blocks_count = piece_length.div_ceil(PIECE_BLOCK_SIZE as usize);
+        Self {
+            piece_index,
+            bytes: vec![0u8; piece_length],
+            contributors: vec![],
+            received_blocks: vec![false; blocks_count],
+        }
+    }
+}
+
+impl PieceBuffer {
+    pub fn add_block(&mut self, begin: u32, block: &[u8], peer: SocketAddr) {
+        let start = begin as usize;
+        let end = (start + block.len()).min(self.bytes.len());
+        if start >= end || !start.is_multiple_of(PIECE_BLOCK_SIZE as usize) {
+            return;
+        }
+        self.bytes[start..end].copy_from_slice(&block[..end - start]);
+        self.received_blocks[start / PIECE_BLOCK_SIZE as usize] = true;
+        self.contributors.push(BlockContributor {
+            begin,
+            length: (end - start) as u32,
+            peer,
+        });
+    }
+
+    // Blocks not received yet, as (begin, length) pairs
+    pub fn missing_blocks(&self) -> Vec<(u32, u32)> {
+        self.received_blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, received)| !**received)
+            .map(|(block_index, _)| {
+                let begin = block_index * PIECE_BLOCK_SIZE as usize;
+                let length = (self.bytes.len() - begin).min(PIECE_BLOCK_SIZE as usize);
+                (begin as u32, length as u32)
+            })
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received_blocks.iter().all(|received| *received)
+    }
+
+    pub fn contributing_peers(&self) -> Vec<SocketAddr> {
+        self.peers_of(|_| true)
+    }
+
+    // Peers that sent one of the blocks starting at the given offsets
+    pub fn contributing_peers_of(&self, blocks: &[u32]) -> Vec<SocketAddr> {
+        self.peers_of(|contributor| blocks.contains(&contributor.begin))
+    }
+
+    fn peers_of(&self, filter: impl Fn(&BlockContributor) -> bool) -> Vec<SocketAddr> {
+        let mut peers: Vec<SocketAddr> = vec![];
+        for contributor in self
+            .contributors
+            .iter()
+            .filter(|contributor| filter(contributor))
+        {
+            if !peers.contains(&contributor.peer) {
+                peers.push(contributor.peer);
+            }
+        }
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn filled_buffer() -> PieceBuffer {
+        let block = vec![0u8; PIECE_BLOCK_SIZE as usize];
+        let mut buffer = PieceBuffer::new(0, 3 * PIECE_BLOCK_SIZE as usize);
+        buffer.add_block(0, &block, peer(1));
+        buffer.add_block(PIECE_BLOCK_SIZE, &block, peer(2));
+        buffer.add_block(2 * PIECE_BLOCK_SIZE, &block, peer(1));
+        buffer
+    }
+
+    #[test]
+    fn every_contributor_of_a_v1_piece_is_blamed_once() {
+        let buffer = filled_buffer();
+        assert!(buffer.is_complete());
+        assert_eq!(buffer.contributing_peers(), vec![peer(1), peer(2)]);
+    }
+
+    #[test]
+    fn only_senders_of_bad_blocks_are_blamed_when_known() {
+        let buffer = filled_buffer();
+        assert_eq!(
+            buffer.contributing_peers_of(&[PIECE_BLOCK_SIZE]),
+            vec![peer(2)]
+        );
+        assert!(buffer.contributing_peers_of(&[]).is_empty());
+    }
+
+    #[test]
+    fn missing_blocks_are_re_requested_with_the_last_one_short() {
+        let mut buffer = PieceBuffer::new(0, PIECE_BLOCK_SIZE as usize + 10);
+        buffer.add_block(0, &vec![0u8; PIECE_BLOCK_SIZE as usize], peer(1));
+        assert_eq!(buffer.missing_blocks(), vec![(PIECE_BLOCK_SIZE, 10)]);
+    }
+}
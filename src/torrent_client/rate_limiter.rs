@@ -0,0 +1,35 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::{self, Instant};
+
+// Spaces out downloads so that everything sharing the limiter stays under a byte rate
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    // When the bytes reserved so far are paid for
+    next_free_at: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            next_free_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Waits for the bytes reserved before, then reserves `bytes` more
+    pub async fn acquire(&self, bytes: usize) {
+        let start_at = {
+            let mut next_free_at = self
+                .next_free_at
+                .lock()
+                .expect("Rate limiter lock poisoned");
+            let start_at = (*next_free_at).max(Instant::now());
+            *next_free_at =
+                start_at + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+            start_at
+        };
+        time::sleep_until(start_at).await;
+    }
+}
@@ -0,0 +1,501 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    net::Ipv4Addr,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, watch, Semaphore},
+    task::JoinHandle,
+};
+
+use super::{
+    dht::{Dht, BOOTSTRAP_NODES},
+    error::Error,
+    events::{Event, EventKind, Events},
+    handshake_message::HandshakeMessage,
+    peer_connection::PeerConnection,
+    peer_id::PeerId,
+    rate_limiter::RateLimiter,
+    torrent_metainfo::TorrentMetainfo,
+    TorrentClient, DEFAULT_PORT,
+};
+
+const DEFAULT_MAX_CONNECTIONS: usize = 8;
+const INCOMING_PEERS_QUEUE_SIZE: usize = 4;
+
+type IncomingPeer = (PeerConnection, HandshakeMessage);
+type IncomingPeerRoutes = Arc<Mutex<HashMap<Vec<u8>, mpsc::Sender<IncomingPeer>>>>;
+
+/// Where a torrent of a [`Session`] stands
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentState {
+    /// Waiting for a connection slot
+    Queued,
+    /// Downloading pieces from a peer or web seed
+    Downloading {
+        /// The pieces downloaded and verified so far
+        pieces_downloaded: usize,
+        /// The pieces of the torrent
+        pieces_count: usize,
+    },
+    /// Paused by [`Session::pause`]
+    Paused,
+    /// Every piece was downloaded and written
+    Finished,
+    /// The download failed for good
+    Failed {
+        /// The error that stopped the download
+        reason: String,
+    },
+}
+
+impl TorrentState {
+    /// Whether the torrent finished or failed
+    pub fn is_done(&self) -> bool {
+        matches!(self, Self::Finished | Self::Failed { .. })
+    }
+}
+
+impl Display for TorrentState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Queued => write!(f, "Queued"),
+            Self::Downloading {
+                pieces_downloaded,
+                pieces_count,
+            } => write!(f, "Downloading ({pieces_downloaded}/{pieces_count} pieces)"),
+            Self::Paused => write!(f, "Paused"),
+            Self::Finished => write!(f, "Finished"),
+            Self::Failed { reason } => write!(f, "Failed: {reason}"),
+        }
+    }
+}
+
+/// Limits and listening port of a [`Session`]
+#[derive(Debug, Clone, Copy)]
+pub struct SessionOptions {
+    /// The port peers connect to, 0 lets the system pick a free port
+    pub port: u16,
+    /// Every downloading torrent holds one peer connection
+    pub max_connections: usize,
+    /// Bytes per second, over all torrents
+    pub max_download_rate: Option<u64>,
+    /// Whether to run a DHT node on the same port, over UDP
+    pub dht: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_download_rate: None,
+            dht: true,
+        }
+    }
+}
+
+/// Downloads many torrents at once behind one listening port and one peer id,
+/// within connection and bandwidth limits shared by all of them. Torrents are
+/// known by the hex info hash they announce with. Peers come from trackers,
+/// from the DHT node of the session and from connections on the listening port.
+pub struct Session {
+    port: u16,
+    peer_id: PeerId,
+    connection_slots: Arc<Semaphore>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    incoming_peer_routes: IncomingPeerRoutes,
+    torrents: HashMap<String, SessionTorrent>,
+    events: Events,
+    listener_task: JoinHandle<()>,
+    dht: Option<Dht>,
+    dht_tasks: Vec<JoinHandle<()>>,
+}
+
+struct SessionTorrent {
+    swarm_hashes: Vec<Vec<u8>>,
+    paused: watch::Sender<bool>,
+    state: watch::Receiver<TorrentState>,
+    task: JoinHandle<()>,
+}
+
+impl Session {
+    /// Starts listening for peers on the port of the options
+    pub async fn bind(options: SessionOptions) -> Result<Self, Error> {
+        let listen_failed = |source| Error::ListenFailed {
+            port: options.port,
+            source,
+        };
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, options.port))
+            .await
+            .map_err(listen_failed)?;
+        let port = listener.local_addr().map_err(listen_failed)?.port();
+        let incoming_peer_routes = IncomingPeerRoutes::default();
+        let events = Events::default();
+        let listener_task = tokio::spawn(Self::accept_peers(
+            listener,
+            incoming_peer_routes.clone(),
+            events.clone(),
+        ));
+
+        // Torrents still get peers from trackers without a DHT node
+        let dht = match options.dht {
+            true => match Dht::bind(port, events.clone()).await {
+                Ok(dht) => Some(dht),
+                Err(error) => {
+                    events.warn(format!("Could not start the DHT node: {error}"));
+                    None
+                }
+            },
+            false => None,
+        };
+        let dht_tasks = match &dht {
+            Some(dht) => {
+                let bootstrap_nodes = BOOTSTRAP_NODES.map(String::from).to_vec();
+                vec![
+                    tokio::spawn(dht.clone().receive_messages()),
+                    tokio::spawn(dht.clone().join_network(bootstrap_nodes)),
+                ]
+            }
+            None => vec![],
+        };
+
+        Ok(Self {
+            port,
+            peer_id: PeerId::generate(),
+            connection_slots: Arc::new(Semaphore::new(options.max_connections.max(1))),
+            rate_limiter: options
+                .max_download_rate
+                .map(|bytes_per_second| Arc::new(RateLimiter::new(bytes_per_second))),
+            incoming_peer_routes,
+            torrents: HashMap::new(),
+            events,
+            listener_task,
+            dht,
+            dht_tasks,
+        })
+    }
+
+    /// Sets the peer id of the session, applies to the torrents added afterwards
+    pub fn with_peer_id(mut self, peer_id: PeerId) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    /// The port the session listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Events of every torrent in the session, tagged with their info hash
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Calls `callback` on every event of every torrent in the session
+    pub fn on_event(&self, callback: impl Fn(&Event) + Send + Sync + 'static) {
+        self.events.on_event(callback);
+    }
+}
+
+// Torrents management
+impl Session {
+    /// Starts downloading a torrent into `output_path`, returns its info hash
+    pub fn add(
+        &mut self,
+        torrent_metainfo: TorrentMetainfo,
+        output_path: impl Into<PathBuf>,
+    ) -> Result<String, Error> {
+        let info_hash = hex::encode(torrent_metainfo.info.swarm_hash_bytes()?);
+        if self.torrents.contains_key(&info_hash) {
+            return Err(Error::TorrentAlreadyAdded { info_hash });
+        }
+
+        let swarm_hashes = torrent_metainfo.info.swarm_hashes_bytes()?;
+        let (incoming_peers_sender, incoming_peers) = mpsc::channel(INCOMING_PEERS_QUEUE_SIZE);
+        {
+            let mut routes = self.lock_routes();
+            for swarm_hash in &swarm_hashes {
+                routes.insert(swarm_hash.clone(), incoming_peers_sender.clone());
+            }
+        }
+
+        let name = torrent_metainfo.info.name.clone();
+        let (paused_sender, paused) = watch::channel(false);
+        let (state_sender, state) = watch::channel(TorrentState::Queued);
+        let task = SessionTask {
+            client: TorrentClient::new(torrent_metainfo)
+                .with_peer_id(self.peer_id)
+                .with_port(self.port)
+                .with_events(&self.events)
+                .with_rate_limiter(self.rate_limiter.clone())
+                .with_dht(self.dht.clone()),
+            output_path: output_path.into(),
+            paused,
+            state: state_sender,
+            incoming_peers,
+            connection_slots: self.connection_slots.clone(),
+        };
+
+        self.torrents.insert(
+            info_hash.clone(),
+            SessionTorrent {
+                swarm_hashes,
+                paused: paused_sender,
+                state,
+                task: tokio::spawn(task.run()),
+            },
+        );
+        self.events
+            .for_torrent(Some(info_hash.clone()))
+            .emit(EventKind::TorrentAdded { name });
+        Ok(info_hash)
+    }
+
+    /// Stops the torrent for good, what was downloaded is dropped
+    pub fn remove(&mut self, info_hash: &str) -> Result<(), Error> {
+        let torrent = self
+            .torrents
+            .remove(info_hash)
+            .ok_or_else(|| Self::not_found_error(info_hash))?;
+        torrent.task.abort();
+
+        let mut routes = self.lock_routes();
+        for swarm_hash in &torrent.swarm_hashes {
+            routes.remove(swarm_hash);
+        }
+        Ok(())
+    }
+
+    /// Pauses a torrent. Takes effect between pieces, the connection slot is
+    /// given back.
+    pub fn pause(&self, info_hash: &str) -> Result<(), Error> {
+        self.set_paused(info_hash, true)
+    }
+
+    /// Resumes a paused torrent
+    pub fn resume(&self, info_hash: &str) -> Result<(), Error> {
+        self.set_paused(info_hash, false)
+    }
+
+    /// The state of a torrent, None if it is not in the session
+    pub fn state(&self, info_hash: &str) -> Option<TorrentState> {
+        self.torrents
+            .get(info_hash)
+            .map(|torrent| torrent.state.borrow().clone())
+    }
+
+    /// The info hash and state of every torrent in the session
+    pub fn torrents(&self) -> Vec<(String, TorrentState)> {
+        let mut torrents: Vec<(String, TorrentState)> = self
+            .torrents
+            .iter()
+            .map(|(info_hash, torrent)| (info_hash.clone(), torrent.state.borrow().clone()))
+            .collect();
+        torrents.sort_by(|(a, _), (b, _)| a.cmp(b));
+        torrents
+    }
+
+    /// Returns once every torrent finished or failed, paused torrents keep it waiting
+    pub async fn wait(&self) {
+        for torrent in self.torrents.values() {
+            let mut state = torrent.state.clone();
+            // The sender only goes away with a removed torrent
+            let _ = state.wait_for(TorrentState::is_done).await;
+        }
+    }
+
+    fn set_paused(&self, info_hash: &str, paused: bool) -> Result<(), Error> {
+        let torrent = self
+            .torrents
+            .get(info_hash)
+            .ok_or_else(|| Self::not_found_error(info_hash))?;
+        torrent.paused.send_replace(paused);
+        Ok(())
+    }
+
+    fn lock_routes(&self) -> MutexGuard<'_, HashMap<Vec<u8>, mpsc::Sender<IncomingPeer>>> {
+        self.incoming_peer_routes
+            .lock()
+            .expect("Incoming peer routes lock poisoned")
+    }
+
+    fn not_found_error(info_hash: &str) -> Error {
+        Error::TorrentNotFound {
+            info_hash: info_hash.into(),
+        }
+    }
+}
+
+// Incoming peers
+impl Session {
+    // Peers tell which torrent they want in their handshake, unknown ones are dropped
+    async fn accept_peers(listener: TcpListener, routes: IncomingPeerRoutes, events: Events) {
+        loop {
+            let (stream, address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    events.warn(format!("Could not accept a peer: {error}"));
+                    continue;
+                }
+            };
+            let routes = routes.clone();
+            let events = events.clone();
+            tokio::spawn(async move {
+                let mut connection = PeerConnection::from_stream(stream, address, events.clone());
+                let handshake_message = match connection.receive_handshake().await {
+                    Ok(handshake_message) => handshake_message,
+                    Err(error) => {
+                        events.debug(format!("Handshake from {address} failed: {error}"));
+                        return;
+                    }
+                };
+                let route = routes
+                    .lock()
+                    .expect("Incoming peer routes lock poisoned")
+                    .get(&handshake_message.info_hash)
+                    .cloned();
+                match route {
+                    Some(sender) => {
+                        if sender.try_send((connection, handshake_message)).is_err() {
+                            events.debug(format!("Too many peers waiting, dropping {address}"));
+                        }
+                    }
+                    None => events.debug(format!(
+                        "Dropping {address}, its torrent is not in the session"
+                    )),
+                }
+            });
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.listener_task.abort();
+        self.dht_tasks.iter().for_each(JoinHandle::abort);
+        self.torrents
+            .values()
+            .for_each(|torrent| torrent.task.abort());
+    }
+}
+
+// One torrent of the session, downloading in its own task
+struct SessionTask {
+    client: TorrentClient,
+    output_path: PathBuf,
+    paused: watch::Receiver<bool>,
+    state: watch::Sender<TorrentState>,
+    incoming_peers: mpsc::Receiver<IncomingPeer>,
+    connection_slots: Arc<Semaphore>,
+}
+
+impl SessionTask {
+    async fn run(mut self) {
+        let state = match self.download().await {
+            Ok(()) => {
+                self.client.events.emit(EventKind::Finished);
+                TorrentState::Finished
+            }
+            Err(error) => {
+                let reason = error.to_string();
+                self.client.events.emit(EventKind::Failed {
+                    reason: reason.clone(),
+                });
+                TorrentState::Failed { reason }
+            }
+        };
+        self.state.send_replace(state);
+    }
+
+    async fn download(&mut self) -> Result<(), Error> {
+        loop {
+            if *self.paused.borrow() {
+                self.state.send_replace(TorrentState::Paused);
+                // The sender only goes away with the session
+                self.paused
+                    .wait_for(|paused| !paused)
+                    .await
+                    .map_err(|_| Error::SessionClosed)?;
+            }
+
+            self.state.send_replace(TorrentState::Queued);
+            let _connection_slot = self
+                .connection_slots
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|_| Error::SessionClosed)?;
+            let is_complete = self.download_until_paused().await;
+            // The peer may already be gone, that does not stop the torrent
+            if self.client.connection.is_some() {
+                if let Err(error) = self.client.disconnect().await {
+                    self.client
+                        .events
+                        .warn(format!("Could not disconnect from the peer: {error}"));
+                }
+            }
+            if is_complete? {
+                break;
+            }
+        }
+
+        let output_path = self.output_path.to_string_lossy().to_string();
+        self.client.save(&output_path).await
+    }
+
+    // Returns true once every piece is there, false when paused
+    async fn download_until_paused(&mut self) -> Result<bool, Error> {
+        self.update_progress();
+        // Web seeds can serve the whole torrent, a peer connection is optional then
+        if let Err(error) = self.client.join_swarm().await {
+            if self.client.web_seeds.is_empty() {
+                return Err(error);
+            }
+            self.client.events.warn(format!(
+                "Could not join the swarm ({error}), downloading from web seeds"
+            ));
+        }
+
+        loop {
+            if *self.paused.borrow() {
+                return Ok(false);
+            }
+            self.accept_incoming_peer().await;
+
+            if self.client.download_next_piece().await?.is_none() {
+                return Ok(true);
+            }
+            self.update_progress();
+        }
+    }
+
+    // Without a connection of its own, the torrent downloads from a peer that came to us
+    async fn accept_incoming_peer(&mut self) {
+        while let Ok((connection, handshake_message)) = self.incoming_peers.try_recv() {
+            let address = connection.address;
+            if self.client.connection.is_some() {
+                self.client
+                    .events
+                    .debug(format!("Already connected, dropping {address}"));
+                continue;
+            }
+            if let Err(error) = self.client.accept_peer(connection, handshake_message).await {
+                self.client
+                    .events
+                    .warn(format!("Could not accept {address}: {error}"));
+            }
+        }
+    }
+
+    fn update_progress(&self) {
+        self.state.send_replace(TorrentState::Downloading {
+            pieces_downloaded: self.client.pieces_downloaded(),
+            pieces_count: self.client.torrent_metainfo.info.pieces_count(),
+        });
+    }
+}
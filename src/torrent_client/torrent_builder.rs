@@ -0,0 +1,440 @@
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha1::{Digest, Sha1};
+
+use super::{
+    error::MetainfoError,
+    torrent_metainfo::{File, Info, TorrentMetainfo, UrlList},
+};
+
+const MIN_PIECE_LENGTH: usize = 16_384; // 16 KiB
+const MAX_PIECE_LENGTH: usize = 16_777_216; // 16 MiB
+const TARGET_PIECES_COUNT: usize = 1_500;
+
+/// Builds the metainfo of a file or a directory, hashing its pieces in parallel
+pub struct TorrentMetainfoBuilder {
+    path: PathBuf,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    web_seeds: Vec<String>,
+    source: Option<String>,
+    piece_length: Option<usize>,
+}
+
+impl TorrentMetainfoBuilder {
+    /// A builder for the file or directory at `path`, dated now
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .ok();
+        Self {
+            path: path.into(),
+            announce_list: vec![],
+            comment: None,
+            created_by: None,
+            creation_date,
+            private: false,
+            web_seeds: vec![],
+            source: None,
+            piece_length: None,
+        }
+    }
+
+    /// Adds a tracker. Each tracker gets its own tier, the first one is also
+    /// the `announce` tracker.
+    pub fn tracker(mut self, url: &str) -> Self {
+        self.announce_list.push(vec![url.to_string()]);
+        self
+    }
+
+    /// Sets the comment of the torrent
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    /// Sets the program the torrent is credited to
+    pub fn created_by(mut self, created_by: &str) -> Self {
+        self.created_by = Some(created_by.to_string());
+        self
+    }
+
+    /// Sets the creation date in seconds since the Unix epoch. None leaves it
+    /// out, making the torrent reproducible.
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    /// Marks the torrent private, restricting peers to those of its trackers
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Adds a web seed to the `url-list` of the torrent
+    pub fn web_seed(mut self, url: &str) -> Self {
+        self.web_seeds.push(url.to_string());
+        self
+    }
+
+    /// Sets the source, giving the torrent an info hash of its own
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    /// Sets the piece length, a power of two of at least 16 KiB. It is picked
+    /// from the total length otherwise.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+}
+
+impl TorrentMetainfoBuilder {
+    /// Reads and hashes the files into the metainfo
+    pub fn build(self) -> Result<TorrentMetainfo, MetainfoError> {
+        let name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| Self::creation_error("path has no file name"))?;
+
+        // Files are hashed as one stream, in path order
+        let is_directory = self.path.is_dir();
+        let mut sources = vec![];
+        match is_directory {
+            true => Self::collect_files(&self.path, &mut vec![], &mut sources)?,
+            false => sources.push((self.path.clone(), vec![], fs::metadata(&self.path)?.len())),
+        }
+        let total_length: usize = sources.iter().map(|(_, _, length)| *length as usize).sum();
+        if total_length == 0 {
+            return Err(Self::creation_error("nothing to hash"));
+        }
+
+        let piece_length = match self.piece_length {
+            Some(piece_length) if Self::is_valid_piece_length(piece_length) => piece_length,
+            Some(piece_length) => {
+                return Err(Self::creation_error(&format!(
+                    "piece length {piece_length} is not a power of two of at least {MIN_PIECE_LENGTH} bytes"
+                )))
+            }
+            None => Self::default_piece_length(total_length),
+        };
+        let file_spans: Vec<(PathBuf, usize)> = sources
+            .iter()
+            .map(|(path, _, length)| (path.clone(), *length as usize))
+            .collect();
+        let pieces = Self::hash_pieces(&file_spans, total_length, piece_length)?;
+
+        let (length, files) = match is_directory {
+            true => (
+                0,
+                sources
+                    .into_iter()
+                    .map(|(_, path, length)| File::new(length as usize, path))
+                    .collect(),
+            ),
+            false => (total_length, vec![]),
+        };
+        let mut info = Info {
+            length,
+            files,
+            name,
+            piece_length,
+            pieces,
+            meta_version: None,
+            file_tree: None,
+            private: self.private.then_some(1),
+            source: self.source,
+            raw_bytes: vec![],
+        };
+        info.raw_bytes = serde_bencode::to_bytes(&info)?;
+
+        let url_list = match self.web_seeds.len() {
+            0 => None,
+            1 => Some(UrlList::One(self.web_seeds[0].clone())),
+            _ => Some(UrlList::Many(self.web_seeds)),
+        };
+        let announce = self
+            .announce_list
+            .first()
+            .map(|tier| tier[0].clone())
+            .unwrap_or_default();
+        // A single tracker needs no announce-list
+        let announce_list = match self.announce_list.len() {
+            1 => vec![],
+            _ => self.announce_list,
+        };
+
+        Ok(TorrentMetainfo {
+            announce,
+            announce_list,
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            info,
+            piece_layers: Default::default(),
+            url_list,
+            httpseeds: vec![],
+        })
+    }
+
+    // Regular files below a directory, with their paths relative to it
+    fn collect_files(
+        directory: &Path,
+        relative_path: &mut Vec<String>,
+        sources: &mut Vec<(PathBuf, Vec<String>, u64)>,
+    ) -> Result<(), MetainfoError> {
+        let mut entries: Vec<fs::DirEntry> = fs::read_dir(directory)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let file_type = entry.file_type()?;
+            relative_path.push(entry.file_name().to_string_lossy().to_string());
+            if file_type.is_dir() {
+                Self::collect_files(&entry.path(), relative_path, sources)?;
+            } else if file_type.is_file() {
+                sources.push((entry.path(), relative_path.clone(), entry.metadata()?.len()));
+            }
+            relative_path.pop();
+        }
+        Ok(())
+    }
+
+    fn is_valid_piece_length(piece_length: usize) -> bool {
+        piece_length.is_power_of_two() && piece_length >= MIN_PIECE_LENGTH
+    }
+
+    // Power of two giving about TARGET_PIECES_COUNT pieces
+    fn default_piece_length(total_length: usize) -> usize {
+        (total_length / TARGET_PIECES_COUNT)
+            .next_power_of_two()
+            .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+    }
+
+    // Every thread hashes its own share of the pieces
+    fn hash_pieces(
+        file_spans: &[(PathBuf, usize)],
+        total_length: usize,
+        piece_length: usize,
+    ) -> Result<Vec<u8>, MetainfoError> {
+        let pieces_count = total_length.div_ceil(piece_length);
+        let threads_count = thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1)
+            .min(pieces_count);
+        let pieces_per_thread = pieces_count.div_ceil(threads_count);
+
+        let hashes: Vec<Vec<u8>> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..pieces_count)
+                .step_by(pieces_per_thread)
+                .map(|first_piece| {
+                    let last_piece = (first_piece + pieces_per_thread).min(pieces_count);
+                    scope.spawn(move || {
+                        let mut hashes = vec![];
+                        for piece_index in first_piece..last_piece {
+                            let start = piece_index * piece_length;
+                            let end = (start + piece_length).min(total_length);
+                            let bytes = Self::read_range(file_spans, start, end)?;
+                            hashes.extend_from_slice(&Sha1::digest(bytes));
+                        }
+                        Ok::<_, MetainfoError>(hashes)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Hashing thread panicked"))
+                .collect::<Result<_, _>>()
+        })?;
+
+        Ok(hashes.concat())
+    }
+
+    // Reads a range of the files laid out one after the other
+    fn read_range(
+        file_spans: &[(PathBuf, usize)],
+        start: usize,
+        end: usize,
+    ) -> Result<Vec<u8>, MetainfoError> {
+        let mut bytes = Vec::with_capacity(end - start);
+        let mut file_start = 0;
+
+        for (path, length) in file_spans {
+            let file_end = file_start + length;
+            let range_start = start.max(file_start);
+            let range_end = end.min(file_end);
+            if range_start < range_end {
+                let mut file = fs::File::open(path)?;
+                file.seek(SeekFrom::Start((range_start - file_start) as u64))?;
+                let mut buffer = vec![0; range_end - range_start];
+                file.read_exact(&mut buffer)?;
+                bytes.extend_from_slice(&buffer);
+            }
+            file_start = file_end;
+        }
+        Ok(bytes)
+    }
+
+    fn creation_error(reason: &str) -> MetainfoError {
+        MetainfoError::CreationFailed {
+            reason: reason.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bytes differing from one piece to the next
+    fn contents(length: usize) -> Vec<u8> {
+        (0..length).map(|index| (index / 7) as u8).collect()
+    }
+
+    fn round_trip(metainfo: &TorrentMetainfo) -> TorrentMetainfo {
+        TorrentMetainfo::from_bytes(&metainfo.to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn single_file_torrents_round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data.bin");
+        let bytes = contents(2 * MIN_PIECE_LENGTH + 10);
+        fs::write(&path, &bytes).unwrap();
+
+        let metainfo = TorrentMetainfoBuilder::new(&path)
+            .tracker("http://tracker.test/announce")
+            .comment("test")
+            .creation_date(None)
+            .piece_length(MIN_PIECE_LENGTH)
+            .build()
+            .unwrap();
+        let parsed = round_trip(&metainfo);
+
+        assert_eq!(parsed.info.name, "data.bin");
+        assert_eq!(parsed.info.length, bytes.len());
+        assert_eq!(parsed.info.raw_bytes, metainfo.info.raw_bytes);
+        assert_eq!(
+            parsed.info.hash_bytes().unwrap(),
+            Sha1::digest(&metainfo.info.raw_bytes).to_vec()
+        );
+        let expected_hashes: Vec<String> = bytes
+            .chunks(MIN_PIECE_LENGTH)
+            .map(|piece| hex::encode(Sha1::digest(piece)))
+            .collect();
+        assert_eq!(parsed.info.pieces_hashes(), expected_hashes);
+        assert_eq!(parsed.trackers(), vec!["http://tracker.test/announce"]);
+        assert_eq!(parsed.comment.as_deref(), Some("test"));
+        assert_eq!(parsed.creation_date, None);
+    }
+
+    #[test]
+    fn directory_torrents_hash_their_files_as_one_stream() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("set");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let first = contents(MIN_PIECE_LENGTH + 5);
+        let second = contents(MIN_PIECE_LENGTH);
+        fs::write(root.join("a.bin"), &first).unwrap();
+        fs::write(root.join("sub").join("b.bin"), &second).unwrap();
+
+        let metainfo = TorrentMetainfoBuilder::new(&root)
+            .piece_length(MIN_PIECE_LENGTH)
+            .build()
+            .unwrap();
+        let parsed = round_trip(&metainfo);
+
+        let paths: Vec<Vec<String>> = parsed
+            .info
+            .files
+            .iter()
+            .map(|file| file.path.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec!["a.bin".to_string()],
+                vec!["sub".into(), "b.bin".into()]
+            ]
+        );
+        assert_eq!(parsed.info.total_length(), first.len() + second.len());
+        let expected_hashes: Vec<String> = [first, second]
+            .concat()
+            .chunks(MIN_PIECE_LENGTH)
+            .map(|piece| hex::encode(Sha1::digest(piece)))
+            .collect();
+        assert_eq!(parsed.info.pieces_hashes(), expected_hashes);
+        assert_eq!(
+            parsed.info.hash_bytes().unwrap(),
+            metainfo.info.hash_bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn private_source_and_web_seeds_round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data.bin");
+        fs::write(&path, contents(100)).unwrap();
+        let build = |source: &str| {
+            TorrentMetainfoBuilder::new(&path)
+                .private(true)
+                .source(source)
+                .web_seed("http://seed.test/data.bin")
+                .web_seed("http://mirror.test/data.bin")
+                .build()
+                .unwrap()
+        };
+
+        let metainfo = build("first");
+        let parsed = round_trip(&metainfo);
+        assert!(parsed.info.is_private());
+        assert_eq!(parsed.info.source.as_deref(), Some("first"));
+        assert_eq!(
+            parsed.web_seeds(),
+            vec!["http://seed.test/data.bin", "http://mirror.test/data.bin"]
+        );
+        // The source makes the info hash unique
+        assert_ne!(
+            parsed.info.hash_bytes().unwrap(),
+            build("second").info.hash_bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn piece_lengths_must_be_powers_of_two_of_at_least_16_kib() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data.bin");
+        fs::write(&path, contents(100)).unwrap();
+
+        for piece_length in [MIN_PIECE_LENGTH / 2, MIN_PIECE_LENGTH + 1, 0] {
+            let result = TorrentMetainfoBuilder::new(&path)
+                .piece_length(piece_length)
+                .build();
+            assert!(matches!(result, Err(MetainfoError::CreationFailed { .. })));
+        }
+        assert!(TorrentMetainfoBuilder::new(&path)
+            .piece_length(2 * MIN_PIECE_LENGTH)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn empty_inputs_are_rejected() {
+        let directory = tempfile::tempdir().unwrap();
+        let result = TorrentMetainfoBuilder::new(directory.path()).build();
+        assert!(matches!(result, Err(MetainfoError::CreationFailed { .. })));
+    }
+}
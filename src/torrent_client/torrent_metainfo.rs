@@ -1,35 +1,325 @@
+use std::collections::{BTreeMap, HashMap};
+
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use super::error::MetainfoError;
+use super::merkle::{self, MerkleHash, MERKLE_BLOCK_SIZE};
+use crate::bencode::parser;
 
 const PIECES_CHUNK_SIZE: usize = 20;
+const META_VERSION_2: u8 = 2;
 
+/// The content of a torrent file
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TorrentMetainfo {
+    /// The tracker to announce to. Torrents relying on web seeds or DHT may
+    /// have no tracker.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+    /// BEP 12 tracker tiers
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+    /// Free text from the creator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// The program that created the torrent
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    /// Seconds since the Unix epoch
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    /// The info dictionary, what the info hash is computed from
     pub info: Info,
+    /// v2 only: pieces root => concatenated piece layer hashes
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+    /// BEP 19 web seeds
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
+    /// BEP 17 HTTP seeds
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub httpseeds: Vec<String>,
+}
+
+/// `url-list` holds either a single URL or a list of them
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum UrlList {
+    /// A single URL
+    One(String),
+    /// A list of URLs
+    Many(Vec<String>),
 }
 
+/// The info dictionary of a torrent: its files and piece hashes
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Info {
+    /// The length of a v1 single file torrent
+    #[serde(default, skip_serializing_if = "is_zero")]
     pub length: usize,
+    /// v1 multi-file torrents list their files instead of a length
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<File>,
+    /// The file name, or the directory name of multi-file torrents
     pub name: String,
+    /// The length of every piece but the last ones
     #[serde(rename = "piece length")]
     pub piece_length: usize,
-    #[serde(with = "serde_bytes")]
+    /// The concatenated SHA-1 hashes of the v1 pieces
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
     pub pieces: Vec<u8>,
+    /// 2 for v2 and hybrid torrents (BEP 52)
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+    /// The v2 file tree, kept as bencode
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<Value>,
+    /// BEP 27: peers only come from the trackers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    /// Makes the info hash unique to a tracker or site
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The info dictionary as found in the torrent file, used for hashing
+    #[serde(skip)]
+    pub raw_bytes: Vec<u8>,
+}
+
+/// A file of a v1 multi-file torrent
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct File {
+    /// The length of the file
+    pub length: usize,
+    /// The path components of the file, under the torrent directory
+    pub path: Vec<String>,
+    /// BEP 47 attributes: p(adding), x (executable), h(idden), l (symlink)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    /// The target of a symlink, as path components
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<String>>,
+    /// The SHA-1 hash of the file (BEP 47)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<ByteBuf>,
+}
+
+/// A file of the file tree of a v2 torrent
+#[derive(Debug, Clone)]
+pub struct FileV2 {
+    /// The path components of the file
+    pub path: Vec<String>,
+    /// The length of the file
+    pub length: usize,
+    /// The root of the merkle tree of the file, None for empty files
+    pub pieces_root: Option<MerkleHash>,
+    /// Index of the first piece of the file, v2 pieces never span files
+    pub first_piece_index: u32,
+    /// BEP 47 attributes
+    pub attr: Option<String>,
+    /// The target of a symlink, as path components
+    pub symlink_path: Option<Vec<String>>,
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+impl File {
+    /// A file without attributes
+    pub fn new(length: usize, path: Vec<String>) -> Self {
+        Self {
+            length,
+            path,
+            attr: None,
+            symlink_path: None,
+            sha1: None,
+        }
+    }
+
+    fn has_attribute(&self, attribute: char) -> bool {
+        self.attr
+            .as_ref()
+            .is_some_and(|attr| attr.contains(attribute))
+    }
+
+    /// Padding files only align the next file on a piece boundary, they are never written
+    pub fn is_padding(&self) -> bool {
+        self.has_attribute('p')
+    }
+
+    /// Whether the file has the executable attribute
+    pub fn is_executable(&self) -> bool {
+        self.has_attribute('x')
+    }
+
+    /// Whether the file has the hidden attribute
+    pub fn is_hidden(&self) -> bool {
+        self.has_attribute('h')
+    }
+
+    /// Whether the file is a symlink
+    pub fn is_symlink(&self) -> bool {
+        self.has_attribute('l') && self.symlink_path.is_some()
+    }
+
+    /// The attributes of the file, spelled out
+    pub fn attributes(&self) -> String {
+        let mut attributes = vec![];
+        if self.is_padding() {
+            attributes.push("padding");
+        }
+        if self.is_executable() {
+            attributes.push("executable");
+        }
+        if self.is_hidden() {
+            attributes.push("hidden");
+        }
+        if self.is_symlink() {
+            attributes.push("symlink");
+        }
+        attributes.join(", ")
+    }
+}
+
+impl TorrentMetainfo {
+    /// Parses a torrent file, keeping the raw info dictionary for hashing
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        let mut torrent_metainfo: TorrentMetainfo = serde_bencode::from_bytes(bytes)?;
+
+        // The info hash covers the info dictionary exactly as found in the torrent file
+        let root = parser::parse(bytes)?;
+        if !matches!(root.value, parser::Value::Dict(_)) {
+            return Err(MetainfoError::NotValid {
+                reason: "torrent is not a dictionary".into(),
+            });
+        }
+        if let Some(info) = root.get(b"info") {
+            torrent_metainfo.info.raw_bytes = bytes[info.span.clone()].to_vec();
+        }
+
+        Ok(torrent_metainfo)
+    }
+
+    /// Encodes the metainfo as a torrent file
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MetainfoError> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    /// Every tracker, tiers flattened, the announce tracker first
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers = vec![];
+        let tiers = self.announce_list.iter().flatten();
+        for tracker in std::iter::once(&self.announce).chain(tiers) {
+            if !tracker.is_empty() && !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+        trackers
+    }
+
+    /// BEP 9 magnet link, with a v2 multihash (BEP 52) when the torrent has one
+    pub fn magnet_link(&self) -> Result<String, MetainfoError> {
+        let mut exact_topics = vec![];
+        if self.info.is_v1() {
+            exact_topics.push(format!("xt=urn:btih:{}", self.info.hash_hex()?));
+        }
+        if self.info.is_v2() {
+            exact_topics.push(format!("xt=urn:btmh:1220{}", self.info.hash_v2_hex()?));
+        }
+
+        let mut params = vec![("dn", self.info.name.clone())];
+        params.extend(self.trackers().into_iter().map(|tracker| ("tr", tracker)));
+        params.extend(self.web_seeds().into_iter().map(|url| ("ws", url)));
+        let encoded_params = serde_urlencoded::to_string(params)?;
+
+        Ok(format!(
+            "magnet:?{}&{encoded_params}",
+            exact_topics.join("&")
+        ))
+    }
+
+    /// The URLs of the BEP 19 web seeds
+    pub fn web_seeds(&self) -> Vec<String> {
+        let urls = match &self.url_list {
+            Some(UrlList::One(url)) => vec![url.clone()],
+            Some(UrlList::Many(urls)) => urls.clone(),
+            None => vec![],
+        };
+        urls.into_iter().filter(|url| !url.is_empty()).collect()
+    }
+
+    /// The URLs of the BEP 17 HTTP seeds
+    pub fn http_seeds(&self) -> Vec<String> {
+        self.httpseeds
+            .iter()
+            .filter(|url| !url.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    // Piece layer hashes of a v2 file, if present in the torrent
+    pub(crate) fn piece_layer(&self, pieces_root: &MerkleHash) -> Option<Vec<MerkleHash>> {
+        self.piece_layers
+            .get(serde_bytes::Bytes::new(pieces_root))
+            .map(|layer| merkle::hashes_from_bytes(layer))
+    }
+
+    pub(crate) fn set_piece_layer(&mut self, pieces_root: &MerkleHash, hashes: &[MerkleHash]) {
+        self.piece_layers.insert(
+            ByteBuf::from(pieces_root.to_vec()),
+            ByteBuf::from(hashes.concat()),
+        );
+    }
 }
 
 impl Info {
-    pub fn hash_bytes(&self) -> anyhow::Result<Vec<u8>> {
+    fn bytes_for_hashing(&self) -> Result<Vec<u8>, MetainfoError> {
+        match self.raw_bytes.is_empty() {
+            true => Ok(serde_bencode::to_bytes(self)?),
+            false => Ok(self.raw_bytes.clone()),
+        }
+    }
+
+    /// SHA-1 info hash of v1 and hybrid torrents
+    pub fn hash_bytes(&self) -> Result<Vec<u8>, MetainfoError> {
         let mut hasher = Sha1::new();
-        let bytes = serde_bencode::to_bytes(self)?;
+        let bytes = self.bytes_for_hashing()?;
         hasher.update(bytes);
         let bytes = hasher.finalize();
         let bytes_vec = bytes.to_vec();
         Ok(bytes_vec)
     }
 
-    pub fn hash_hex(&self) -> anyhow::Result<String> {
+    /// [`Info::hash_bytes`] in hex
+    pub fn hash_hex(&self) -> Result<String, MetainfoError> {
         let bytes = self.hash_bytes()?;
         let hash = bytes
             .iter()
@@ -38,16 +328,46 @@ impl Info {
         Ok(hash)
     }
 
-    pub fn hash_string(&self) -> anyhow::Result<String> {
-        let bytes = self.hash_bytes()?;
-        let mut str = String::new();
-        for byte in bytes {
-            str.push('%');
-            str.push_str(&format!("{:02x}", byte));
+    /// SHA-256 info hash of v2 torrents
+    pub fn hash_v2_bytes(&self) -> Result<Vec<u8>, MetainfoError> {
+        let bytes = self.bytes_for_hashing()?;
+        Ok(Sha256::digest(bytes).to_vec())
+    }
+
+    /// [`Info::hash_v2_bytes`] in hex
+    pub fn hash_v2_hex(&self) -> Result<String, MetainfoError> {
+        Ok(hex::encode(self.hash_v2_bytes()?))
+    }
+
+    /// The 20 bytes info hash used in handshakes and tracker announces.
+    /// v2-only torrents use the truncated SHA-256 hash.
+    pub fn swarm_hash_bytes(&self) -> Result<Vec<u8>, MetainfoError> {
+        if self.is_v1() {
+            return self.hash_bytes();
         }
-        Ok(str)
+        self.truncated_hash_v2_bytes()
     }
 
+    /// The info hashes of every swarm of the torrent. Hybrid torrents live in
+    /// both a v1 and a v2 swarm.
+    pub fn swarm_hashes_bytes(&self) -> Result<Vec<Vec<u8>>, MetainfoError> {
+        let mut hashes = vec![];
+        if self.is_v1() {
+            hashes.push(self.hash_bytes()?);
+        }
+        if self.is_v2() {
+            hashes.push(self.truncated_hash_v2_bytes()?);
+        }
+        Ok(hashes)
+    }
+
+    fn truncated_hash_v2_bytes(&self) -> Result<Vec<u8>, MetainfoError> {
+        let mut bytes = self.hash_v2_bytes()?;
+        bytes.truncate(PIECES_CHUNK_SIZE);
+        Ok(bytes)
+    }
+
+    /// The v1 piece hashes in hex
     pub fn pieces_hashes(&self) -> Vec<String> {
         let hashes: Vec<String> = self
             .pieces
@@ -62,7 +382,257 @@ impl Info {
         hashes
     }
 
+    /// The number of pieces of the torrent
     pub fn pieces_count(&self) -> usize {
-        self.pieces.len() / PIECES_CHUNK_SIZE
+        if self.is_v1() {
+            return self.pieces.len() / PIECES_CHUNK_SIZE;
+        }
+        self.files_v2()
+            .map(|files| {
+                files
+                    .iter()
+                    .map(|file| file.length.div_ceil(self.piece_length))
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    /// The length of every file together, padding files included
+    pub fn total_length(&self) -> usize {
+        if self.is_v1() {
+            return self.length_v1();
+        }
+        self.files_v2()
+            .map(|files| files.iter().map(|file| file.length).sum())
+            .unwrap_or(0)
+    }
+
+    /// The length of a piece, shorter than the piece length at the end of the data
+    pub fn piece_size(&self, piece_index: u32) -> usize {
+        let (file_length, piece_offset) = match self.is_v1() {
+            true => (self.length_v1(), piece_index as usize * self.piece_length),
+            false => match self.piece_location_v2(piece_index) {
+                Some((file, piece_in_file)) => {
+                    (file.length, piece_in_file as usize * self.piece_length)
+                }
+                None => return 0,
+            },
+        };
+        file_length
+            .saturating_sub(piece_offset)
+            .min(self.piece_length)
+    }
+
+    /// Offset of a piece within the files laid out one after the other
+    pub fn piece_offset(&self, piece_index: u32) -> usize {
+        if self.is_v1() {
+            return piece_index as usize * self.piece_length;
+        }
+        let mut file_offset = 0;
+        for file in self.files_v2().unwrap_or_default() {
+            let pieces_count = file.length.div_ceil(self.piece_length) as u32;
+            if (file.first_piece_index..file.first_piece_index + pieces_count)
+                .contains(&piece_index)
+            {
+                let piece_in_file = piece_index - file.first_piece_index;
+                return file_offset + piece_in_file as usize * self.piece_length;
+            }
+            file_offset += file.length;
+        }
+        file_offset
+    }
+}
+
+// Files related
+impl Info {
+    /// Files in the order their bytes appear in the pieces, padding files included
+    pub fn files(&self) -> Result<Vec<File>, MetainfoError> {
+        if self.is_v1() {
+            return match self.files.is_empty() {
+                true => Ok(vec![File::new(self.length, vec![self.name.clone()])]),
+                false => Ok(self.files.clone()),
+            };
+        }
+        let files = self
+            .files_v2()?
+            .into_iter()
+            .map(|file_v2| {
+                let mut file = File::new(file_v2.length, file_v2.path);
+                file.attr = file_v2.attr;
+                file.symlink_path = file_v2.symlink_path;
+                file
+            })
+            .collect();
+        Ok(files)
+    }
+
+    /// Whether the torrent has several files. Multi-file torrents are saved as
+    /// a directory.
+    pub fn is_multi_file(&self) -> Result<bool, MetainfoError> {
+        if self.is_v1() {
+            return Ok(!self.files.is_empty());
+        }
+        let files = self.files_v2()?;
+        Ok(files.len() != 1 || files[0].path != [self.name.clone()])
+    }
+}
+
+// v1 helpers
+impl Info {
+    /// Whether the torrent has v1 piece hashes
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    /// Whether the torrent is private (BEP 27)
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    // Files of a v1 torrent are laid out one after the other
+    fn length_v1(&self) -> usize {
+        match self.files.is_empty() {
+            true => self.length,
+            false => self.files.iter().map(|file| file.length).sum(),
+        }
+    }
+}
+
+// v2 (BEP 52) helpers
+impl Info {
+    /// Whether the torrent is both v1 and v2
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    /// Whether the torrent has a v2 file tree
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(META_VERSION_2) && self.file_tree.is_some()
+    }
+
+    /// The files of the v2 file tree, in path order
+    pub fn files_v2(&self) -> Result<Vec<FileV2>, MetainfoError> {
+        let Some(Value::Dict(file_tree)) = &self.file_tree else {
+            return Ok(vec![]);
+        };
+        let mut files = vec![];
+        let mut first_piece_index = 0;
+        Self::collect_files_v2(
+            file_tree,
+            &mut vec![],
+            &mut files,
+            &mut first_piece_index,
+            self.piece_length,
+        )?;
+        Ok(files)
+    }
+
+    fn collect_files_v2(
+        node: &HashMap<Vec<u8>, Value>,
+        path: &mut Vec<String>,
+        files: &mut Vec<FileV2>,
+        first_piece_index: &mut u32,
+        piece_length: usize,
+    ) -> Result<(), MetainfoError> {
+        // File tree entries are ordered by name
+        let mut entries: Vec<(&Vec<u8>, &Value)> = node.iter().collect();
+        entries.sort_by_key(|(name, _)| *name);
+
+        for (name, child) in entries {
+            let Value::Dict(child) = child else {
+                return Err(Self::file_tree_error("entries must be dictionaries"));
+            };
+
+            // An empty name marks the properties of a file
+            if name.is_empty() {
+                let length = match child.get(b"length".as_slice()) {
+                    Some(Value::Int(length)) if *length >= 0 => *length as usize,
+                    _ => return Err(Self::file_tree_error("file length missing")),
+                };
+                let pieces_root = match child.get(b"pieces root".as_slice()) {
+                    Some(Value::Bytes(root)) => Some(
+                        root.as_slice()
+                            .try_into()
+                            .map_err(|_| Self::file_tree_error("pieces root is not 32 bytes"))?,
+                    ),
+                    _ => None,
+                };
+                let attr = match child.get(b"attr".as_slice()) {
+                    Some(Value::Bytes(attr)) => Some(String::from_utf8_lossy(attr).into()),
+                    _ => None,
+                };
+                let symlink_path = match child.get(b"symlink path".as_slice()) {
+                    Some(Value::List(components)) => Some(
+                        components
+                            .iter()
+                            .filter_map(|component| match component {
+                                Value::Bytes(component) => {
+                                    Some(String::from_utf8_lossy(component).into())
+                                }
+                                _ => None,
+                            })
+                            .collect(),
+                    ),
+                    _ => None,
+                };
+                files.push(FileV2 {
+                    path: path.clone(),
+                    length,
+                    pieces_root,
+                    first_piece_index: *first_piece_index,
+                    attr,
+                    symlink_path,
+                });
+                *first_piece_index += length.div_ceil(piece_length) as u32;
+                continue;
+            }
+
+            path.push(String::from_utf8_lossy(name).into());
+            Self::collect_files_v2(child, path, files, first_piece_index, piece_length)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    fn file_tree_error(reason: &str) -> MetainfoError {
+        MetainfoError::NotValid {
+            reason: format!("file tree: {reason}"),
+        }
+    }
+
+    // The file a piece belongs to, along with the index of the piece within the file
+    pub(crate) fn piece_location_v2(&self, piece_index: u32) -> Option<(FileV2, u32)> {
+        self.files_v2()
+            .ok()?
+            .into_iter()
+            .filter(|file| file.length > 0)
+            .find(|file| {
+                let pieces_count = file.length.div_ceil(self.piece_length) as u32;
+                (file.first_piece_index..file.first_piece_index + pieces_count)
+                    .contains(&piece_index)
+            })
+            .map(|file| {
+                let piece_in_file = piece_index - file.first_piece_index;
+                (file, piece_in_file)
+            })
+    }
+
+    // Size of a piece within its file, hybrid torrents pad it up to the piece length in v1
+    pub(crate) fn piece_size_v2(&self, file: &FileV2, piece_in_file: u32) -> usize {
+        file.length
+            .saturating_sub(piece_in_file as usize * self.piece_length)
+            .min(self.piece_length)
+    }
+
+    pub(crate) fn blocks_per_piece(&self) -> usize {
+        self.piece_length / MERKLE_BLOCK_SIZE
+    }
+
+    // Leaves under a piece hash: a whole piece, or the padded file if it fits in one piece
+    pub(crate) fn piece_leaves_count(&self, file: &FileV2) -> usize {
+        match file.length > self.piece_length {
+            true => self.blocks_per_piece(),
+            false => file.length.div_ceil(MERKLE_BLOCK_SIZE).next_power_of_two(),
+        }
     }
 }
@@ -0,0 +1,460 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+use reqwest::{header, Response, StatusCode, Url};
+use tokio::time::Instant;
+
+use super::{
+    error::{Error, WebSeedError},
+    rate_limiter::RateLimiter,
+    torrent_metainfo::{File, Info},
+};
+
+const MAX_WEB_SEED_FAILURES: u32 = 3;
+const HTTP_SEED_BLOCK_SIZE: usize = 16_384; // 16 KiB
+const DEFAULT_RETRY_AFTER: u64 = 30; // seconds
+
+/// The protocol a web seed speaks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebSeedKind {
+    /// A plain HTTP server holding the torrent files (BEP 19)
+    UrlList,
+    /// A script serving pieces by index (BEP 17)
+    HttpSeed,
+}
+
+/// An HTTP server the client downloads pieces from, next to peers
+#[derive(Debug)]
+pub struct WebSeed {
+    /// The URL of the server or script
+    pub url: String,
+    /// The protocol of the web seed
+    pub kind: WebSeedKind,
+    /// The bytes of verified pieces it served
+    pub bytes_downloaded: usize,
+    failures: u32,
+    // HTTP seeds may ask us to come back later
+    retry_at: Option<Instant>,
+    http_client: reqwest::Client,
+}
+
+impl WebSeed {
+    /// A web seed that has not failed yet
+    pub fn new(url: String, kind: WebSeedKind) -> Self {
+        Self {
+            url,
+            kind,
+            bytes_downloaded: 0,
+            failures: 0,
+            retry_at: None,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    /// Whether the web seed has not failed too many times
+    pub fn is_usable(&self) -> bool {
+        self.failures < MAX_WEB_SEED_FAILURES
+    }
+
+    /// Usable and not waiting out a retry delay
+    pub fn is_available(&self) -> bool {
+        self.is_usable()
+            && self
+                .retry_at
+                .is_none_or(|retry_at| retry_at <= Instant::now())
+    }
+
+    pub(crate) fn retry_at(&self) -> Option<Instant> {
+        self.retry_at.filter(|_| self.is_usable())
+    }
+
+    // Returns true if the web seed should not be used anymore
+    pub(crate) fn record_failure(&mut self) -> bool {
+        self.failures += 1;
+        !self.is_usable()
+    }
+}
+
+impl WebSeed {
+    // Bytes are charged to the rate limiter as they arrive
+    pub(crate) async fn fetch_piece(
+        &mut self,
+        info: &Info,
+        piece_index: u32,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<u8>, Error> {
+        match self.kind {
+            WebSeedKind::UrlList => {
+                self.fetch_piece_from_files(info, piece_index, rate_limiter)
+                    .await
+            }
+            WebSeedKind::HttpSeed => {
+                self.fetch_piece_from_http_seed(info, piece_index, rate_limiter)
+                    .await
+            }
+        }
+    }
+
+    // Asks for the blocks of a piece by their ranges within the piece
+    async fn fetch_piece_from_http_seed(
+        &mut self,
+        info: &Info,
+        piece_index: u32,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<u8>, Error> {
+        let piece_size = info.piece_size(piece_index);
+        let ranges: Vec<String> = (0..piece_size)
+            .step_by(HTTP_SEED_BLOCK_SIZE)
+            .map(|begin| {
+                let end = (begin + HTTP_SEED_BLOCK_SIZE).min(piece_size);
+                format!("{}-{}", begin, end - 1)
+            })
+            .collect();
+
+        // Raw bytes are percent-encoded by hand, like in tracker announces
+        let info_hash: String = info
+            .swarm_hash_bytes()?
+            .iter()
+            .map(|byte| format!("%{:02x}", byte))
+            .collect();
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{separator}info_hash={info_hash}&piece={piece_index}&ranges={}",
+            self.url,
+            ranges.join(",")
+        );
+
+        let response = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .map_err(WebSeedError::from)?;
+        let status = response.status();
+        let bytes = Self::read_body(response, rate_limiter)
+            .await
+            .map_err(WebSeedError::from)?;
+
+        match status {
+            StatusCode::OK if bytes.len() == piece_size => {
+                self.retry_at = None;
+                Ok(bytes.to_vec())
+            }
+            StatusCode::OK => Err(self
+                .request_error(&format!("expected {piece_size} bytes, got {}", bytes.len()))
+                .into()),
+            // The body tells how many seconds to wait before asking again
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let retry_after = String::from_utf8_lossy(&bytes)
+                    .trim()
+                    .parse()
+                    .unwrap_or(DEFAULT_RETRY_AFTER);
+                self.retry_at = Some(Instant::now() + Duration::from_secs(retry_after));
+                Err(WebSeedError::Busy {
+                    url: self.url.clone(),
+                    retry_after,
+                }
+                .into())
+            }
+            _ => Err(self.request_error(&format!("status {status}")).into()),
+        }
+    }
+
+    // Fetches a piece range by range, a piece may span several files
+    async fn fetch_piece_from_files(
+        &self,
+        info: &Info,
+        piece_index: u32,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<u8>, Error> {
+        let piece_start = info.piece_offset(piece_index);
+        let piece_end = piece_start + info.piece_size(piece_index);
+        let is_multi_file = info.is_multi_file()?;
+
+        let mut piece_bytes = Vec::with_capacity(piece_end - piece_start);
+        let mut file_start = 0;
+        for file in info.files()? {
+            let file_end = file_start + file.length;
+            let start = piece_start.max(file_start);
+            let end = piece_end.min(file_end);
+
+            if start < end {
+                // Padding files are all zeros and not served
+                if file.is_padding() {
+                    piece_bytes.resize(piece_bytes.len() + end - start, 0);
+                } else {
+                    let url = self.file_url(&info.name, &file, is_multi_file)?;
+                    let bytes = self
+                        .fetch_range(url, start - file_start, end - file_start, rate_limiter)
+                        .await?;
+                    piece_bytes.extend_from_slice(&bytes);
+                }
+            }
+            file_start = file_end;
+        }
+
+        Ok(piece_bytes)
+    }
+
+    // A URL ending with a slash is a directory holding the torrent, named as the torrent
+    fn file_url(&self, name: &str, file: &File, is_multi_file: bool) -> Result<Url, WebSeedError> {
+        let mut url =
+            Url::parse(&self.url).map_err(|error| self.request_error(&error.to_string()))?;
+        if !is_multi_file && !self.url.ends_with('/') {
+            return Ok(url);
+        }
+
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| self.request_error("URL cannot hold a path"))?;
+        segments.pop_if_empty().push(name);
+        if is_multi_file {
+            segments.extend(&file.path);
+        }
+        drop(segments);
+        Ok(url)
+    }
+
+    async fn fetch_range(
+        &self,
+        url: Url,
+        start: usize,
+        end: usize,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let response = self
+            .http_client
+            .get(url)
+            .header(header::RANGE, format!("bytes={}-{}", start, end - 1))
+            .send()
+            .await?;
+
+        let status = response.status();
+        let bytes = Self::read_body(response, rate_limiter).await?;
+        let range_bytes = match status {
+            StatusCode::PARTIAL_CONTENT => &bytes[..],
+            // The server ignored the range and sent the whole file
+            StatusCode::OK => bytes.get(start..end).unwrap_or_default(),
+            _ => return Err(self.request_error(&format!("status {status}"))),
+        };
+
+        match range_bytes.len() == end - start {
+            true => Ok(range_bytes.to_vec()),
+            false => Err(self.request_error(&format!(
+                "expected {} bytes, got {}",
+                end - start,
+                range_bytes.len()
+            ))),
+        }
+    }
+
+    // Reads the body chunk by chunk, waiting on the rate limiter after each of them
+    async fn read_body(
+        mut response: Response,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<u8>, reqwest::Error> {
+        let mut bytes = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter.acquire(chunk.len()).await;
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    fn request_error(&self, reason: &str) -> WebSeedError {
+        WebSeedError::RequestFailed {
+            url: self.url.clone(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for WebSeed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            WebSeedKind::UrlList => "Web seed",
+            WebSeedKind::HttpSeed => "HTTP seed",
+        };
+        write!(
+            f,
+            "{kind} {}: {} bytes downloaded",
+            self.url, self.bytes_downloaded
+        )
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{
+        fs,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::torrent_client::torrent_builder::TorrentMetainfoBuilder;
+
+    const PIECE_LENGTH: usize = 16_384;
+
+    // An HTTP server answering every request head with a status and a body
+    pub(in crate::torrent_client) async fn serve(
+        respond: impl Fn(&str) -> (u16, Vec<u8>) + Send + 'static,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buffer = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let (status, body) = respond(&String::from_utf8_lossy(&request));
+                let head = format!(
+                    "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        address
+    }
+
+    fn request_path(request: &str) -> &str {
+        request.split(' ').nth(1).unwrap_or_default()
+    }
+
+    // "bytes=10-19" => 10..20
+    fn requested_range(request: &str) -> Option<(usize, usize)> {
+        let range = request.lines().find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("range: bytes=")
+                .map(String::from)
+        })?;
+        let (start, end) = range.trim().split_once('-')?;
+        Some((start.parse().ok()?, end.parse::<usize>().ok()? + 1))
+    }
+
+    fn contents(length: usize, seed: u8) -> Vec<u8> {
+        (0..length)
+            .map(|index| (index as u8).wrapping_mul(seed))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn pieces_spanning_files_are_fetched_with_range_requests() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("set");
+        fs::create_dir(&root).unwrap();
+        let files = [("a", contents(20_000, 3)), ("b", contents(30_000, 7))];
+        for (name, bytes) in &files {
+            fs::write(root.join(name), bytes).unwrap();
+        }
+        let info = TorrentMetainfoBuilder::new(&root)
+            .piece_length(PIECE_LENGTH)
+            .build()
+            .unwrap()
+            .info;
+
+        let served = files.clone();
+        let address = serve(move |request| {
+            let path = request_path(request);
+            let Some((_, bytes)) = served
+                .iter()
+                .find(|(name, _)| path == format!("/set/{name}"))
+            else {
+                return (404, vec![]);
+            };
+            match requested_range(request) {
+                Some((start, end)) => (206, bytes[start..end].to_vec()),
+                None => (400, vec![]),
+            }
+        })
+        .await;
+
+        let mut web_seed = WebSeed::new(format!("http://{address}/"), WebSeedKind::UrlList);
+        let all_bytes = [files[0].1.clone(), files[1].1.clone()].concat();
+        for (piece_index, piece) in all_bytes.chunks(PIECE_LENGTH).enumerate() {
+            let piece_bytes = web_seed
+                .fetch_piece(&info, piece_index as u32, None)
+                .await
+                .unwrap();
+            assert_eq!(piece_bytes, piece);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_seeds_are_asked_for_a_piece_and_its_block_ranges() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data.bin");
+        let bytes = contents(PIECE_LENGTH + 100, 5);
+        fs::write(&path, &bytes).unwrap();
+        let info = TorrentMetainfoBuilder::new(&path)
+            .piece_length(2 * PIECE_LENGTH)
+            .build()
+            .unwrap()
+            .info;
+        let info_hash: String = info
+            .swarm_hash_bytes()
+            .unwrap()
+            .iter()
+            .map(|byte| format!("%{byte:02x}"))
+            .collect();
+
+        let expected_path =
+            format!("/seed?info_hash={info_hash}&piece=0&ranges=0-16383,16384-16483");
+        let served = bytes.clone();
+        let address = serve(
+            move |request| match request_path(request) == expected_path {
+                true => (200, served.clone()),
+                false => (404, vec![]),
+            },
+        )
+        .await;
+
+        let mut web_seed = WebSeed::new(format!("http://{address}/seed"), WebSeedKind::HttpSeed);
+        assert_eq!(web_seed.fetch_piece(&info, 0, None).await.unwrap(), bytes);
+    }
+
+    #[tokio::test]
+    async fn busy_http_seeds_are_retried_after_the_given_delay() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data.bin");
+        fs::write(&path, contents(100, 5)).unwrap();
+        let info = TorrentMetainfoBuilder::new(&path).build().unwrap().info;
+
+        let requests = Arc::new(Mutex::new(0));
+        let counted_requests = requests.clone();
+        let address = serve(move |_| {
+            *counted_requests.lock().unwrap() += 1;
+            (503, b"7".to_vec())
+        })
+        .await;
+
+        let mut web_seed = WebSeed::new(format!("http://{address}/seed"), WebSeedKind::HttpSeed);
+        let error = web_seed.fetch_piece(&info, 0, None).await.unwrap_err();
+        assert!(matches!(
+            error,
+            Error::WebSeed(WebSeedError::Busy { retry_after: 7, .. })
+        ));
+        assert_eq!(*requests.lock().unwrap(), 1);
+        assert!(web_seed.is_usable() && !web_seed.is_available());
+        let retry_at = web_seed.retry_at().unwrap();
+        assert!(retry_at > Instant::now() + Duration::from_secs(6));
+    }
+}